@@ -10,8 +10,10 @@ cargo +nightly build --target=arm-unknown-linux-gnueabihf &&
 #copy file to target system (pi@raspberrypi.local)
 echo "Tranfering to pi@raspberrypi.local" &&
 scp target/arm-unknown-linux-gnueabihf/debug/rust_tank_v2 pi@raspberrypi.local:~/debug_rust_tank &&
+#only copy the example config if the tank doesn't have one yet
+(ssh pi@raspberrypi.local "test -e ~/tank.toml" || scp rust_tank_v2/tank.toml pi@raspberrypi.local:~/tank.toml) &&
 
 #tell remote to start program
 echo "Starting Program" &&
-echo "use 'netcat raspberrypi.local 27272' to send commands" &&
+echo "use 'cargo run --bin tankctl -- help' or 'netcat raspberrypi.local 27272' to send commands" &&
 ssh -t pi@raspberrypi.local "sudo RUST_BACKTRACE=1 ./debug_rust_tank 2> msgs.txt"
@@ -0,0 +1,315 @@
+//! In memory stand in for an I2C device, for testing drivers without the
+//! hardware.
+//!
+//! The device is a file of 256 byte registers behind a register pointer, the
+//! way most I2C chips work: writing sets the pointer with the first byte and
+//! stores the rest, reading returns bytes from the pointer onwards. Every
+//! transaction is logged, and errors can be injected to exercise a driver's
+//! error handling.
+//!
+//! The device is a handle, clones share the same registers and log, so a
+//! test can keep one while the driver owns another.
+extern crate i2cdev;
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use i2cdev::core::I2CDevice;
+
+/// One transaction on the bus, as the device saw it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transaction {
+    /// Bytes written from register on, empty when only the pointer was set
+    Write { register: u8, data: Vec<u8> },
+    /// Bytes read from register on
+    Read { register: u8, data: Vec<u8> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockI2CError {
+    /// Injected with fail_after or fail_register
+    Injected,
+    /// The device doesn't model this kind of transaction
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for MockI2CError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MockI2CError::Injected => write!(f, "Injected I2C error"),
+            MockI2CError::Unsupported(what) => write!(f, "The mock I2C device doesn't support {}", what),
+        }
+    }
+}
+
+impl Error for MockI2CError {}
+
+struct State {
+    registers: [u8; 256],
+    pointer: u8,
+    /// Whether multi byte transfers move on through the registers,
+    /// rather than all going to the one register
+    auto_increment: bool,
+    log: Vec<Transaction>,
+    /// Transactions left until the next one fails
+    fail_after: Option<usize>,
+    /// Registers that fail when a transaction starts at them
+    fail_registers: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct MockI2CDevice {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for MockI2CDevice {
+    fn default() -> MockI2CDevice {
+        MockI2CDevice::new()
+    }
+}
+
+impl MockI2CDevice {
+    /// A device with every register 0, and auto increment on
+    pub fn new() -> MockI2CDevice {
+        MockI2CDevice {
+            state: Arc::new(Mutex::new(State {
+                registers: [0; 256],
+                pointer: 0,
+                auto_increment: true,
+                log: vec![],
+                fail_after: None,
+                fail_registers: vec![],
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn register(&self, register: u8) -> u8 {
+        self.state().registers[register as usize]
+    }
+
+    /// Sets a register without it showing up in the log
+    pub fn set_register(&self, register: u8, value: u8) {
+        self.state().registers[register as usize] = value;
+    }
+
+    /// Sets consecutive registers from register on
+    pub fn set_registers(&self, register: u8, values: &[u8]) {
+        let mut state = self.state();
+        for (i, &value) in values.iter().enumerate() {
+            state.registers[register.wrapping_add(i as u8) as usize] = value;
+        }
+    }
+
+    /// Whether multi byte transfers move on through the registers.
+    /// Without it every byte goes to, or comes from, the first register.
+    pub fn set_auto_increment(&self, on: bool) {
+        self.state().auto_increment = on;
+    }
+
+    /// Every transaction so far, oldest first
+    pub fn log(&self) -> Vec<Transaction> {
+        self.state().log.clone()
+    }
+
+    /// Just the writes that stored data, as (register, data)
+    pub fn writes(&self) -> Vec<(u8, Vec<u8>)> {
+        self.state().log.iter()
+            .filter_map(|t| match *t {
+                Transaction::Write { register, ref data } if !data.is_empty() => Some((register, data.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Just the reads, as (register, number of bytes)
+    pub fn reads(&self) -> Vec<(u8, usize)> {
+        self.state().log.iter()
+            .filter_map(|t| match *t {
+                Transaction::Read { register, ref data } => Some((register, data.len())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn clear_log(&self) {
+        self.state().log.clear();
+    }
+
+    /// Lets count more transactions through, then fails the one after
+    pub fn fail_after(&self, count: usize) {
+        self.state().fail_after = Some(count);
+    }
+
+    /// Fails every transaction that starts at register, until cleared
+    pub fn fail_register(&self, register: u8) {
+        self.state().fail_registers.push(register);
+    }
+
+    pub fn clear_failures(&self) {
+        let mut state = self.state();
+        state.fail_after = None;
+        state.fail_registers.clear();
+    }
+}
+
+impl State {
+    /// Decides whether the next transaction, starting at register, fails
+    fn check(&mut self, register: u8) -> Result<(), MockI2CError> {
+        let injected = match self.fail_after {
+            Some(0) => {
+                self.fail_after = None;
+                true
+            },
+            Some(n) => {
+                self.fail_after = Some(n - 1);
+                false
+            },
+            None => false,
+        };
+        if injected || self.fail_registers.contains(&register) {
+            Err(MockI2CError::Injected)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The register the i'th byte of a transfer goes to
+    fn address(&self, register: u8, i: usize) -> usize {
+        if self.auto_increment {
+            register.wrapping_add(i as u8) as usize
+        } else {
+            register as usize
+        }
+    }
+
+    fn write(&mut self, register: u8, data: &[u8]) -> Result<(), MockI2CError> {
+        self.check(register)?;
+        for (i, &value) in data.iter().enumerate() {
+            let address = self.address(register, i);
+            self.registers[address] = value;
+        }
+        self.pointer = register;
+        self.log.push(Transaction::Write { register, data: data.to_vec() });
+        Ok(())
+    }
+
+    fn read(&mut self, register: u8, data: &mut [u8]) -> Result<(), MockI2CError> {
+        self.check(register)?;
+        for (i, value) in data.iter_mut().enumerate() {
+            *value = self.registers[self.address(register, i)];
+        }
+        self.pointer = register;
+        self.log.push(Transaction::Read { register, data: data.to_vec() });
+        Ok(())
+    }
+}
+
+impl I2CDevice for MockI2CDevice {
+    type Error = MockI2CError;
+
+    /// Reads from the register pointer on
+    fn read(&mut self, data: &mut [u8]) -> Result<(), MockI2CError> {
+        let mut state = self.state();
+        let register = state.pointer;
+        state.read(register, data)
+    }
+
+    /// Sets the register pointer to the first byte, and writes the rest from there on
+    fn write(&mut self, data: &[u8]) -> Result<(), MockI2CError> {
+        match data.split_first() {
+            Some((&register, data)) => self.state().write(register, data),
+            None => Ok(()),
+        }
+    }
+
+    fn smbus_write_quick(&mut self, _bit: bool) -> Result<(), MockI2CError> {
+        Ok(())
+    }
+
+    fn smbus_read_byte_data(&mut self, register: u8) -> Result<u8, MockI2CError> {
+        let mut buf = [0];
+        self.state().read(register, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn smbus_read_word_data(&mut self, register: u8) -> Result<u16, MockI2CError> {
+        let mut buf = [0; 2];
+        self.state().read(register, &mut buf)?;
+        Ok(buf[0] as u16 | (buf[1] as u16) << 8)
+    }
+
+    fn smbus_read_block_data(&mut self, _register: u8) -> Result<Vec<u8>, MockI2CError> {
+        Err(MockI2CError::Unsupported("SMBus block reads"))
+    }
+
+    fn smbus_read_i2c_block_data(&mut self, register: u8, len: u8) -> Result<Vec<u8>, MockI2CError> {
+        let mut buf = vec![0; len as usize];
+        self.state().read(register, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Like a real device, the count byte is stored first
+    fn smbus_write_block_data(&mut self, register: u8, values: &[u8]) -> Result<(), MockI2CError> {
+        let mut data = vec![values.len() as u8];
+        data.extend_from_slice(values);
+        self.state().write(register, &data)
+    }
+
+    fn smbus_process_block(&mut self, _register: u8, _values: &[u8]) -> Result<(), MockI2CError> {
+        Err(MockI2CError::Unsupported("SMBus block process calls"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_and_log() {
+        let mut dev = MockI2CDevice::new();
+        let handle = dev.clone();
+        dev.smbus_write_byte_data(0x10, 0xAB).unwrap();
+        dev.write(&[0x20, 1, 2, 3]).unwrap();
+        assert_eq!(handle.register(0x10), 0xAB);
+        assert_eq!(handle.register(0x22), 3);
+
+        handle.set_registers(0x30, &[0x34, 0x12]);
+        assert_eq!(dev.smbus_read_word_data(0x30).unwrap(), 0x1234);
+        //reading without a register carries on from the pointer
+        assert_eq!(dev.smbus_read_byte().unwrap(), 0x34);
+
+        assert_eq!(handle.writes(), vec![(0x10, vec![0xAB]), (0x20, vec![1, 2, 3])]);
+        assert_eq!(handle.reads(), vec![(0x30, 2), (0x30, 1)]);
+    }
+
+    #[test]
+    fn without_auto_increment() {
+        let mut dev = MockI2CDevice::new();
+        dev.set_auto_increment(false);
+        dev.write(&[0x05, 1, 2, 3]).unwrap();
+        assert_eq!(dev.register(0x05), 3);
+        assert_eq!(dev.register(0x06), 0);
+    }
+
+    #[test]
+    fn injected_errors() {
+        let mut dev = MockI2CDevice::new();
+        dev.fail_after(1);
+        assert!(dev.smbus_write_byte_data(0, 1).is_ok());
+        assert_eq!(dev.smbus_write_byte_data(0, 2), Err(MockI2CError::Injected));
+        assert!(dev.smbus_write_byte_data(0, 3).is_ok());
+        //failed transactions don't happen
+        assert_eq!(dev.writes(), vec![(0, vec![1]), (0, vec![3])]);
+
+        dev.fail_register(0x42);
+        assert!(dev.smbus_read_byte_data(0x42).is_err());
+        assert!(dev.smbus_read_byte_data(0x41).is_ok());
+        dev.clear_failures();
+        assert!(dev.smbus_read_byte_data(0x42).is_ok());
+    }
+}
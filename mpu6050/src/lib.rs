@@ -1,4 +1,6 @@
 extern crate i2cdev;
+#[cfg(test)]
+extern crate mock_i2c;
 
 use i2cdev::core::*;
 use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
@@ -30,7 +32,7 @@ pub const GYRO_RANGE_2000DEG: u8 = 0x18;
 
 //MPU-6050 Registers
 const PWR_MGMT_1: u8 = 0x6B;
-const PWR_MGMT_2: u8 = 0x6C;
+//const PWR_MGMT_2: u8 = 0x6C;
 const ACCEL_XOUT0: u8 = 0x3B;
 const ACCEL_YOUT0: u8 = 0x3D;
 const ACCEL_ZOUT0: u8 = 0x3F;
@@ -43,34 +45,40 @@ const GYRO_CONFIG: u8 = 0x1B;
 
 
 
-pub struct MPU6050 {
-    dev: LinuxI2CDevice,
+pub struct MPU6050<D: I2CDevice> {
+    dev: D,
 }
-impl MPU6050 {
-    pub fn new(addr: u16) -> Result<MPU6050, LinuxI2CError> {
+impl MPU6050<LinuxI2CDevice> {
+    pub fn new(addr: u16) -> Result<MPU6050<LinuxI2CDevice>, LinuxI2CError> {
         let d = LinuxI2CDevice::new(I2C_DEV, addr)?;
-        let mut mpu = MPU6050 {dev: d};
+        MPU6050::with_device(d)
+    }
+}
+impl<D: I2CDevice> MPU6050<D> {
+    /// Wakes the chip up on an already opened device
+    pub fn with_device(dev: D) -> Result<MPU6050<D>, D::Error> {
+        let mut mpu = MPU6050 {dev};
         mpu.dev.smbus_write_byte_data(PWR_MGMT_1, 0x00)?;
         Ok(mpu)
     }
     pub fn read_i2c_word(&mut self, register: u8)
-                         -> Result<i16, LinuxI2CError> {
+                         -> Result<i16, D::Error> {
         let high = self.dev.smbus_read_byte_data(register)? as i16;
         let low = self.dev.smbus_read_byte_data(register + 1)? as i16;
         let value = (high << 8) | low;
         Ok(value)
     }
-    pub fn get_temp(&mut self) -> Result<f32, LinuxI2CError> {
+    pub fn get_temp(&mut self) -> Result<f32, D::Error> {
         let raw_tmp = self.read_i2c_word(TEMP_OUT0)? as f32;
         let actual_tmp = (raw_tmp / 340.0) + 36.53;
         Ok(actual_tmp)
     }
-    pub fn set_accel_range(&mut self, accel_range: u8) -> Result<(), LinuxI2CError> {
+    pub fn set_accel_range(&mut self, accel_range: u8) -> Result<(), D::Error> {
         self.dev.smbus_write_byte_data(ACCEL_CONFIG, 0x00)?;
         self.dev.smbus_write_byte_data(ACCEL_CONFIG, accel_range)?;//TODO enforce use of predefined ranges
         Ok(())
     }
-    pub fn read_accel_range(&mut self, raw: bool) -> Result<u8, LinuxI2CError> {
+    pub fn read_accel_range(&mut self, raw: bool) -> Result<u8, D::Error> {
         let raw_data = self.dev.smbus_read_byte_data(ACCEL_CONFIG)?;
         if raw {
             Ok(raw_data)
@@ -84,7 +92,7 @@ impl MPU6050 {
             })
         }
     }
-    pub fn get_accel_data(&mut self, g: bool) -> Result<(f32, f32, f32), LinuxI2CError> {
+    pub fn get_accel_data(&mut self, g: bool) -> Result<(f32, f32, f32), D::Error> {
         let mut x = self.read_i2c_word(ACCEL_XOUT0)? as f32;
         let mut y = self.read_i2c_word(ACCEL_YOUT0)? as f32;
         let mut z = self.read_i2c_word(ACCEL_ZOUT0)? as f32;
@@ -96,27 +104,27 @@ impl MPU6050 {
             ACCEL_RANGE_16G => ACCEL_SCALE_MODIFIER_16G,
             _ => {println!("Unknown accel range - calculated as 2G"); ACCEL_SCALE_MODIFIER_2G}
         };
-        x = x / accel_scale_modifier;
-        y = y / accel_scale_modifier;
-        z = z / accel_scale_modifier;
+        x /= accel_scale_modifier;
+        y /= accel_scale_modifier;
+        z /= accel_scale_modifier;
 
         if !g {
-            x = x * GRAVITY_MS2;
-            y = y * GRAVITY_MS2;
-            z = z * GRAVITY_MS2;
+            x *= GRAVITY_MS2;
+            y *= GRAVITY_MS2;
+            z *= GRAVITY_MS2;
         }
 
         Ok((x,y,z))
     }
-    pub fn set_gyro_range(&mut self, gyro_range: u8) -> Result<(), LinuxI2CError> {
+    pub fn set_gyro_range(&mut self, gyro_range: u8) -> Result<(), D::Error> {
         self.dev.smbus_write_byte_data(GYRO_CONFIG, 0x00)?;
         self.dev.smbus_write_byte_data(GYRO_CONFIG, gyro_range)?;//TODO enforce use of predefined ranges
         Ok(())
     }
-    pub fn read_gyro_range_raw(&mut self) -> Result<u8, LinuxI2CError> {
+    pub fn read_gyro_range_raw(&mut self) -> Result<u8, D::Error> {
         self.dev.smbus_read_byte_data(GYRO_CONFIG)
     }
-    pub fn read_gyro_range(&mut self) -> Result<u16, LinuxI2CError> {
+    pub fn read_gyro_range(&mut self) -> Result<u16, D::Error> {
         Ok(match self.dev.smbus_read_byte_data(GYRO_CONFIG)? {
             GYRO_RANGE_250DEG => 250,
             GYRO_RANGE_500DEG => 500,
@@ -125,7 +133,7 @@ impl MPU6050 {
             _ => 0,
         })
     }
-    pub fn get_gyro_data(&mut self) -> Result<(f32, f32, f32), LinuxI2CError> {
+    pub fn get_gyro_data(&mut self) -> Result<(f32, f32, f32), D::Error> {
         let mut x = self.read_i2c_word(GYRO_XOUT0)? as f32;
         let mut y = self.read_i2c_word(GYRO_YOUT0)? as f32;
         let mut z = self.read_i2c_word(GYRO_ZOUT0)? as f32;
@@ -137,10 +145,60 @@ impl MPU6050 {
             GYRO_RANGE_2000DEG => GYRO_SCALE_MODIFIER_2000DEG,
             _ => {println!("Unknown gyro range - calculated as 250DEG"); GYRO_SCALE_MODIFIER_250DEG}
         };
-        x = x / gyro_scale_modifier;
-        y = y / gyro_scale_modifier;
-        z = z / gyro_scale_modifier;
+        x /= gyro_scale_modifier;
+        y /= gyro_scale_modifier;
+        z /= gyro_scale_modifier;
 
         Ok((x,y,z))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_i2c::{MockI2CDevice, MockI2CError};
+
+    #[test]
+    fn new_wakes_the_chip() {
+        let dev = MockI2CDevice::new();
+        dev.set_register(PWR_MGMT_1, 0x40); //sleeping, as after power on
+        MPU6050::with_device(dev.clone()).unwrap();
+        assert_eq!(dev.writes(), vec![(PWR_MGMT_1, vec![0x00])]);
+    }
+
+    #[test]
+    fn get_accel_data_scales_by_range() {
+        let dev = MockI2CDevice::new();
+        let mut mpu = MPU6050::with_device(dev.clone()).unwrap();
+        //1g, -0.5g and 2g at +-4g
+        dev.set_registers(ACCEL_XOUT0, &[0x20, 0x00, 0xF0, 0x00, 0x40, 0x00]);
+        dev.set_register(ACCEL_CONFIG, ACCEL_RANGE_4G);
+        dev.clear_log();
+
+        assert_eq!(mpu.get_accel_data(true).unwrap(), (1.0, -0.5, 2.0));
+        assert_eq!(dev.reads(), vec![
+            (ACCEL_XOUT0, 1), (ACCEL_XOUT0 + 1, 1),
+            (ACCEL_YOUT0, 1), (ACCEL_YOUT0 + 1, 1),
+            (ACCEL_ZOUT0, 1), (ACCEL_ZOUT0 + 1, 1),
+            (ACCEL_CONFIG, 1),
+        ]);
+        assert!(dev.writes().is_empty());
+
+        let (x, _, _) = mpu.get_accel_data(false).unwrap();
+        assert_eq!(x, GRAVITY_MS2);
+    }
+
+    #[test]
+    fn errors_are_passed_on() {
+        let dev = MockI2CDevice::new();
+        dev.fail_register(PWR_MGMT_1);
+        assert_eq!(MPU6050::with_device(dev.clone()).err(), Some(MockI2CError::Injected));
+        dev.clear_failures();
+
+        let mut mpu = MPU6050::with_device(dev.clone()).unwrap();
+        dev.fail_register(ACCEL_ZOUT0 + 1);
+        assert_eq!(mpu.get_accel_data(true), Err(MockI2CError::Injected));
+        dev.clear_failures();
+        assert!(mpu.get_accel_data(true).is_ok());
+    }
+}
@@ -1,11 +1,19 @@
 extern crate i2cdev;
+#[cfg(test)]
+extern crate mock_i2c;
 
+use std::error::Error as StdError;
+use std::fmt;
+use std::path::Path;
 use std::thread;
 use std::time::Duration;
 
 use i2cdev::core::*;
 use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
 
+mod servo;
+pub use servo::{Servo, ServoCalibration};
+
 //Board constants
 const PCA9685_ADDRESS: u16 = 0x40;
 const MODE1: u8         = 0x00;
@@ -24,19 +32,31 @@ const ALL_LED_OFF_L: u8 = 0xFC;
 const ALL_LED_OFF_H: u8 = 0xFD;
 
 const SWRST: u8 = 0x06;
-//const RESTART: u8 = 0x80;
+const RESTART: u8 = 0x80;
+/// Auto increment, multi byte writes move on through the registers
+const AI: u8 = 0x20;
 const SLEEP: u8 = 0x10;
 const ALLCALL: u8 = 0x01;
-//const INVRT: u8   = 0x10;
+const INVRT: u8   = 0x10;
 const OUTDRV: u8  = 0x04;
 
 const I2C_DEV: &str = "/dev/i2c-1";
 
-pub struct PCA9685 {
-    dev: LinuxI2CDevice,
+/// Frequency of the internal oscillator
+const OSC_HZ: f32 = 25000000.0;
+/// Smallest prescale the chip accepts, it treats anything less as this
+const MIN_PRESCALE: u8 = 3;
+
+/// Driver for a PCA9685 on any I2C device, usually a LinuxI2CDevice
+pub struct PCA9685<D: I2CDevice> {
+    dev: D,
+    /// Output frequency the prescale was last set to, in Hz
+    freq_hz: f32,
+    /// Whether writes are read back and checked
+    verify: bool,
 }
 
-impl PCA9685 {
+impl PCA9685<LinuxI2CDevice> {
 
     /// Shorthand for asking the device to reset.
     pub fn software_reset() -> Result<(), LinuxI2CError> {
@@ -45,44 +65,124 @@ impl PCA9685 {
         Ok(())
     }
 
-    pub fn new(addr: u16) -> Result<PCA9685, LinuxI2CError> {
-        let mut dev = LinuxI2CDevice::new(I2C_DEV, addr)?;
+    /// Opens the chip at addr on /dev/i2c-1
+    pub fn new(addr: u16) -> Result<PCA9685<LinuxI2CDevice>, Error<LinuxI2CError>> {
+        PCA9685::open(I2C_DEV, addr)
+    }
+
+    /// Opens the chip at addr on the given bus, e.g. "/dev/i2c-0"
+    pub fn open<P: AsRef<Path>>(bus: P, addr: u16) -> Result<PCA9685<LinuxI2CDevice>, Error<LinuxI2CError>> {
+        let dev = LinuxI2CDevice::new(bus, addr).map_err(Error::I2C)?;
+        PCA9685::with_device(dev)
+    }
+
+    /// Opens the chip at its default address on /dev/i2c-1
+    pub fn open_default() -> Result<PCA9685<LinuxI2CDevice>, Error<LinuxI2CError>> {
+        PCA9685::new(PCA9685_ADDRESS)
+    }
+}
+
+impl<D: I2CDevice> PCA9685<D> {
+
+    /// Sets the chip up on an already opened device
+    pub fn with_device(dev: D) -> Result<PCA9685<D>, Error<D::Error>> {
+        let mut pca = PCA9685 {dev, freq_hz: 0.0, verify: false};
+        pca.reinit()?;
+        Ok(pca)
+    }
+
+    /// Gives back the device, leaving the chip as it is
+    pub fn into_device(self) -> D {
+        self.dev
+    }
 
-        dev.smbus_write_byte_data(MODE2, OUTDRV)?;
-        dev.smbus_write_byte_data(MODE1, ALLCALL)?;
+    /// Turns verify after write on or off. When on every write is read back,
+    /// and a register that doesn't hold what was written is an Error::Mismatch.
+    /// This doubles the traffic on the bus, it is meant for finding wiring faults.
+    /// The ALL_LED registers can't be read back, so set_all_pwm isn't checked.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
+
+    /// Sets the mode registers up again and wakes the chip,
+    /// e.g. after it browned out. The pwm frequency needs setting again after this,
+    /// a brown out resets it to about 200Hz.
+    pub fn reinit(&mut self) -> Result<(), Error<D::Error>> {
+        self.write_byte(MODE2, OUTDRV)?;
+        self.write_byte(MODE1, ALLCALL | AI)?;
 
         thread::sleep(Duration::from_millis(5));
 
-        let mode1 = dev.smbus_read_byte()?;
+        let mode1 = self.read_byte(MODE1)?;
         let mode1 = mode1 & !SLEEP;
-        dev.smbus_write_byte_data(MODE1, mode1)?;
+        self.write_byte(MODE1, mode1)?;
         thread::sleep(Duration::from_millis(5));
-
-        Ok(PCA9685 {dev: dev})
+        let prescale = self.read_byte(PRESCALE)?;
+        self.freq_hz = prescale_freq(prescale);
+        Ok(())
     }
 
-    pub fn default() -> Result<PCA9685, LinuxI2CError> {
-        PCA9685::new(PCA9685_ADDRESS)
+    /// The frequency the outputs run at, in Hz, as of the last time it was
+    /// set or read. get_pwm_freq asks the chip.
+    pub fn pwm_freq(&self) -> f32 {
+        self.freq_hz
     }
 
+    /// Sets the frequency of all the outputs, returns the frequency they
+    /// actually run at. The prescaler can't do every frequency, so it can be
+    /// a little off what was asked for. It only goes from 3 to 255, about
+    /// 1526Hz down to 24Hz, anything outside that gets the nearest end.
     pub fn set_pwm_freq(&mut self, freq_hz: f32)
-                        -> Result<(), LinuxI2CError> {
-        let mut prescaleval = 25000000.0; //25MHz
+                        -> Result<f32, Error<D::Error>> {
+        let mut prescaleval = OSC_HZ; //25MHz
         prescaleval /= 4096.0; //12 bit
         prescaleval /= freq_hz;
         prescaleval -= 1.0;
         //println!("Setting PWM frequency to {} Hz", freq_hz);
         //println!("Estimated pre-scale: {}", prescaleval);
-        let prescale = (prescaleval +0.5).floor() as u8;
+        let prescale = (prescaleval +0.5).floor().max(MIN_PRESCALE as f32).min(255.0) as u8;
         //println!("Final pre-scale: {}", prescale);
-        let oldmode = self.dev.smbus_read_byte()?;
-        let newmode = (oldmode & 0x7F) | 0x10; //sleep
-        self.dev.smbus_write_byte_data(MODE1, newmode)?;//go to sleep
-        self.dev.smbus_write_byte_data(PRESCALE, prescale)?;
-        self.dev.smbus_write_byte_data(MODE1, oldmode)?;
+        //the register pointer is wherever the last write left it, so ask for MODE1
+        let oldmode = self.read_byte(MODE1)?;
+        let newmode = (oldmode & !RESTART) | SLEEP;
+        self.write_byte(MODE1, newmode)?;//go to sleep
+        self.write_byte(PRESCALE, prescale)?;
+        self.write_byte(MODE1, oldmode)?;
         thread::sleep(Duration::from_millis(5));
-        self.dev.smbus_write_byte_data(MODE1, oldmode | 0x80)?;
-        Ok(())
+        self.write_byte(MODE1, oldmode | RESTART)?;
+        self.freq_hz = prescale_freq(prescale);
+        Ok(self.freq_hz)
+    }
+
+    /// Reads the prescale register, the oscillator is divided by this plus one
+    pub fn get_prescale(&mut self) -> Result<u8, Error<D::Error>> {
+        self.read_byte(PRESCALE)
+    }
+
+    /// Reads the frequency the outputs are running at, in Hz
+    pub fn get_pwm_freq(&mut self) -> Result<f32, Error<D::Error>> {
+        let prescale = self.get_prescale()?;
+        self.freq_hz = prescale_freq(prescale);
+        Ok(self.freq_hz)
+    }
+
+    /// Reads the mode registers
+    pub fn get_modes(&mut self) -> Result<Modes, Error<D::Error>> {
+        Ok(Modes {
+            mode1: self.read_byte(MODE1)?,
+            mode2: self.read_byte(MODE2)?,
+        })
+    }
+
+    /// Reads when a channel goes high and low, in ticks, as (on, off).
+    /// Bit 12 (4096) is set for fully on or fully off, as with set_pwm.
+    pub fn get_pwm(&mut self, channel: u8) -> Result<(u16, u16), Error<D::Error>> {
+        let data = self.read_block(channel_register(channel)?, 4)?;
+        Ok((data[0] as u16 | (data[1] as u16) << 8, data[2] as u16 | (data[3] as u16) << 8))
     }
 
     /// Directly set value of a pwm pin
@@ -95,11 +195,12 @@ impl PCA9685 {
     ///    pwm.set_pwm(pin, 4096, 0);
     ///You can set the pin to be fully off with
     ///    pwm.set_pwm(pin, 0, 4096);
-    pub fn set_pwm(&mut self, channel: u8, on: u16, off: u16) -> Result<(), LinuxI2CError> {
-        self.dev.smbus_write_byte_data(LED0_ON_L+4*channel, (on & 0xFF) as u8)?;
-        self.dev.smbus_write_byte_data(LED0_ON_H+4*channel, (on >> 8) as u8)?;
-        self.dev.smbus_write_byte_data(LED0_OFF_L+4*channel, (off & 0xFF) as u8)?;
-        self.dev.smbus_write_byte_data(LED0_OFF_H+4*channel, (off >> 8) as u8)?;
+    pub fn set_pwm(&mut self, channel: u8, on: u16, off: u16) -> Result<(), Error<D::Error>> {
+        channel_register(channel)?;
+        self.write_byte(LED0_ON_L+4*channel, (on & 0xFF) as u8)?;
+        self.write_byte(LED0_ON_H+4*channel, (on >> 8) as u8)?;
+        self.write_byte(LED0_OFF_L+4*channel, (off & 0xFF) as u8)?;
+        self.write_byte(LED0_OFF_H+4*channel, (off >> 8) as u8)?;
         Ok(())
     }
     /// Directly set value of all pwm pins
@@ -111,23 +212,406 @@ impl PCA9685 {
     ///    pwm.set_all_pwm(4096, 0);
     ///You can set the pin to be fully off with
     ///    pwm.set_all_pwm(0, 4096);
-    pub fn set_all_pwm(&mut self, on: u16, off: u16) -> Result<(), LinuxI2CError> {
-        self.dev.smbus_write_byte_data(ALL_LED_ON_L, (on & 0xFF) as u8)?;
-        self.dev.smbus_write_byte_data(ALL_LED_ON_H, (on >> 8) as u8)?;
-        self.dev.smbus_write_byte_data(ALL_LED_OFF_L, (off & 0xFF) as u8)?;
-        self.dev.smbus_write_byte_data(ALL_LED_OFF_H, (off >> 8) as u8)?;
+    pub fn set_all_pwm(&mut self, on: u16, off: u16) -> Result<(), Error<D::Error>> {
+        //the ALL_LED registers read back as 0, so these can't be verified
+        self.dev.smbus_write_byte_data(ALL_LED_ON_L, (on & 0xFF) as u8).map_err(Error::I2C)?;
+        self.dev.smbus_write_byte_data(ALL_LED_ON_H, (on >> 8) as u8).map_err(Error::I2C)?;
+        self.dev.smbus_write_byte_data(ALL_LED_OFF_L, (off & 0xFF) as u8).map_err(Error::I2C)?;
+        self.dev.smbus_write_byte_data(ALL_LED_OFF_H, (off >> 8) as u8).map_err(Error::I2C)?;
         Ok(())
     }
 
-    pub fn set_pwm_off(&mut self, channel: u8) -> Result<(), LinuxI2CError> {
+    /// Same as set_pwm, but in one transaction rather than four
+    pub fn set_pwm_block(&mut self, channel: u8, on: u16, off: u16) -> Result<(), Error<D::Error>> {
+        let mut data = vec![channel_register(channel)?];
+        push_ticks(&mut data, on, off);
+        self.write_block(&data)
+    }
+
+    /// Same as set_all_pwm, but in one transaction rather than four
+    pub fn set_all_pwm_block(&mut self, on: u16, off: u16) -> Result<(), Error<D::Error>> {
+        let mut data = vec![ALL_LED_ON_L];
+        push_ticks(&mut data, on, off);
+        self.dev.write(&data).map_err(Error::I2C)
+    }
+
+    /// Sets several channels, given as (channel, on, off), with a transaction
+    /// for each run of consecutive channels rather than four per channel.
+    /// They can be in any order, if a channel is given twice the last one wins.
+    /// Nothing is written if any channel is past 15.
+    pub fn set_pwms(&mut self, updates: &[(u8, u16, u16)]) -> Result<(), Error<D::Error>> {
+        let mut channels: Vec<Option<(u16, u16)>> = vec![None; 16];
+        for &(channel, on, off) in updates {
+            *channels.get_mut(channel as usize).ok_or(Error::Channel(channel))? = Some((on, off));
+        }
+        let mut run: Vec<u8> = vec![];
+        for (channel, ticks) in channels.into_iter().enumerate() {
+            match ticks {
+                Some((on, off)) => {
+                    if run.is_empty() {
+                        run.push(LED0_ON_L + 4 * channel as u8);
+                    }
+                    push_ticks(&mut run, on, off);
+                },
+                None => if !run.is_empty() {
+                    self.write_block(&run)?;
+                    run.clear();
+                },
+            }
+        }
+        if !run.is_empty() {
+            self.write_block(&run)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_pwm_off(&mut self, channel: u8) -> Result<(), Error<D::Error>> {
         self.set_pwm(channel, 0, 4096)
     }
 
-    pub fn set_pwm_on(&mut self, channel: u8) -> Result<(), LinuxI2CError> {
+    pub fn set_pwm_on(&mut self, channel: u8) -> Result<(), Error<D::Error>> {
         self.set_pwm(channel, 4096, 0)
     }
 
-    pub fn set_all_pwm_off(&mut self) -> Result<(), LinuxI2CError> {
+    pub fn set_all_pwm_off(&mut self) -> Result<(), Error<D::Error>> {
         self.set_all_pwm(0, 4096)
     }
-}
\ No newline at end of file
+
+    fn read_byte(&mut self, register: u8) -> Result<u8, Error<D::Error>> {
+        self.dev.smbus_read_byte_data(register).map_err(Error::I2C)
+    }
+
+    /// Reads len registers from register on, needs auto increment
+    fn read_block(&mut self, register: u8, len: u8) -> Result<Vec<u8>, Error<D::Error>> {
+        self.dev.smbus_read_i2c_block_data(register, len).map_err(Error::I2C)
+    }
+
+    fn write_byte(&mut self, register: u8, value: u8) -> Result<(), Error<D::Error>> {
+        self.dev.smbus_write_byte_data(register, value).map_err(Error::I2C)?;
+        if self.verify {
+            let read = self.read_byte(register)?;
+            check(register, &[value], &[read])?;
+        }
+        Ok(())
+    }
+
+    /// Writes data[1..] from register data[0] on, needs auto increment
+    fn write_block(&mut self, data: &[u8]) -> Result<(), Error<D::Error>> {
+        self.dev.write(data).map_err(Error::I2C)?;
+        if self.verify {
+            let read = self.read_block(data[0], data.len() as u8 - 1)?;
+            check(data[0], &data[1..], &read)?;
+        }
+        Ok(())
+    }
+}
+
+/// The mode registers, as read back from the chip
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modes {
+    pub mode1: u8,
+    pub mode2: u8,
+}
+
+impl Modes {
+    /// A restart is pending, the outputs were running when the chip was put to sleep
+    pub fn restart(&self) -> bool {
+        self.mode1 & RESTART != 0
+    }
+
+    /// Multi byte transfers move on through the registers
+    pub fn auto_increment(&self) -> bool {
+        self.mode1 & AI != 0
+    }
+
+    /// The oscillator is off, so are the outputs
+    pub fn sleep(&self) -> bool {
+        self.mode1 & SLEEP != 0
+    }
+
+    /// The chip answers to the all call address
+    pub fn all_call(&self) -> bool {
+        self.mode1 & ALLCALL != 0
+    }
+
+    /// The outputs are inverted
+    pub fn inverted(&self) -> bool {
+        self.mode2 & INVRT != 0
+    }
+
+    /// The outputs are totem pole rather than open drain
+    pub fn totem_pole(&self) -> bool {
+        self.mode2 & OUTDRV != 0
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error<E> {
+    I2C(E),
+    /// A register didn't read back what was written, only when verifying
+    Mismatch { register: u8, wrote: u8, read: u8 },
+    /// There is no such channel, they go from 0 to 15
+    Channel(u8),
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::I2C(ref e) => e.fmt(f),
+            Error::Mismatch { register, wrote, read } =>
+                write!(f, "Register 0x{:02X} read back 0x{:02X} after writing 0x{:02X}", register, read, wrote),
+            Error::Channel(channel) => write!(f, "There is no channel {}, they go from 0 to 15", channel),
+        }
+    }
+}
+
+impl<E: StdError> StdError for Error<E> {}
+
+/// Compares what was written from register on with what was read back.
+/// The restart bit of MODE1 clears itself, so it isn't compared.
+fn check<E>(register: u8, wrote: &[u8], read: &[u8]) -> Result<(), Error<E>> {
+    for (i, (&w, &r)) in wrote.iter().zip(read).enumerate() {
+        let address = register.wrapping_add(i as u8);
+        let mask = if address == MODE1 { !RESTART } else { 0xFF };
+        if w & mask != r & mask {
+            return Err(Error::Mismatch { register: address, wrote: w, read: r });
+        }
+    }
+    Ok(())
+}
+
+/// The first register of a channel, or an error for channels past 15
+fn channel_register<E>(channel: u8) -> Result<u8, Error<E>> {
+    if channel > 15 {
+        Err(Error::Channel(channel))
+    } else {
+        Ok(LED0_ON_L + 4 * channel)
+    }
+}
+
+/// Adds the on and off registers of a channel, in register order
+fn push_ticks(data: &mut Vec<u8>, on: u16, off: u16) {
+    data.extend_from_slice(&[(on & 0xFF) as u8, (on >> 8) as u8, (off & 0xFF) as u8, (off >> 8) as u8]);
+}
+
+/// Output frequency for a prescale value
+fn prescale_freq(prescale: u8) -> f32 {
+    OSC_HZ / 4096.0 / (prescale as f32 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_i2c::{MockI2CDevice, MockI2CError};
+
+    /// A chip as it comes out of power on reset, auto increment off
+    pub fn chip() -> MockI2CDevice {
+        let dev = MockI2CDevice::new();
+        dev.set_auto_increment(false);
+        dev.set_register(MODE1, SLEEP | ALLCALL);
+        dev.set_register(PRESCALE, 0x1E);
+        dev
+    }
+
+    #[test]
+    fn new_wakes_the_chip() {
+        let dev = chip();
+        let pca = PCA9685::with_device(dev.clone()).unwrap();
+        assert_eq!(dev.writes(), vec![
+            (MODE2, vec![OUTDRV]),
+            (MODE1, vec![ALLCALL | AI]),
+            (MODE1, vec![ALLCALL | AI]),
+        ]);
+        assert_eq!(dev.reads(), vec![(MODE1, 1), (PRESCALE, 1)]);
+        assert_eq!(dev.register(MODE1) & SLEEP, 0);
+        assert_eq!(pca.pwm_freq().round(), 197.0);
+    }
+
+    #[test]
+    fn set_pwm_freq_sleeps_to_change_prescale() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        //leave the register pointer somewhere other than MODE1
+        pca.set_pwm(2, 0, 2048).unwrap();
+        dev.clear_log();
+
+        let freq = pca.set_pwm_freq(50.0).unwrap();
+        //25MHz / 4096 / 50Hz - 1, rounded
+        assert_eq!(dev.writes(), vec![
+            (MODE1, vec![ALLCALL | AI | SLEEP]),
+            (PRESCALE, vec![121]),
+            (MODE1, vec![ALLCALL | AI]),
+            (MODE1, vec![ALLCALL | AI | 0x80]),
+        ]);
+        assert_eq!(dev.reads(), vec![(MODE1, 1)]);
+        assert_eq!(freq, pca.pwm_freq());
+        assert!((freq - 50.03).abs() < 0.01);
+    }
+
+    #[test]
+    fn set_pwm_freq_clamps_the_prescale() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+
+        //would be a prescale of 1
+        let freq = pca.set_pwm_freq(10000.0).unwrap();
+        assert_eq!(dev.register(PRESCALE), 3);
+        assert_eq!(freq.round(), 1526.0);
+
+        //would be a prescale of 609, which doesn't fit in the register
+        let freq = pca.set_pwm_freq(10.0).unwrap();
+        assert_eq!(dev.register(PRESCALE), 255);
+        assert_eq!(freq.round(), 24.0);
+    }
+
+    #[test]
+    fn state_reads_back() {
+        let dev = chip();
+        dev.set_auto_increment(true);
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        pca.set_pwm_freq(1000.0).unwrap();
+        pca.set_pwm_block(7, 4096, 0).unwrap();
+
+        assert_eq!(pca.get_pwm(7).unwrap(), (4096, 0));
+        assert_eq!(pca.get_prescale().unwrap(), 5);
+        //the prescaler can only get to 1017Hz
+        assert_eq!(pca.get_pwm_freq().unwrap().round(), 1017.0);
+        let modes = pca.get_modes().unwrap();
+        assert!(modes.auto_increment() && modes.all_call() && modes.totem_pole());
+        assert!(!modes.sleep() && !modes.inverted());
+    }
+
+    #[test]
+    fn verify_catches_mismatches() {
+        let dev = chip();
+        dev.set_auto_increment(true);
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        pca.set_verify(true);
+        //the restart bit clears itself, that isn't a mismatch
+        pca.set_pwm_freq(50.0).unwrap();
+        pca.set_pwms(&[(0, 0, 2048), (1, 0, 1024)]).unwrap();
+        assert_eq!(dev.reads().last(), Some(&(LED0_ON_L, 8)));
+
+        //a register that won't take what is written, as with a bad connection
+        dev.set_auto_increment(false);
+        assert_eq!(pca.set_pwm_block(2, 0, 2048), Err(Error::Mismatch {
+            register: LED0_ON_L + 8, wrote: 0, read: 0x08,
+        }));
+        pca.set_verify(false);
+        assert!(pca.set_pwm_block(2, 0, 2048).is_ok());
+    }
+
+    #[test]
+    fn set_pwm_writes_the_channel_registers() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+
+        pca.set_pwm(3, 0x123, 0xABC).unwrap();
+        pca.set_all_pwm_off().unwrap();
+        assert_eq!(dev.writes(), vec![
+            (LED0_ON_L + 12, vec![0x23]),
+            (LED0_ON_H + 12, vec![0x01]),
+            (LED0_OFF_L + 12, vec![0xBC]),
+            (LED0_OFF_H + 12, vec![0x0A]),
+            (ALL_LED_ON_L, vec![0]),
+            (ALL_LED_ON_H, vec![0]),
+            (ALL_LED_OFF_L, vec![0]),
+            (ALL_LED_OFF_H, vec![0x10]),
+        ]);
+    }
+
+    #[test]
+    fn block_writes_are_one_transaction() {
+        let dev = chip();
+        dev.set_auto_increment(true);
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+
+        pca.set_pwm_block(3, 0x123, 0xABC).unwrap();
+        pca.set_all_pwm_block(0, 4096).unwrap();
+        assert_eq!(dev.writes(), vec![
+            (LED0_ON_L + 12, vec![0x23, 0x01, 0xBC, 0x0A]),
+            (ALL_LED_ON_L, vec![0, 0, 0, 0x10]),
+        ]);
+        assert_eq!(dev.register(LED0_OFF_H + 12), 0x0A);
+    }
+
+    #[test]
+    fn set_pwms_batches_consecutive_channels() {
+        let dev = chip();
+        dev.set_auto_increment(true);
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+
+        pca.set_pwms(&[(11, 4096, 0), (10, 0, 2000), (12, 0, 4096), (15, 0, 1000), (11, 0, 4096)]).unwrap();
+        assert_eq!(dev.writes(), vec![
+            (LED0_ON_L + 40, vec![0, 0, 0xD0, 0x07, 0, 0, 0, 0x10, 0, 0, 0, 0x10]),
+            (LED0_ON_L + 60, vec![0, 0, 0xE8, 0x03]),
+        ]);
+        assert_eq!(dev.register(LED0_OFF_H + 48), 0x10);
+    }
+
+    #[test]
+    fn set_pwms_refuses_missing_channels() {
+        let dev = chip();
+        dev.set_auto_increment(true);
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+
+        //16 would otherwise wrap around to channel 0
+        assert_eq!(pca.set_pwms(&[(1, 0, 4096), (16, 0, 2000)]), Err(Error::Channel(16)));
+        assert_eq!(dev.writes(), vec![]);
+    }
+
+    #[test]
+    fn get_pwm_refuses_missing_channels() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+
+        //61 would otherwise read the ALL_LED registers
+        assert_eq!(pca.get_pwm(61), Err(Error::Channel(61)));
+        assert_eq!(dev.reads(), vec![]);
+    }
+
+    #[test]
+    fn set_pwm_refuses_missing_channels() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+
+        //62 would otherwise write the prescale
+        assert_eq!(pca.set_pwm(62, 0, 2000), Err(Error::Channel(62)));
+        assert_eq!(pca.set_pwm_off(16), Err(Error::Channel(16)));
+        assert_eq!(pca.set_pwm_on(255), Err(Error::Channel(255)));
+        assert_eq!(dev.writes(), vec![]);
+    }
+
+    #[test]
+    fn set_pwm_block_refuses_missing_channels() {
+        let dev = chip();
+        dev.set_auto_increment(true);
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+
+        assert_eq!(pca.set_pwm_block(16, 0, 2000), Err(Error::Channel(16)));
+        assert_eq!(dev.writes(), vec![]);
+    }
+
+    #[test]
+    fn errors_are_passed_on() {
+        let dev = chip();
+        dev.fail_register(MODE2);
+        assert_eq!(PCA9685::with_device(dev.clone()).err(), Some(Error::I2C(MockI2CError::Injected)));
+        dev.clear_failures();
+
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+        //the second byte fails, and nothing after it is written
+        dev.fail_after(1);
+        assert_eq!(pca.set_pwm(0, 0, 4096), Err(Error::I2C(MockI2CError::Injected)));
+        assert_eq!(dev.writes(), vec![(LED0_ON_L, vec![0])]);
+        assert!(pca.set_pwm(0, 0, 4096).is_ok());
+    }
+}
@@ -0,0 +1,236 @@
+//! Hobby servos on PCA9685 channels.
+//!
+//! A servo takes a pulse every 20ms or so, and the width of the pulse sets
+//! its angle. The chip counts in ticks of a 4096 tick period, so the pulse
+//! width has to be worked out against the frequency it is running at, which
+//! should be set to around 50Hz with set_pwm_freq before driving servos.
+
+use std::thread;
+use std::time::Duration;
+
+use i2cdev::core::I2CDevice;
+
+use {Error, PCA9685};
+
+/// Pulse widths that move a servo to either end of its travel.
+/// These vary a little between servos, even of the same model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ServoCalibration {
+    /// Pulse width for the most negative angle, in microseconds
+    pub min_us: f32,
+    /// Pulse width for the most positive angle, in microseconds
+    pub max_us: f32,
+    /// Angle between the two ends of travel, in degrees
+    pub range: f32,
+}
+
+impl Default for ServoCalibration {
+    /// The usual 1ms to 2ms over 180 degrees
+    fn default() -> ServoCalibration {
+        ServoCalibration {
+            min_us: 1000.0,
+            max_us: 2000.0,
+            range: 180.0,
+        }
+    }
+}
+
+impl ServoCalibration {
+    /// Pulse width for an angle from the centre of travel, clamped to the ends
+    pub fn pulse_us(&self, degrees: f32) -> f32 {
+        let half = self.range / 2.0;
+        let degrees = degrees.max(-half).min(half);
+        let centre = (self.min_us + self.max_us) / 2.0;
+        centre + degrees / self.range * (self.max_us - self.min_us)
+    }
+}
+
+/// A servo on one channel of a PCA9685.
+/// The chip is passed in to each call, so any number of servos can share one.
+#[derive(Clone, Debug)]
+pub struct Servo {
+    channel: u8,
+    calibration: ServoCalibration,
+    /// Angle from the centre the servo was last sent to, None until it has been
+    angle: Option<f32>,
+}
+
+impl Servo {
+    pub fn new(channel: u8, calibration: ServoCalibration) -> Servo {
+        Servo {
+            channel,
+            calibration,
+            angle: None,
+        }
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn calibration(&self) -> ServoCalibration {
+        self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: ServoCalibration) {
+        self.calibration = calibration;
+    }
+
+    /// Angle from the centre the servo was last sent to, in degrees.
+    /// None before it has been moved, or after a raw pulse or turning it off.
+    pub fn angle(&self) -> Option<f32> {
+        self.angle
+    }
+
+    /// Sends a pulse of the given width, clamped to the calibrated range
+    pub fn set_pulse_us<D: I2CDevice>(&mut self, pca: &mut PCA9685<D>, us: f32) -> Result<(), Error<D::Error>> {
+        let (low, high) = if self.calibration.min_us <= self.calibration.max_us {
+            (self.calibration.min_us, self.calibration.max_us)
+        } else {
+            (self.calibration.max_us, self.calibration.min_us)
+        };
+        let us = us.max(low).min(high);
+        let ticks = us_to_ticks(us, pca.pwm_freq());
+        pca.set_pwm(self.channel, 0, ticks)?;
+        self.angle = None;
+        Ok(())
+    }
+
+    /// Moves straight to an angle from the centre, in degrees.
+    /// Angles past either end of travel are clamped to it.
+    pub fn set_angle<D: I2CDevice>(&mut self, pca: &mut PCA9685<D>, degrees: f32) -> Result<(), Error<D::Error>> {
+        let half = self.calibration.range / 2.0;
+        let degrees = degrees.max(-half).min(half);
+        let ticks = us_to_ticks(self.calibration.pulse_us(degrees), pca.pwm_freq());
+        pca.set_pwm(self.channel, 0, ticks)?;
+        self.angle = Some(degrees);
+        Ok(())
+    }
+
+    /// Moves to an angle at no more than speed degrees per second, blocking
+    /// until it gets there. A step is sent every pwm period, as the servo
+    /// won't see changes any faster than that. If the servo hasn't been
+    /// moved yet where it is isn't known, so it is moved straight there.
+    pub fn sweep_to<D: I2CDevice>(&mut self, pca: &mut PCA9685<D>, degrees: f32, speed: f32)
+                                  -> Result<(), Error<D::Error>> {
+        let half = self.calibration.range / 2.0;
+        let target = degrees.max(-half).min(half);
+        let period = 1.0 / pca.pwm_freq();
+        let max_step = speed.abs() * period;
+        loop {
+            let step = match self.angle {
+                Some(angle) if max_step > 0.0 => step_towards(angle, target, max_step),
+                _ => target,
+            };
+            self.set_angle(pca, step)?;
+            if step == target {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis((period * 1000.0) as u64));
+        }
+    }
+
+    /// Stops sending pulses, letting the servo go limp
+    pub fn off<D: I2CDevice>(&mut self, pca: &mut PCA9685<D>) -> Result<(), Error<D::Error>> {
+        pca.set_pwm_off(self.channel)?;
+        self.angle = None;
+        Ok(())
+    }
+}
+
+/// Ticks a pulse of us microseconds lasts, at freq_hz
+fn us_to_ticks(us: f32, freq_hz: f32) -> u16 {
+    let ticks = (us * freq_hz * 4096.0 / 1000000.0).round();
+    ticks.clamp(0.0, 4095.0) as u16
+}
+
+/// Moves from towards target by at most max_step
+fn step_towards(from: f32, target: f32, max_step: f32) -> f32 {
+    if (target - from).abs() <= max_step {
+        target
+    } else if target > from {
+        from + max_step
+    } else {
+        from - max_step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::chip;
+    use {LED0_OFF_H, LED0_OFF_L};
+
+    /// The pulse width channel is set to, in ticks
+    fn off_ticks(dev: &::mock_i2c::MockI2CDevice, channel: u8) -> u16 {
+        dev.register(LED0_OFF_L + 4 * channel) as u16 | (dev.register(LED0_OFF_H + 4 * channel) as u16) << 8
+    }
+
+    #[test]
+    fn pulse_width_follows_frequency() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        pca.set_pwm_freq(50.0).unwrap();
+        let mut servo = Servo::new(1, ServoCalibration::default());
+
+        //1.5ms of a 20ms period
+        servo.set_angle(&mut pca, 0.0).unwrap();
+        assert_eq!(off_ticks(&dev, 1), 307);
+        servo.set_pulse_us(&mut pca, 1000.0).unwrap();
+        assert_eq!(off_ticks(&dev, 1), 205);
+        //twice the frequency, twice the ticks for the same pulse
+        pca.set_pwm_freq(100.0).unwrap();
+        servo.set_pulse_us(&mut pca, 1000.0).unwrap();
+        assert_eq!(off_ticks(&dev, 1), 410);
+    }
+
+    #[test]
+    fn angles_are_calibrated_and_clamped() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        pca.set_pwm_freq(50.0).unwrap();
+        let calibration = ServoCalibration { min_us: 600.0, max_us: 2400.0, range: 180.0 };
+        let mut servo = Servo::new(0, calibration);
+
+        assert_eq!(calibration.pulse_us(45.0), 1950.0);
+        servo.set_angle(&mut pca, 120.0).unwrap();
+        assert_eq!(servo.angle(), Some(90.0));
+        assert_eq!(off_ticks(&dev, 0), 492);
+        servo.set_pulse_us(&mut pca, 100.0).unwrap();
+        assert_eq!(off_ticks(&dev, 0), 123);
+        assert_eq!(servo.angle(), None);
+    }
+
+    #[test]
+    fn sweeps_are_speed_limited() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        pca.set_pwm_freq(50.0).unwrap();
+        let mut servo = Servo::new(0, ServoCalibration::default());
+        servo.set_angle(&mut pca, 0.0).unwrap();
+        dev.clear_log();
+
+        //20ms periods at 500 deg/s is 10 degrees a step
+        servo.sweep_to(&mut pca, 45.0, 500.0).unwrap();
+        assert_eq!(servo.angle(), Some(45.0));
+        let steps: Vec<_> = dev.writes().into_iter()
+            .filter(|&(register, _)| register == LED0_OFF_L)
+            .map(|(_, data)| data)
+            .collect();
+        assert_eq!(steps.len(), 5);
+    }
+
+    #[test]
+    fn missing_channels_are_refused() {
+        let dev = chip();
+        let mut pca = PCA9685::with_device(dev.clone()).unwrap();
+        dev.clear_log();
+        let mut servo = Servo::new(16, ServoCalibration::default());
+
+        assert_eq!(servo.set_angle(&mut pca, 0.0), Err(Error::Channel(16)));
+        assert_eq!(servo.set_pulse_us(&mut pca, 1500.0), Err(Error::Channel(16)));
+        assert_eq!(servo.off(&mut pca), Err(Error::Channel(16)));
+        assert_eq!(servo.angle(), None);
+        assert_eq!(dev.writes(), vec![]);
+    }
+}
@@ -0,0 +1,123 @@
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+
+use hardware_interface::{RTEvent, SensorState};
+use super::{Behavior, BehaviorKind, DriveTarget, wrap_heading};
+
+/// How long to back up for after finding an obstacle
+const BACKUP_TIME_MS: u64 = 750;
+const BACKUP_SPEED: f32 = -0.25;
+/// How far to turn after backing up, in degrees
+const TURN_DEGREES: f32 = 55.0;
+const CRUISE_SPEED: f32 = 0.375;
+
+enum State {
+    Driving,
+    BackingUp,
+    Turning,
+}
+
+/// When driving forward into something (or up something too steep) backs
+/// up for a moment, turns a bit, and carries on.
+pub struct Bounce {
+    state: State,
+}
+
+impl Default for Bounce {
+    fn default() -> Bounce {
+        Bounce::new()
+    }
+}
+
+impl Bounce {
+    pub fn new() -> Bounce {
+        Bounce { state: State::Driving }
+    }
+
+    fn back_up(&mut self, sensors: &mut SensorState, drive: DriveTarget) -> Option<DriveTarget> {
+        if drive.speed <= 0.0 {
+            return None;
+        }
+        self.state = State::BackingUp;
+        sensors.set_target_time(Instant::now() + Duration::from_millis(BACKUP_TIME_MS));
+        Some(DriveTarget { speed: BACKUP_SPEED, ..drive })
+    }
+}
+
+impl Behavior for Bounce {
+    fn name(&self) -> &'static str {
+        BehaviorKind::Bounce.name()
+    }
+
+    fn handle_event(&mut self, event: &RTEvent, sensors: &mut SensorState, drive: DriveTarget) -> Option<DriveTarget> {
+        match *event {
+            RTEvent::SonarProximity => {
+                let target = self.back_up(sensors, drive);
+                if target.is_some() {
+                    eprintln!("Detected nearby object");
+                }
+                target
+            },
+            RTEvent::SteepIncline => {
+                let target = self.back_up(sensors, drive);
+                if target.is_some() {
+                    eprintln!("Too steep a climb");
+                }
+                target
+            },
+            RTEvent::TargetTimeReached => match self.state {
+                State::BackingUp => {
+                    self.state = State::Turning;
+                    let turn = wrap_heading(drive.turn + TURN_DEGREES * PI / 180.0);
+                    sensors.set_target_angle(turn);
+                    Some(DriveTarget { speed: 0.0, turn })
+                },
+                _ => None,
+            },
+            RTEvent::TargetAngleReached => match self.state {
+                State::Turning => {
+                    self.state = State::Driving;
+                    Some(DriveTarget { speed: CRUISE_SPEED, ..drive })
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::sensors;
+
+    #[test]
+    fn backs_up_then_turns() {
+        let mut bounce = Bounce::default();
+        let mut s = sensors(0.0, 5.0);
+        let drive = DriveTarget { speed: CRUISE_SPEED, turn: 1.0 };
+
+        let back = bounce.handle_event(&RTEvent::SonarProximity, &mut s, drive).unwrap();
+        assert_eq!((back.speed, back.turn), (BACKUP_SPEED, 1.0));
+        //already going backwards, so nothing more to do
+        assert!(bounce.handle_event(&RTEvent::SonarProximity, &mut s, back).is_none());
+        //the turn only comes after backing up
+        assert!(bounce.handle_event(&RTEvent::TargetAngleReached, &mut s, back).is_none());
+
+        let turn = bounce.handle_event(&RTEvent::TargetTimeReached, &mut s, back).unwrap();
+        assert_eq!(turn.speed, 0.0);
+        assert!((turn.turn - (1.0 + TURN_DEGREES.to_radians())).abs() < 1e-4);
+
+        let cruise = bounce.handle_event(&RTEvent::TargetAngleReached, &mut s, turn).unwrap();
+        assert_eq!((cruise.speed, cruise.turn), (CRUISE_SPEED, turn.turn));
+    }
+
+    #[test]
+    fn only_bounces_going_forward() {
+        let mut bounce = Bounce::new();
+        let mut s = sensors(0.0, 5.0);
+        let stopped = DriveTarget { speed: 0.0, turn: 0.0 };
+        assert!(bounce.handle_event(&RTEvent::SteepIncline, &mut s, stopped).is_none());
+        assert!(bounce.handle_event(&RTEvent::TargetTimeReached, &mut s, stopped).is_none());
+    }
+}
@@ -0,0 +1,112 @@
+//! Autonomous behaviors of the tank.
+//! A behavior watches the real time events and sensor state, and decides
+//! where the tank should drive. Only one behavior runs at a time, and
+//! it is paused while a mission is running.
+
+use std::f32::consts::PI;
+
+use hardware_interface::{RTEvent, SensorState};
+
+mod bounce;
+mod wander;
+mod wall_follow;
+
+pub use self::bounce::Bounce;
+pub use self::wander::Wander;
+pub use self::wall_follow::WallFollow;
+pub use tank_protocol::messages::BehaviorKind;
+
+/// Drive settings a behavior asks for
+#[derive(Clone, Copy, Debug)]
+pub struct DriveTarget {
+    /// Forward speed in m/s, negative to reverse
+    pub speed: f32,
+    /// Heading to hold, in radians
+    pub turn: f32,
+}
+
+pub trait Behavior {
+    fn name(&self) -> &'static str;
+
+    /// Called when the behavior is selected
+    fn start(&mut self, _sensors: &mut SensorState, _drive: DriveTarget) -> Option<DriveTarget> {
+        None
+    }
+
+    /// Called once per loop with the latest sensor state
+    fn update(&mut self, _sensors: &mut SensorState, _drive: DriveTarget) -> Option<DriveTarget> {
+        None
+    }
+
+    /// Called for each event from the real time thread
+    fn handle_event(&mut self, event: &RTEvent, sensors: &mut SensorState, drive: DriveTarget) -> Option<DriveTarget>;
+}
+
+/// Starts a new instance of the behavior
+pub fn create(kind: BehaviorKind) -> Box<dyn Behavior> {
+    match kind {
+        BehaviorKind::Manual => Box::new(Manual),
+        BehaviorKind::Bounce => Box::new(Bounce::new()),
+        BehaviorKind::Wander => Box::new(Wander::new()),
+        BehaviorKind::WallFollow => Box::new(WallFollow::new()),
+    }
+}
+
+/// Leaves driving entirely up to the user
+pub struct Manual;
+
+impl Behavior for Manual {
+    fn name(&self) -> &'static str {
+        BehaviorKind::Manual.name()
+    }
+
+    fn handle_event(&mut self, _event: &RTEvent, _sensors: &mut SensorState, _drive: DriveTarget) -> Option<DriveTarget> {
+        None
+    }
+}
+
+/// Wraps an angle in radians into the range [0, 2PI)
+fn wrap_heading(radians: f32) -> f32 {
+    let a = radians % (2.0 * PI);
+    if a < 0.0 { a + 2.0 * PI } else { a }
+}
+
+/// Smallest difference between two headings, in radians
+fn heading_error(target: f32, actual: f32) -> f32 {
+    let a = wrap_heading(target - actual);
+    if a > PI { a - 2.0 * PI } else { a }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use hardware_interface::{RawSensorState, Vec3};
+
+    /// Sensors of a tank on level ground facing yaw, with the sonar reading sonar_cm
+    pub fn sensors(yaw: f32, sonar_cm: f32) -> SensorState {
+        let mut sensors = SensorState::default();
+        reading(&mut sensors, Instant::now(), yaw);
+        //the sonar is averaged, so takes a few readings to settle
+        for _ in 0..30 {
+            sensors.set_sonar((sonar_cm, Instant::now()));
+        }
+        sensors
+    }
+
+    /// Feeds the sensors an IMU reading at time, facing yaw
+    pub fn reading(sensors: &mut SensorState, time: Instant, yaw: f32) {
+        sensors.update(RawSensorState {
+            time,
+            orientation: Vec3 { x: yaw, y: 0.0, z: 0.0 },
+            ..RawSensorState::default()
+        }, 0.0);
+    }
+
+    #[test]
+    fn headings_wrap() {
+        assert!((wrap_heading(-PI / 2.0) - 1.5 * PI).abs() < 1e-5);
+        assert!((heading_error(0.1, 2.0 * PI - 0.1) - 0.2).abs() < 1e-5);
+        assert!((heading_error(2.0 * PI - 0.1, 0.1) + 0.2).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,114 @@
+use std::f32::consts::PI;
+
+use floating_duration::TimeAsFloat;
+
+use hardware_interface::{RTEvent, SensorState};
+use super::{Behavior, BehaviorKind, DriveTarget, wrap_heading};
+
+const FOLLOW_SPEED: f32 = 0.2;
+const BACKUP_SPEED: f32 = -0.15;
+/// Sonar distance (in cm) at which the wall counts as being ahead
+const WALL_NEAR: f32 = 30.0;
+/// How fast to drift toward the wall, in radians/s
+const DRIFT_RATE: f32 = 0.3;
+/// How far to steer away from the wall once it is ahead, in radians
+const STEER_AWAY: f32 = PI / 6.0;
+
+/// Follows a wall on the right hand side using the front sonar.
+/// Drifts toward the wall until the sonar sees it ahead, then steers away
+/// from it, giving a gentle zig-zag along the wall.
+/// The heading increases clockwise, so toward the wall is a larger heading.
+pub struct WallFollow {
+    backing_up: bool,
+}
+
+impl Default for WallFollow {
+    fn default() -> WallFollow {
+        WallFollow::new()
+    }
+}
+
+impl WallFollow {
+    pub fn new() -> WallFollow {
+        WallFollow { backing_up: false }
+    }
+}
+
+impl Behavior for WallFollow {
+    fn name(&self) -> &'static str {
+        BehaviorKind::WallFollow.name()
+    }
+
+    fn start(&mut self, sensors: &mut SensorState, _drive: DriveTarget) -> Option<DriveTarget> {
+        self.backing_up = false;
+        Some(DriveTarget { speed: FOLLOW_SPEED, turn: sensors.yaw() })
+    }
+
+    fn update(&mut self, sensors: &mut SensorState, drive: DriveTarget) -> Option<DriveTarget> {
+        let near = sensors.sonar() < WALL_NEAR;
+        if self.backing_up {
+            if near {
+                return None;
+            }
+            self.backing_up = false;
+        }
+        if near {
+            //turn left, away from the wall
+            Some(DriveTarget { speed: FOLLOW_SPEED, turn: wrap_heading(sensors.yaw() - STEER_AWAY) })
+        } else {
+            let dt = sensors.duration().as_fractional_secs() as f32;
+            Some(DriveTarget { speed: FOLLOW_SPEED, turn: wrap_heading(drive.turn + DRIFT_RATE * dt) })
+        }
+    }
+
+    fn handle_event(&mut self, event: &RTEvent, _sensors: &mut SensorState, drive: DriveTarget) -> Option<DriveTarget> {
+        match *event {
+            RTEvent::SonarProximity | RTEvent::SteepIncline if !self.backing_up => {
+                self.backing_up = true;
+                Some(DriveTarget { speed: BACKUP_SPEED, ..drive })
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use super::super::tests::{sensors, reading};
+
+    #[test]
+    fn zig_zags_along_the_wall() {
+        let mut follow = WallFollow::default();
+        let mut s = sensors(1.0, 100.0);
+        let drive = follow.start(&mut s, DriveTarget { speed: 0.0, turn: 0.0 }).unwrap();
+        assert_eq!((drive.speed, drive.turn), (FOLLOW_SPEED, 1.0));
+
+        //drifts clockwise, toward the wall on the right
+        let time = *s.time();
+        reading(&mut s, time + Duration::from_millis(100), 1.0);
+        let drift = follow.update(&mut s, drive).unwrap();
+        assert!((drift.turn - (1.0 + DRIFT_RATE * 0.1)).abs() < 1e-4);
+
+        //and turns left once the wall is ahead
+        let mut s = sensors(1.0, WALL_NEAR / 2.0);
+        let away = follow.update(&mut s, drift).unwrap();
+        assert!((away.turn - (1.0 - STEER_AWAY)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn backs_up_until_clear() {
+        let mut follow = WallFollow::new();
+        let mut s = sensors(0.5, 5.0);
+        let drive = DriveTarget { speed: FOLLOW_SPEED, turn: 0.5 };
+        let back = follow.handle_event(&RTEvent::SonarProximity, &mut s, drive).unwrap();
+        assert_eq!((back.speed, back.turn), (BACKUP_SPEED, 0.5));
+        //only once
+        assert!(follow.handle_event(&RTEvent::SonarProximity, &mut s, back).is_none());
+        assert!(follow.update(&mut s, back).is_none());
+
+        let mut s = sensors(0.5, 100.0);
+        assert_eq!(follow.update(&mut s, back).unwrap().speed, FOLLOW_SPEED);
+    }
+}
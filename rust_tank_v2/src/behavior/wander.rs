@@ -0,0 +1,144 @@
+use std::f32::consts::PI;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hardware_interface::{RTEvent, SensorState};
+use super::{Behavior, BehaviorKind, DriveTarget, wrap_heading, heading_error};
+
+const WANDER_SPEED: f32 = 0.25;
+/// How often to pick a new heading
+const CHANGE_INTERVAL_MS: u64 = 3000;
+/// Largest change of heading when picking a new one, in degrees
+const MAX_DRIFT_DEGREES: f32 = 90.0;
+/// Largest deviation from turning straight around at an obstacle, in degrees
+const MAX_BOUNCE_DEGREES: f32 = 45.0;
+/// How close to the new heading counts as done turning
+const TURNED_EPSILON: f32 = PI / 16.0;
+
+enum State {
+    Driving,
+    Turning,
+}
+
+/// Drives around aimlessly, picking a new random heading every so often
+/// and turning around when it finds an obstacle.
+pub struct Wander {
+    state: State,
+    next_change: Instant,
+    rng: u32,
+}
+
+impl Default for Wander {
+    fn default() -> Wander {
+        Wander::new()
+    }
+}
+
+impl Wander {
+    pub fn new() -> Wander {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        Wander {
+            state: State::Driving,
+            next_change: Instant::now(),
+            rng: seed | 1, //xorshift gets stuck on 0
+        }
+    }
+
+    /// Random number in [-1, 1]
+    fn random(&mut self) -> f32 {
+        //xorshift32
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+
+    fn drive(&mut self, sensors: &SensorState, turn: f32) -> Option<DriveTarget> {
+        self.state = State::Driving;
+        self.next_change = *sensors.time() + Duration::from_millis(CHANGE_INTERVAL_MS);
+        Some(DriveTarget { speed: WANDER_SPEED, turn })
+    }
+}
+
+impl Behavior for Wander {
+    fn name(&self) -> &'static str {
+        BehaviorKind::Wander.name()
+    }
+
+    fn start(&mut self, sensors: &mut SensorState, _drive: DriveTarget) -> Option<DriveTarget> {
+        let turn = sensors.yaw();
+        self.drive(sensors, turn)
+    }
+
+    fn update(&mut self, sensors: &mut SensorState, drive: DriveTarget) -> Option<DriveTarget> {
+        match self.state {
+            State::Driving if *sensors.time() >= self.next_change => {
+                let drift = self.random() * MAX_DRIFT_DEGREES * PI / 180.0;
+                self.drive(sensors, wrap_heading(drive.turn + drift))
+            },
+            State::Turning if heading_error(drive.turn, sensors.yaw()).abs() < TURNED_EPSILON => {
+                self.drive(sensors, drive.turn)
+            },
+            _ => None,
+        }
+    }
+
+    fn handle_event(&mut self, event: &RTEvent, _sensors: &mut SensorState, drive: DriveTarget) -> Option<DriveTarget> {
+        match (event, &self.state) {
+            (&RTEvent::SonarProximity, &State::Driving) | (&RTEvent::SteepIncline, &State::Driving) => {
+                self.state = State::Turning;
+                let bounce = self.random() * MAX_BOUNCE_DEGREES * PI / 180.0;
+                Some(DriveTarget { speed: 0.0, turn: wrap_heading(drive.turn + PI + bounce) })
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::{sensors, reading};
+
+    #[test]
+    fn random_stays_in_range() {
+        let mut wander = Wander::default();
+        for _ in 0..1000 {
+            let r = wander.random();
+            assert!((-1.0..=1.0).contains(&r));
+        }
+    }
+
+    #[test]
+    fn picks_new_headings_over_time() {
+        let mut wander = Wander::new();
+        let mut s = sensors(2.0, 100.0);
+        let drive = wander.start(&mut s, DriveTarget { speed: 0.0, turn: 0.0 }).unwrap();
+        assert_eq!((drive.speed, drive.turn), (WANDER_SPEED, 2.0));
+        assert!(wander.update(&mut s, drive).is_none());
+
+        let later = *s.time() + Duration::from_millis(CHANGE_INTERVAL_MS);
+        reading(&mut s, later, 2.0);
+        let change = wander.update(&mut s, drive).unwrap();
+        assert_eq!(change.speed, WANDER_SPEED);
+        assert!(heading_error(change.turn, 2.0).abs() <= MAX_DRIFT_DEGREES.to_radians() + 1e-4);
+    }
+
+    #[test]
+    fn turns_around_at_obstacles() {
+        let mut wander = Wander::new();
+        let mut s = sensors(0.0, 5.0);
+        let drive = wander.start(&mut s, DriveTarget { speed: 0.0, turn: 0.0 }).unwrap();
+
+        let turn = wander.handle_event(&RTEvent::SonarProximity, &mut s, drive).unwrap();
+        assert_eq!(turn.speed, 0.0);
+        assert!(heading_error(turn.turn, PI).abs() <= MAX_BOUNCE_DEGREES.to_radians() + 1e-4);
+        //keeps turning until it gets there
+        assert!(wander.handle_event(&RTEvent::SonarProximity, &mut s, turn).is_none());
+        assert!(wander.update(&mut s, turn).is_none());
+
+        let mut s = sensors(turn.turn, 100.0);
+        assert_eq!(wander.update(&mut s, turn).unwrap().speed, WANDER_SPEED);
+    }
+}
@@ -0,0 +1,548 @@
+//! Settings that differ between chassis: wiring, gains and thresholds.
+//! Loaded from a TOML file at startup, anything left out of the file
+//! keeps its default value.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+
+use toml;
+
+use ::csv_log::Column;
+
+/// Where the config is looked for when no path is given
+pub const DEFAULT_PATH: &str = "tank.toml";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub motors: MotorConfig,
+    pub sonar: SonarConfig,
+    pub encoders: EncoderConfig,
+    pub pid: PidConfig,
+    pub speed: SpeedConfig,
+    pub safety: SafetyConfig,
+    pub tcp: TcpConfig,
+    pub real_time: RealTimeConfig,
+    pub csv: CsvConfig,
+}
+
+/// PCA9685 channels of the motor driver
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotorConfig {
+    pub left_pwm: u8,
+    pub left_a: u8,
+    pub left_b: u8,
+    pub right_pwm: u8,
+    pub right_a: u8,
+    pub right_b: u8,
+    /// PWM frequency in Hz
+    pub pwm_freq: f32,
+    /// Read back every write to the PWM driver, reporting any that didn't take as errors
+    pub verify_pwm: bool,
+}
+
+impl Default for MotorConfig {
+    fn default() -> MotorConfig {
+        MotorConfig {
+            left_pwm: 15,
+            left_a: 14,
+            left_b: 13,
+            right_pwm: 10,
+            right_a: 11,
+            right_b: 12,
+            pwm_freq: 120.0,
+            verify_pwm: false,
+        }
+    }
+}
+
+/// GPIO pins of the sonar, and how close is too close
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SonarConfig {
+    pub trigger_pin: u64,
+    pub echo_pin: u64,
+    /// Distance in cm
+    pub too_close: f32,
+}
+
+impl Default for SonarConfig {
+    fn default() -> SonarConfig {
+        SonarConfig {
+            trigger_pin: 18,
+            echo_pin: 25,
+            too_close: 7.5,
+        }
+    }
+}
+
+/// Track encoders, on GPIO pins like the sonar
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncoderConfig {
+    /// False when the chassis has no encoders
+    pub enabled: bool,
+    pub left_a: u64,
+    /// Only set for quadrature encoders
+    pub left_b: Option<u64>,
+    pub right_a: u64,
+    /// Only set for quadrature encoders
+    pub right_b: Option<u64>,
+    /// Ticks counted per meter of track travel
+    pub ticks_per_meter: f32,
+    /// How often the tick counts are read
+    pub sample_hz: f32,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> EncoderConfig {
+        EncoderConfig {
+            enabled: false,
+            left_a: 23,
+            left_b: None,
+            right_a: 24,
+            right_b: None,
+            ticks_per_meter: 1000.0,
+            sample_hz: 50.0,
+        }
+    }
+}
+
+impl EncoderConfig {
+    /// True when both tracks have quadrature encoders, which count backwards as well as forwards
+    pub fn quadrature(&self) -> bool {
+        self.left_b.is_some() && self.right_b.is_some()
+    }
+}
+
+/// Gains and limits of the heading PID
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PidConfig {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    /// Largest correction the PID can apply to the motor powers
+    pub output_limit: f32,
+    /// Largest the integral of the heading error can grow, in radian seconds
+    pub integral_limit: f32,
+    /// Time constant of the low pass filter on the derivative, in seconds
+    pub derivative_filter: f32,
+}
+
+impl Default for PidConfig {
+    fn default() -> PidConfig {
+        PidConfig {
+            kp: 2.0,
+            ki: 1.0,
+            kd: 0.0,
+            output_limit: 1.0,
+            integral_limit: 0.5,
+            derivative_filter: 0.05,
+        }
+    }
+}
+
+/// Forward speed controller
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeedConfig {
+    /// Speed at full power on flat ground, in m/s
+    pub max_speed: f32,
+    pub kp: f32,
+    pub ki: f32,
+    /// Largest the integral of the speed error can grow, in meters
+    pub integral_limit: f32,
+}
+
+impl Default for SpeedConfig {
+    fn default() -> SpeedConfig {
+        SpeedConfig {
+            max_speed: 0.5,
+            kp: 1.0,
+            ki: 2.0,
+            integral_limit: 0.25,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafetyConfig {
+    /// Pitch in radians below which the tank is climbing too steep a slope
+    pub pitch_too_neg: f32,
+    /// Seconds without a drive command before the real time thread turns the motors off
+    pub watchdog_timeout: f32,
+    /// Seconds the controlling TCP client can go quiet while the tank is
+    /// moving, or running a behavior, it asked for before it is stopped.
+    /// 0 turns this off
+    pub heartbeat_timeout: f32,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> SafetyConfig {
+        SafetyConfig {
+            pitch_too_neg: -0.2,
+            watchdog_timeout: 0.5,
+            heartbeat_timeout: 1.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    /// Address the command interface listens on
+    pub bind: String,
+}
+
+impl Default for TcpConfig {
+    fn default() -> TcpConfig {
+        TcpConfig {
+            bind: String::from("0.0.0.0:27272"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RealTimeConfig {
+    /// How often the I2C devices are read and written
+    pub loop_hz: f32,
+}
+
+impl Default for RealTimeConfig {
+    fn default() -> RealTimeConfig {
+        RealTimeConfig {
+            loop_hz: 60.0,
+        }
+    }
+}
+
+/// What goes in the CSV log
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsvConfig {
+    /// Columns to write, in order
+    pub columns: Vec<Column>,
+    /// Directory the logs are written to, they are never written anywhere else
+    pub dir: String,
+}
+
+impl Default for CsvConfig {
+    fn default() -> CsvConfig {
+        CsvConfig {
+            columns: Column::ALL.to_vec(),
+            dir: String::from("csv"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read
+    Io(String, io::Error),
+    /// The file is not valid TOML, or has fields of the wrong type
+    Parse(String, toml::de::Error),
+    /// The file parsed, but some values make no sense
+    Invalid(String, Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref e) => write!(f, "Could not read config '{}': {}", path, e),
+            ConfigError::Parse(ref path, ref e) => write!(f, "Could not parse config '{}': {}", path, e),
+            ConfigError::Invalid(ref path, ref problems) => {
+                write!(f, "Invalid config '{}':", path)?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Loads and validates the config file at path
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| ConfigError::Io(String::from(path), e))?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|e| ConfigError::Parse(String::from(path), e))?;
+        let problems = config.validate();
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(String::from(path), problems))
+        }
+    }
+
+    /// Loads the config at path if one is given, otherwise loads
+    /// DEFAULT_PATH if it exists, otherwise uses the defaults.
+    pub fn load_or_default(path: Option<&str>) -> Result<Config, ConfigError> {
+        match path {
+            Some(path) => Config::load(path),
+            None => match Config::load(DEFAULT_PATH) {
+                Err(ConfigError::Io(_, ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+                result => result,
+            },
+        }
+    }
+
+    /// Writes the PID gains into the config file at path, leaving the rest
+    /// of the file, comments and all, as it was
+    pub fn save_pid_gains(&self, path: &str) -> io::Result<()> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        let text = set_pid_gains(&text, &self.pid);
+        //never leave behind a file the tank can't start with
+        toml::from_str::<Config>(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        File::create(path)?.write_all(text.as_bytes())
+    }
+
+    /// Returns a description of each problem with the config
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        let m = &self.motors;
+        let channels = [
+            ("motors.left_pwm", m.left_pwm), ("motors.left_a", m.left_a), ("motors.left_b", m.left_b),
+            ("motors.right_pwm", m.right_pwm), ("motors.right_a", m.right_a), ("motors.right_b", m.right_b),
+        ];
+        let mut used = HashSet::new();
+        for &(name, channel) in channels.iter() {
+            if channel > 15 {
+                problems.push(format!("{} is {}, PCA9685 channels are 0 to 15", name, channel));
+            } else if !used.insert(channel) {
+                problems.push(format!("{} is {}, which is already used by another motor channel", name, channel));
+            }
+        }
+        //limits of the PCA9685 prescaler
+        if !(m.pwm_freq >= 24.0 && m.pwm_freq <= 1526.0) {
+            problems.push(format!("motors.pwm_freq is {}, should be between 24 and 1526 Hz", m.pwm_freq));
+        }
+
+        if self.sonar.trigger_pin == self.sonar.echo_pin {
+            problems.push(format!("sonar.trigger_pin and sonar.echo_pin are both {}", self.sonar.echo_pin));
+        }
+        if !is_positive(self.sonar.too_close) {
+            problems.push(format!("sonar.too_close is {}, should be a positive distance in cm", self.sonar.too_close));
+        }
+
+        let e = &self.encoders;
+        if e.enabled {
+            let mut pins = vec![
+                ("sonar.trigger_pin", self.sonar.trigger_pin), ("sonar.echo_pin", self.sonar.echo_pin),
+                ("encoders.left_a", e.left_a), ("encoders.right_a", e.right_a),
+            ];
+            pins.extend(e.left_b.map(|pin| ("encoders.left_b", pin)));
+            pins.extend(e.right_b.map(|pin| ("encoders.right_b", pin)));
+            let mut used = HashSet::new();
+            for &(name, pin) in pins.iter() {
+                if !used.insert(pin) && name.starts_with("encoders") {
+                    problems.push(format!("{} is {}, which is already used by another pin", name, pin));
+                }
+            }
+            if e.left_b.is_some() != e.right_b.is_some() {
+                problems.push(String::from("encoders.left_b and encoders.right_b should both be set for quadrature \
+                                            encoders, or both left out"));
+            }
+            if !is_positive(e.ticks_per_meter) {
+                problems.push(format!("encoders.ticks_per_meter is {}, should be positive", e.ticks_per_meter));
+            }
+            if !(e.sample_hz > 0.0 && e.sample_hz <= 1000.0) {
+                problems.push(format!("encoders.sample_hz is {}, should be between 0 and 1000", e.sample_hz));
+            }
+        }
+
+        for &(name, gain) in [("pid.kp", self.pid.kp), ("pid.ki", self.pid.ki), ("pid.kd", self.pid.kd)].iter() {
+            if !gain.is_finite() {
+                problems.push(format!("{} is {}, should be a number", name, gain));
+            }
+        }
+
+        if !is_positive(self.pid.output_limit) {
+            problems.push(format!("pid.output_limit is {}, should be positive", self.pid.output_limit));
+        }
+        if !is_non_negative(self.pid.integral_limit) {
+            problems.push(format!("pid.integral_limit is {}, should not be negative", self.pid.integral_limit));
+        }
+        if !is_non_negative(self.pid.derivative_filter) {
+            problems.push(format!("pid.derivative_filter is {}, should be a time in seconds",
+                                  self.pid.derivative_filter));
+        }
+
+        if !is_positive(self.speed.max_speed) {
+            problems.push(format!("speed.max_speed is {}, should be a positive speed in m/s", self.speed.max_speed));
+        }
+        for &(name, gain) in [("speed.kp", self.speed.kp), ("speed.ki", self.speed.ki)].iter() {
+            if !gain.is_finite() {
+                problems.push(format!("{} is {}, should be a number", name, gain));
+            }
+        }
+        if !is_non_negative(self.speed.integral_limit) {
+            problems.push(format!("speed.integral_limit is {}, should not be negative", self.speed.integral_limit));
+        }
+
+        if !is_non_positive(self.safety.pitch_too_neg) {
+            problems.push(format!("safety.pitch_too_neg is {}, should be a negative angle in radians",
+                                  self.safety.pitch_too_neg));
+        }
+        //the main loop sends drive commands every real time tick
+        let tick = 1.0 / self.real_time.loop_hz;
+        if !(self.safety.watchdog_timeout > tick && self.safety.watchdog_timeout.is_finite()) {
+            problems.push(format!("safety.watchdog_timeout is {}, should be longer than a real time tick ({}s)",
+                                  self.safety.watchdog_timeout, tick));
+        }
+        if !(self.safety.heartbeat_timeout >= 0.0 && self.safety.heartbeat_timeout.is_finite()) {
+            problems.push(format!("safety.heartbeat_timeout is {}, should be a time in seconds, or 0 for none",
+                                  self.safety.heartbeat_timeout));
+        }
+
+        if let Err(e) = self.tcp.bind.parse::<SocketAddr>() {
+            problems.push(format!("tcp.bind is '{}', should be an ip:port address ({})", self.tcp.bind, e));
+        }
+
+        if !(self.real_time.loop_hz > 0.0 && self.real_time.loop_hz <= 1000.0) {
+            problems.push(format!("real_time.loop_hz is {}, should be between 0 and 1000",
+                                  self.real_time.loop_hz));
+        }
+
+        if self.csv.columns.is_empty() {
+            problems.push(String::from("csv.columns is empty, should list at least one column"));
+        }
+        if self.csv.dir.is_empty() {
+            problems.push(String::from("csv.dir is empty, should be the directory to write CSV logs to"));
+        }
+
+        problems
+    }
+}
+
+/// Sets kp, ki and kd in the [pid] table of a TOML document, keeping
+/// any comment after them. Gains missing from the table are added to the
+/// top of it, and the table is added to the end if there isn't one.
+fn set_pid_gains(text: &str, pid: &PidConfig) -> String {
+    let mut gains = vec![("kp", pid.kp), ("ki", pid.ki), ("kd", pid.kd)];
+    let mut lines: Vec<String> = vec![];
+    let mut pid_header = None;
+    let mut in_pid = false;
+    for line in text.lines() {
+        let content = line.split('#').next().unwrap_or("").trim();
+        if content.starts_with('[') {
+            in_pid = content == "[pid]";
+            if in_pid {
+                pid_header = Some(lines.len());
+            }
+        } else if in_pid {
+            let key = content.split('=').next().unwrap_or("").trim();
+            if let Some(i) = gains.iter().position(|&(name, _)| name == key) {
+                let (name, gain) = gains.remove(i);
+                let comment = line.find('#').map(|i| format!(" {}", &line[i..])).unwrap_or_default();
+                lines.push(format!("{} = {:?}{}", name, gain, comment));
+                continue;
+            }
+        }
+        lines.push(String::from(line));
+    }
+
+    let missing = gains.iter().map(|&(name, gain)| format!("{} = {:?}", name, gain));
+    match pid_header {
+        Some(header) => {
+            let at = header + 1;
+            lines.splice(at..at, missing);
+        },
+        None => {
+            if !lines.is_empty() && !lines[lines.len() - 1].trim().is_empty() {
+                lines.push(String::new());
+            }
+            lines.push(String::from("[pid]"));
+            lines.extend(missing);
+        },
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+/// True for numbers above zero. NaN compares false with everything, so is rejected.
+fn is_positive(x: f32) -> bool {
+    x > 0.0
+}
+
+/// True for zero and numbers above it, rejecting NaN like is_positive
+fn is_non_negative(x: f32) -> bool {
+    x >= 0.0
+}
+
+/// True for zero and numbers below it, rejecting NaN like is_positive
+fn is_non_positive(x: f32) -> bool {
+    x <= 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_empty());
+    }
+
+    #[test]
+    fn duplicate_channels_are_invalid() {
+        let mut config = Config::default();
+        config.motors.right_b = config.motors.left_pwm;
+        let problems = config.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("motors.right_b is 15, which is already used"));
+    }
+
+    #[test]
+    fn non_positive_values_are_invalid() {
+        let mut config = Config::default();
+        config.motors.pwm_freq = 0.0;
+        config.sonar.too_close = f32::NAN;
+        config.speed.max_speed = -0.5;
+        let problems = config.validate();
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("motors.pwm_freq is 0"));
+        assert!(problems[1].starts_with("sonar.too_close is NaN"));
+        assert!(problems[2].starts_with("speed.max_speed is -0.5"));
+    }
+
+    #[test]
+    fn pid_gains_are_saved_in_place() {
+        let text = "# tank\n[motors]\nleft_pwm = 15\n\n[pid]\n# gains\nkp = 2.0 # proportional\n\
+                    ki = 1.0\noutput_limit = 1.0\n\n[speed]\nkp = 1.0\n";
+        let pid = PidConfig { kp: 3.5, ki: 0.25, kd: 0.1, ..PidConfig::default() };
+        assert_eq!(set_pid_gains(text, &pid),
+                   "# tank\n[motors]\nleft_pwm = 15\n\n[pid]\nkd = 0.1\n# gains\nkp = 3.5 # proportional\n\
+                    ki = 0.25\noutput_limit = 1.0\n\n[speed]\nkp = 1.0\n");
+
+        let saved = set_pid_gains("[motors]\nleft_pwm = 15", &pid);
+        assert_eq!(saved, "[motors]\nleft_pwm = 15\n\n[pid]\nkp = 3.5\nki = 0.25\nkd = 0.1\n");
+        let config: Config = toml::from_str(&saved).unwrap();
+        assert_eq!((config.pid.kp, config.pid.ki, config.pid.kd), (3.5, 0.25, 0.1));
+    }
+
+    #[test]
+    fn misspelled_keys_are_rejected() {
+        let config: Config = toml::from_str("[pid]\nkp = 3.0\n").unwrap();
+        assert_eq!(config.pid.kp, 3.0);
+        assert_eq!(config.pid.ki, PidConfig::default().ki);
+
+        let e = toml::from_str::<Config>("[pid]\nkpp = 3.0\n").unwrap_err();
+        assert!(e.to_string().contains("unknown field `kpp`"), "{}", e);
+    }
+}
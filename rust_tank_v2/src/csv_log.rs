@@ -0,0 +1,172 @@
+//! Sensor history as CSV, a row per real time tick, for looking at in a
+//! spreadsheet or plotting tool.
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use floating_duration::TimeAsFloat;
+
+use hardware_interface::Controller;
+
+pub use tank_protocol::messages::Column;
+
+/// Names of the CSV columns a column group writes
+fn headers(column: Column) -> &'static [&'static str] {
+    match column {
+        Column::Time => &["time"],
+        Column::Orientation => &["roll", "pitch", "yaw"],
+        Column::Gyro => &["gyro_x", "gyro_y", "gyro_z"],
+        Column::Accel => &["accel_x", "accel_y", "accel_z"],
+        Column::Mag => &["mag_x", "mag_y", "mag_z"],
+        Column::Temp => &["temp"],
+        Column::Sonar => &["sonar"],
+        Column::PidOutput => &["pid_output"],
+        Column::MotorPower => &["left_power", "right_power"],
+    }
+}
+
+pub struct CsvLog {
+    out: Box<dyn Write + Send>,
+    columns: Vec<Column>,
+    /// Time of the first row
+    start: Option<Instant>,
+}
+
+impl CsvLog {
+    /// Starts a log in a new file in dir. The name is taken from clients,
+    /// so it can't have any directories in it, and won't replace a file
+    /// that is already there.
+    pub fn create(dir: &str, name: &str, columns: Vec<Column>) -> io::Result<CsvLog> {
+        check_file_name(name)?;
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new().write(true).create_new(true).open(Path::new(dir).join(name))?;
+        CsvLog::new(Box::new(BufWriter::new(file)), columns)
+    }
+
+    /// Starts a log written to out, beginning with the header
+    pub fn new(out: Box<dyn Write + Send>, columns: Vec<Column>) -> io::Result<CsvLog> {
+        let mut log = CsvLog { out, columns, start: None };
+        let headers: Vec<&str> = log.columns.iter()
+            .flat_map(|c| headers(*c).iter().cloned())
+            .collect();
+        writeln!(log.out, "{}", headers.join(","))?;
+        Ok(log)
+    }
+
+    /// Writes a row for the latest reading
+    pub fn write_row(&mut self, controller: &Controller) -> io::Result<()> {
+        let state = controller.sensor_state();
+        let raw = state.raw();
+        let mut row: Vec<String> = vec![];
+        for column in &self.columns {
+            match *column {
+                Column::Time => {
+                    let time = *state.time();
+                    let start = *self.start.get_or_insert(time);
+                    let t = if time > start { time - start } else { Duration::default() };
+                    row.push(format!("{:.3}", t.as_fractional_secs()));
+                },
+                Column::Orientation => {
+                    row.extend([state.roll(), state.pitch(), state.yaw()].iter().map(|v| v.to_string()));
+                },
+                Column::Gyro => row.extend([raw.gyro.x, raw.gyro.y, raw.gyro.z].iter().map(|v| v.to_string())),
+                Column::Accel => row.extend([raw.accel.x, raw.accel.y, raw.accel.z].iter().map(|v| v.to_string())),
+                Column::Mag => row.extend([raw.mag.x, raw.mag.y, raw.mag.z].iter().map(|v| v.to_string())),
+                Column::Temp => row.push(raw.temp.to_string()),
+                Column::Sonar => row.push(state.sonar().to_string()),
+                Column::PidOutput => row.push(controller.pid_telemetry().output.to_string()),
+                Column::MotorPower => {
+                    let (left, right) = controller.motor_powers();
+                    row.push(left.to_string());
+                    row.push(right.to_string());
+                },
+            }
+        }
+        writeln!(self.out, "{}", row.join(","))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Fails unless name is a plain file name, that stays in the directory it is joined to
+fn check_file_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(&['/', '\\'][..]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "should be a file name, without any directories"));
+    }
+    Ok(())
+}
+
+/// Parses a list of column names, separated by commas or spaces
+pub fn parse_columns(s: &str) -> Result<Vec<Column>, String> {
+    let columns = s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.parse())
+        .collect::<Result<Vec<Column>, String>>()?;
+    if columns.is_empty() {
+        return Err(String::from("Expected at least one column"));
+    }
+    Ok(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use config::Config;
+    use hardware_interface::RawSensorState;
+    use tests::Shared;
+
+    #[test]
+    fn rows_match_header() {
+        let buffer = Shared::default();
+        let columns = vec![Column::Time, Column::Gyro, Column::MotorPower];
+        let mut log = CsvLog::new(Box::new(buffer.clone()), columns).unwrap();
+        let mut controller = Controller::new(&Config::default());
+        controller.set_drive(0.3, 0.0);
+        for _ in 0..3 {
+            controller.update_imu(RawSensorState::default());
+            log.write_row(&controller).unwrap();
+        }
+
+        let text = String::from_utf8(buffer.contents()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "time,gyro_x,gyro_y,gyro_z,left_power,right_power");
+        assert_eq!(lines.len(), 4);
+        for line in &lines[1..] {
+            assert_eq!(line.split(',').count(), 6);
+        }
+    }
+
+    #[test]
+    fn logs_stay_in_their_dir() {
+        let dir = env::temp_dir().join(format!("tank_csv_{}", process::id()));
+        let dir = dir.to_str().unwrap();
+        for name in &["", ".", "..", "../escape.csv", "/tmp/escape.csv", "sub/run.csv", "..\\escape.csv"] {
+            let e = CsvLog::create(dir, name, vec![Column::Time]).err().expect(name);
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", name);
+        }
+        assert!(CsvLog::create(dir, "Run1.csv", vec![Column::Time]).is_ok());
+        //an existing file is never replaced
+        let e = CsvLog::create(dir, "Run1.csv", vec![Column::Time]).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn columns_parse() {
+        assert_eq!(parse_columns("time, yaw").unwrap_err(),
+                   "Unknown column 'yaw', expected one of time, orientation, gyro, accel, mag, \
+                    temp, sonar, pid_output, motor_power");
+        assert_eq!(parse_columns("time,orientation motor_power").unwrap(),
+                   vec![Column::Time, Column::Orientation, Column::MotorPower]);
+        assert!(parse_columns(" , ").is_err());
+    }
+}
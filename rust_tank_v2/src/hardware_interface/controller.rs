@@ -0,0 +1,103 @@
+use std::time::Instant;
+
+use super::RTEvent;
+use super::drive_pid::DrivePid;
+use super::PidTelemetry;
+use super::speed_control::SpeedControl;
+use super::sensor_processing::SensorState;
+use super::real_time::RTCommand;
+use super::{RawSensorState, EncoderState};
+use ::config::Config;
+
+/// The sensor processing and drive control fed by the real time threads.
+///
+/// Kept apart from the threads themselves, so a recording can be fed back
+/// through exactly the same steps off the tank.
+pub struct Controller {
+    sensor_state: SensorState,
+    drive_pid: DrivePid,
+    speed_control: SpeedControl,
+}
+
+impl Controller {
+    pub fn new(config: &Config) -> Controller {
+        Controller {
+            sensor_state: SensorState::new(config),
+            //TODO tune drive
+            drive_pid: DrivePid::new(&config.pid, config.motors.clone()),
+            speed_control: SpeedControl::new(&config.speed),
+        }
+    }
+
+    /// Takes in a reading from the IMU, and updates the drive to match
+    pub fn update_imu(&mut self, new_state: RawSensorState) -> Option<RTEvent> {
+        let event = self.sensor_state.update(new_state, self.drive_pid.target_power());
+        self.speed_control.update(&self.sensor_state);
+        let turn = self.drive_pid.target_angle();
+        self.drive_pid.set_target(self.speed_control.power(), turn);
+        self.drive_pid.update(&self.sensor_state);
+        event
+    }
+
+    /// Takes in a reading from the sonar, in cm
+    pub fn update_sonar(&mut self, cm: f32, time: Instant) -> Option<RTEvent> {
+        self.sensor_state.set_sonar((cm, time))
+    }
+
+    pub fn update_encoders(&mut self, state: EncoderState) {
+        self.sensor_state.set_encoders(state);
+    }
+
+    /// Sets the forward speed (in m/s) and heading (in radians) to hold
+    pub fn set_drive(&mut self, speed: f32, turn: f32) {
+        self.speed_control.set_target(speed);
+        self.drive_pid.set_target(self.speed_control.power(), turn);
+    }
+
+    /// Drops the drive targets to nothing
+    pub fn stop(&mut self) {
+        self.speed_control.set_target(0.0);
+        self.drive_pid.set_target(0.0, 0.0);
+    }
+
+    /// The current (speed, turn) the drive is aiming for
+    pub fn drive_target(&self) -> (f32, f32) {
+        (self.speed_control.target(), self.drive_pid.target_angle())
+    }
+
+    /// Returns the (proportional, integral, derivative) gains of the drive PID
+    pub fn pid_gains(&self) -> (f32, f32, f32) {
+        self.drive_pid.gains()
+    }
+
+    pub fn set_pid_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        self.drive_pid.set_gains(kp, ki, kd);
+    }
+
+    /// Clears the integral and error history of the drive PID
+    pub fn reset_pid(&mut self) {
+        self.drive_pid.reset();
+    }
+
+    pub fn pid_telemetry(&self) -> PidTelemetry {
+        self.drive_pid.telemetry()
+    }
+
+    /// Power of the (left, right) track, from -1 to 1
+    pub fn motor_powers(&self) -> (f32, f32) {
+        self.drive_pid.motor_powers()
+    }
+
+    /// Commands to drive the motors as the PID wants
+    pub fn pwm_commands(&self) -> Vec<RTCommand> {
+        self.drive_pid.get_pwm_commands()
+    }
+
+    pub fn sensor_state(&self) -> &SensorState {
+        &self.sensor_state
+    }
+
+    pub fn sensor_state_mut(&mut self) -> &mut SensorState {
+        &mut self.sensor_state
+    }
+}
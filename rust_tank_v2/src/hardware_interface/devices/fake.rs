@@ -0,0 +1,78 @@
+use std::thread::sleep;
+use std::time::{Instant, Duration};
+
+use i2cdev::linux::LinuxI2CError;
+use pca9685;
+use sysfs_gpio;
+
+use super::super::{RawSensorState, Vec3};
+use super::{PwmOutput, pwm_error, OrientationSensor, RangeSensor};
+
+/// Stand in for the PWM driver, remembers what each channel was set to
+pub struct FakePwm {
+    pub channels: [(u16, u16); 16],
+    pub freq_hz: f32,
+}
+
+impl Default for FakePwm {
+    fn default() -> FakePwm {
+        FakePwm {
+            channels: [(0, 4096); 16],
+            freq_hz: 0.0,
+        }
+    }
+}
+
+impl PwmOutput for FakePwm {
+    fn set_pwm_freq(&mut self, freq_hz: f32) -> Result<(), LinuxI2CError> {
+        self.freq_hz = freq_hz;
+        Ok(())
+    }
+
+    fn set_pwm(&mut self, channel: u8, on: u16, off: u16) -> Result<(), LinuxI2CError> {
+        //channels past 15 fail the same way they do on the real driver
+        let slot = self.channels.get_mut(channel as usize)
+            .ok_or_else(|| pwm_error(pca9685::Error::Channel(channel)))?;
+        *slot = (on, off);
+        Ok(())
+    }
+
+    fn set_all_pwm_off(&mut self) -> Result<(), LinuxI2CError> {
+        self.channels = [(0, 4096); 16];
+        Ok(())
+    }
+}
+
+/// Stand in for the orientation sensor, reports a tank sitting still on level ground
+#[derive(Default)]
+pub struct FakeImu;
+
+impl OrientationSensor for FakeImu {
+    fn read(&mut self, time: Instant) -> Result<RawSensorState, LinuxI2CError> {
+        Ok(RawSensorState {
+            time,
+            accel: Vec3 { x: 0.0, y: 0.0, z: 9.80665 },
+            temp: 20.0,
+            ..RawSensorState::default()
+        })
+    }
+}
+
+/// Stand in for the sonar, always sees something at the same distance
+pub struct FakeSonar {
+    pub distance_cm: f32,
+}
+
+impl Default for FakeSonar {
+    fn default() -> FakeSonar {
+        FakeSonar { distance_cm: 200.0 }
+    }
+}
+
+impl RangeSensor for FakeSonar {
+    fn measure(&mut self) -> Result<Option<f32>, sysfs_gpio::Error> {
+        //roughly how long the echo would take to come back
+        sleep(Duration::from_micros((self.distance_cm * 58.0) as u64));
+        Ok(Some(self.distance_cm))
+    }
+}
@@ -0,0 +1,253 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::{Instant, Duration};
+
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use i2cdev_bno055::{BNO055, BNO055OperationMode};
+use i2csensors::{Accelerometer, Gyroscope, Magnetometer, Thermometer};
+use i2csensors::Vec3 as iVec3;
+use pca9685;
+use pca9685::PCA9685;
+use sysfs_gpio;
+use sysfs_gpio::{Direction, Edge, Pin, PinPoller};
+
+use super::super::on_export;
+use super::super::{RawSensorState, Vec3};
+use super::{PwmOutput, OrientationSensor, RangeSensor, TrackEncoders};
+
+/// Registers that didn't read back what was written (with motors.verify_pwm on),
+/// and channels the chip doesn't have, are reported like any other I2C error
+pub fn pwm_error(e: pca9685::Error<LinuxI2CError>) -> LinuxI2CError {
+    match e {
+        pca9685::Error::I2C(e) => e,
+        mismatch => LinuxI2CError::Io(io::Error::new(io::ErrorKind::InvalidData, mismatch.to_string())),
+    }
+}
+
+impl PwmOutput for PCA9685<LinuxI2CDevice> {
+    fn set_pwm_freq(&mut self, freq_hz: f32) -> Result<(), LinuxI2CError> {
+        PCA9685::set_pwm_freq(self, freq_hz).map(|_| ()).map_err(pwm_error)
+    }
+
+    fn set_pwm(&mut self, channel: u8, on: u16, off: u16) -> Result<(), LinuxI2CError> {
+        PCA9685::set_pwm_block(self, channel, on, off).map_err(pwm_error)
+    }
+
+    fn set_all_pwm_off(&mut self) -> Result<(), LinuxI2CError> {
+        PCA9685::set_all_pwm_block(self, 0, 4096).map_err(pwm_error)
+    }
+
+    fn set_pwms(&mut self, updates: &[(u8, u16, u16)]) -> Result<(), LinuxI2CError> {
+        PCA9685::set_pwms(self, updates).map_err(pwm_error)
+    }
+
+    fn reinit(&mut self) -> Result<(), LinuxI2CError> {
+        PCA9685::reinit(self).map_err(pwm_error)
+    }
+}
+
+fn vec3(v: iVec3) -> Vec3 {
+    Vec3 {
+        x: v.x,
+        y: v.y,
+        z: v.z
+    }
+}
+
+impl OrientationSensor for BNO055<LinuxI2CDevice> {
+    fn read(&mut self, time: Instant) -> Result<RawSensorState, LinuxI2CError> {
+        let orientation = vec3(self.get_euler()?);
+        let accel = vec3(self.acceleration_reading()?);
+        let linear_accel = vec3(self.get_linear_acceleration()?);
+        let gyro = vec3(self.angular_rate_reading()?);
+        let mag = vec3(self.magnetic_reading()?);
+        let temp = self.temperature_celsius()?;
+        Ok(RawSensorState {
+            orientation, accel, linear_accel, mag, time, gyro, temp,
+        })
+    }
+
+    fn reinit(&mut self) -> Result<(), LinuxI2CError> {
+        self.reset()?;
+        self.set_external_crystal(true)?;
+        self.set_mode(BNO055OperationMode::Ndof)
+    }
+}
+
+/// HC-SR04 ultrasonic sonar, connected through sysfs gpio
+pub struct HcSr04 {
+    trigger_pin: Pin,
+    echo_pin: Pin,
+    echo_pin_poller: PinPoller,
+}
+
+impl HcSr04 {
+    /// Exports and configures the trigger and echo pins.
+    /// They are unexported again when this is dropped.
+    pub fn new(trigger: u64, echo: u64) -> Result<HcSr04, sysfs_gpio::Error> {
+        let trigger_pin = Pin::new(trigger);
+        let echo_pin = Pin::new(echo);
+        trigger_pin.export()?;
+        echo_pin.export()?;
+        on_export::wait();
+        HcSr04::configure(trigger_pin, echo_pin)?;
+        let echo_pin_poller = echo_pin.get_poller()?;
+        Ok(HcSr04 { trigger_pin, echo_pin, echo_pin_poller })
+    }
+
+    fn configure(trigger_pin: Pin, echo_pin: Pin) -> Result<(), sysfs_gpio::Error> {
+        trigger_pin.set_direction(Direction::Out)?;
+        echo_pin.set_direction(Direction::In)?;
+        echo_pin.set_edge(Edge::BothEdges)
+    }
+}
+
+impl RangeSensor for HcSr04 {
+    fn measure(&mut self) -> Result<Option<f32>, sysfs_gpio::Error> {
+        //run trigger
+        self.trigger_pin.set_value(1)?;
+        sleep(Duration::from_millis(1));
+        self.trigger_pin.set_value(0)?;
+
+        //wait for signal
+        if self.echo_pin_poller.poll(500)?.is_none() {
+            return Ok(None);
+        }
+        let start = Instant::now();
+
+        //wait for end
+        if self.echo_pin_poller.poll(500)?.is_none() {
+            return Ok(None); //Echo timed out
+        }
+        //calculate how long
+        let time = start.elapsed();
+
+        //Convert to distance
+        Ok(Some((time.subsec_nanos() as f32 * 34300.0) / 2000000000.0))
+    }
+
+    fn reinit(&mut self) -> Result<(), sysfs_gpio::Error> {
+        HcSr04::configure(self.trigger_pin, self.echo_pin)
+    }
+}
+
+impl Drop for HcSr04 {
+    fn drop(&mut self) {
+        if let Err(e) = self.trigger_pin.unexport() {
+            eprintln!("Failed to unexport sonar trigger pin: {}", e);
+        }
+        if let Err(e) = self.echo_pin.unexport() {
+            eprintln!("Failed to unexport sonar echo pin: {}", e);
+        }
+    }
+}
+
+/// Counts the edges on one encoder's A channel.
+/// Each counter waits on its pin in its own thread, the same way the sonar
+/// waits on its echo pin, so edges on one track aren't missed while
+/// waiting on the other.
+struct EdgeCounter {
+    a_pin: Pin,
+    b_pin: Option<Pin>,
+    ticks: Arc<AtomicIsize>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EdgeCounter {
+    fn new(a: u64, b: Option<u64>) -> Result<EdgeCounter, sysfs_gpio::Error> {
+        let a_pin = Pin::new(a);
+        let b_pin = b.map(Pin::new);
+        a_pin.export()?;
+        if let Some(b_pin) = b_pin {
+            b_pin.export()?;
+        }
+        on_export::wait();
+        a_pin.set_direction(Direction::In)?;
+        a_pin.set_edge(Edge::BothEdges)?;
+        if let Some(b_pin) = b_pin {
+            b_pin.set_direction(Direction::In)?;
+        }
+        let mut poller = a_pin.get_poller()?;
+
+        let ticks = Arc::new(AtomicIsize::new(0));
+        let running = Arc::new(AtomicBool::new(true));
+        let (thread_ticks, thread_running) = (Arc::clone(&ticks), Arc::clone(&running));
+        let thread = thread::spawn(move || {
+            while thread_running.load(Ordering::Relaxed) {
+                //time out now and then to notice being dropped
+                let a_value = match poller.poll(100) {
+                    Ok(Some(value)) => value,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("Failed to poll encoder pin {}: {}", a, e);
+                        sleep(Duration::from_millis(100));
+                        continue;
+                    },
+                };
+                //with quadrature, A leading B counts forward
+                let step = match b_pin.map(|b_pin| b_pin.get_value()) {
+                    Some(Ok(b_value)) => if a_value != b_value { 1 } else { -1 },
+                    Some(Err(e)) => {
+                        eprintln!("Failed to read encoder pin {}: {}", b.unwrap_or(0), e);
+                        continue;
+                    },
+                    None => 1,
+                };
+                thread_ticks.fetch_add(step, Ordering::Relaxed);
+            }
+        });
+
+        Ok(EdgeCounter { a_pin, b_pin, ticks, running, thread: Some(thread) })
+    }
+
+    fn ticks(&self) -> i64 {
+        self.ticks.load(Ordering::Relaxed) as i64
+    }
+}
+
+impl Drop for EdgeCounter {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        //the thread is polling the pin, so has to finish before it goes
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("Encoder thread panicked");
+            }
+        }
+        if let Err(e) = self.a_pin.unexport() {
+            eprintln!("Failed to unexport encoder pin: {}", e);
+        }
+        if let Some(Err(e)) = self.b_pin.map(|b_pin| b_pin.unexport()) {
+            eprintln!("Failed to unexport encoder pin: {}", e);
+        }
+    }
+}
+
+/// Quadrature or single channel track encoders, connected through sysfs gpio
+pub struct GpioEncoders {
+    left: EdgeCounter,
+    right: EdgeCounter,
+}
+
+impl GpioEncoders {
+    /// Exports and configures the encoder pins, the B pins are only
+    /// given for quadrature encoders.
+    /// They are unexported again when this is dropped.
+    pub fn new(left_a: u64, left_b: Option<u64>, right_a: u64, right_b: Option<u64>)
+            -> Result<GpioEncoders, sysfs_gpio::Error> {
+        Ok(GpioEncoders {
+            left: EdgeCounter::new(left_a, left_b)?,
+            right: EdgeCounter::new(right_a, right_b)?,
+        })
+    }
+}
+
+impl TrackEncoders for GpioEncoders {
+    fn ticks(&mut self) -> Result<(i64, i64), sysfs_gpio::Error> {
+        Ok((self.left.ticks(), self.right.ticks()))
+    }
+}
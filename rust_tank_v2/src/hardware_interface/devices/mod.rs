@@ -0,0 +1,81 @@
+//! Abstractions over the devices used by the real time threads.
+//! The real time loops are generic over these traits, so the rest of the
+//! controller can run against the real hardware or a stand in.
+
+use std::time::Instant;
+
+use i2cdev::linux::LinuxI2CError;
+use sysfs_gpio;
+
+use super::RawSensorState;
+
+mod linux;
+mod fake;
+mod sim;
+
+pub use self::linux::{HcSr04, GpioEncoders, pwm_error};
+pub use self::fake::{FakePwm, FakeImu, FakeSonar};
+pub use self::sim::{Simulation, Room};
+
+/// A 16 channel, 12 bit PWM driver (e.g. the PCA9685)
+pub trait PwmOutput: Send {
+    fn set_pwm_freq(&mut self, freq_hz: f32) -> Result<(), LinuxI2CError>;
+
+    /// Sets when the channel goes high and low, in ticks (0..4095).
+    /// 4096 in on or off means fully on or fully off respectively.
+    fn set_pwm(&mut self, channel: u8, on: u16, off: u16) -> Result<(), LinuxI2CError>;
+
+    fn set_all_pwm_off(&mut self) -> Result<(), LinuxI2CError>;
+
+    /// Sets several channels at once, given as (channel, on, off).
+    /// Drivers that can should do this in fewer transactions than a channel at a time.
+    fn set_pwms(&mut self, updates: &[(u8, u16, u16)]) -> Result<(), LinuxI2CError> {
+        for &(channel, on, off) in updates {
+            self.set_pwm(channel, on, off)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the device up again after repeated errors.
+    /// The frequency is set again afterwards.
+    fn reinit(&mut self) -> Result<(), LinuxI2CError> {
+        Ok(())
+    }
+
+    fn set_pwm_on(&mut self, channel: u8) -> Result<(), LinuxI2CError> {
+        self.set_pwm(channel, 4096, 0)
+    }
+
+    fn set_pwm_off(&mut self, channel: u8) -> Result<(), LinuxI2CError> {
+        self.set_pwm(channel, 0, 4096)
+    }
+}
+
+/// An absolute orientation sensor (e.g. the BNO055)
+pub trait OrientationSensor: Send {
+    /// Takes a full reading of the sensor, stamped with the given time
+    fn read(&mut self, time: Instant) -> Result<RawSensorState, LinuxI2CError>;
+
+    /// Sets the device up again after repeated errors
+    fn reinit(&mut self) -> Result<(), LinuxI2CError> {
+        Ok(())
+    }
+}
+
+/// A forward facing distance sensor (e.g. a HC-SR04 sonar)
+pub trait RangeSensor: Send {
+    /// Takes a single measurement in cm, returns None if there was no echo.
+    fn measure(&mut self) -> Result<Option<f32>, sysfs_gpio::Error>;
+
+    /// Sets the device up again after repeated errors
+    fn reinit(&mut self) -> Result<(), sysfs_gpio::Error> {
+        Ok(())
+    }
+}
+
+/// Encoders on the left and right tracks
+pub trait TrackEncoders: Send {
+    /// Total (left, right) ticks counted so far.
+    /// Single channel encoders only count up, whichever way the track turns.
+    fn ticks(&mut self) -> Result<(i64, i64), sysfs_gpio::Error>;
+}
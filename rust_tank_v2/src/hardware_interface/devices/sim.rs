@@ -0,0 +1,355 @@
+//! Simulated tank, for testing the controller without any hardware.
+//! The PWM, IMU and sonar stand ins all share one simulated world.
+//! The IMU advances the simulation each time it is read.
+
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Instant, Duration};
+
+use floating_duration::TimeAsFloat;
+use i2cdev::linux::LinuxI2CError;
+use pca9685;
+use sysfs_gpio;
+
+use ::config::MotorConfig;
+use super::super::{RawSensorState, Vec3};
+use super::{PwmOutput, pwm_error, OrientationSensor, RangeSensor, TrackEncoders};
+
+const GRAVITY: f32 = 9.80665;
+/// Track speed at full power, in m/s
+const MAX_TRACK_SPEED: f32 = 0.5;
+/// Fraction of the pwm duty cycle that doesn't move the motors
+const MOTOR_DEADBAND: f32 = 0.25;
+/// Time constant of the motors getting up to speed, in seconds
+const MOTOR_LAG: f32 = 0.2;
+/// Distance between the center of the tracks, in meters
+const TRACK_WIDTH: f32 = 0.15;
+/// Distance from the center of the tank to the front/sides, in meters
+const TANK_RADIUS: f32 = 0.1;
+/// Furthest the sonar can see, in cm
+const SONAR_MAX_RANGE: f32 = 400.0;
+/// Strength of the earth's magnetic field, in micro tesla
+const MAG_FIELD: f32 = 40.0;
+/// Gaps between steps longer than this (in seconds) are treated as this long
+const MAX_STEP: f32 = 0.1;
+
+/// A wall segment in the simulated room, coordinates in meters
+#[derive(Clone, Copy, Debug)]
+pub struct Wall {
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32,
+}
+
+/// The 2D room the simulated tank drives around in.
+/// The tank starts at (0, 0) facing along the x axis, with y to its right,
+/// the same way round as the pose estimate.
+#[derive(Clone, Debug)]
+pub struct Room {
+    pub walls: Vec<Wall>,
+}
+
+impl Room {
+    /// A rectangular room centered on the start point
+    pub fn rectangle(width: f32, depth: f32) -> Room {
+        let (x, y) = (width / 2.0, depth / 2.0);
+        Room {
+            walls: vec![
+                Wall { x1: -x, y1: -y, x2: x, y2: -y },
+                Wall { x1: x, y1: -y, x2: x, y2: y },
+                Wall { x1: x, y1: y, x2: -x, y2: y },
+                Wall { x1: -x, y1: y, x2: -x, y2: -y },
+            ],
+        }
+    }
+
+    /// Loads a room from a text file with one wall per line as "x1 y1 x2 y2".
+    /// Blank lines and lines starting with '#' are ignored.
+    pub fn load(path: &str) -> io::Result<Room> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut walls = vec![];
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Vec<f32> = line.split_whitespace()
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<_, _>>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                                            format!("{}:{}: {}", path, number + 1, e)))?;
+            if values.len() != 4 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{}:{}: expected 'x1 y1 x2 y2'", path, number + 1)));
+            }
+            walls.push(Wall { x1: values[0], y1: values[1], x2: values[2], y2: values[3] });
+        }
+        Ok(Room { walls })
+    }
+
+    /// Distance along a ray to the nearest wall, if any
+    fn ray_cast(&self, x: f32, y: f32, angle: f32) -> Option<f32> {
+        let (dx, dy) = (angle.cos(), angle.sin());
+        self.walls.iter().filter_map(|w| {
+            let (ex, ey) = (w.x2 - w.x1, w.y2 - w.y1);
+            let denom = dx * ey - dy * ex;
+            if denom.abs() < 1e-6 {
+                return None; //parallel
+            }
+            let (fx, fy) = (w.x1 - x, w.y1 - y);
+            let t = (fx * ey - fy * ex) / denom;
+            let u = (fx * dy - fy * dx) / denom;
+            if t >= 0.0 && (0.0..=1.0).contains(&u) { Some(t) } else { None }
+        }).fold(None, |nearest: Option<f32>, t| Some(nearest.map_or(t, |n| n.min(t))))
+    }
+
+    /// Distance from a point to the nearest wall
+    fn clearance(&self, x: f32, y: f32) -> f32 {
+        self.walls.iter().map(|w| {
+            let (ex, ey) = (w.x2 - w.x1, w.y2 - w.y1);
+            let len2 = ex * ex + ey * ey;
+            let t = if len2 > 0.0 {
+                (((x - w.x1) * ex + (y - w.y1) * ey) / len2).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let (px, py) = (w.x1 + t * ex - x, w.y1 + t * ey - y);
+            (px * px + py * py).sqrt()
+        }).fold(f32::INFINITY, f32::min)
+    }
+}
+
+/// Physical state of the simulated tank
+struct World {
+    room: Room,
+    motors: MotorConfig,
+    channels: [(u16, u16); 16],
+    x: f32,
+    y: f32,
+    /// Radians, increasing clockwise (the left track faster than the right)
+    /// like the BNO055's heading
+    yaw: f32,
+    left_speed: f32,
+    right_speed: f32,
+    /// How far each track has moved, in meters, backwards counts as negative
+    left_travel: f32,
+    right_travel: f32,
+    /// How far each track has moved either way, in meters
+    left_odometer: f32,
+    right_odometer: f32,
+    /// Radians per second, clockwise
+    yaw_rate: f32,
+    forward_accel: f32,
+    last_step: Option<Instant>,
+}
+
+impl World {
+    /// Fraction of the period a channel is high
+    fn duty(&self, channel: u8) -> f32 {
+        let (on, off) = self.channels[channel as usize];
+        if off >= 4096 {
+            0.0
+        } else if on >= 4096 {
+            1.0
+        } else {
+            ((off as i32 - on as i32 + 4096) % 4096) as f32 / 4096.0
+        }
+    }
+
+    /// Power of one motor from its direction and pwm channels, in [-1, 1]
+    fn motor_power(&self, pwm: u8, a: u8, b: u8) -> f32 {
+        let duty = ((self.duty(pwm) - MOTOR_DEADBAND) / (1.0 - MOTOR_DEADBAND)).max(0.0);
+        match (self.duty(a) > 0.5, self.duty(b) > 0.5) {
+            (true, false) => duty,
+            (false, true) => -duty,
+            _ => 0.0, //braking
+        }
+    }
+
+    fn step(&mut self, time: Instant) {
+        let dt = match self.last_step {
+            Some(last) if time > last => (time - last).as_fractional_secs() as f32,
+            Some(_) => 0.0,
+            None => 0.0,
+        }.min(MAX_STEP);
+        self.last_step = Some(time);
+        if dt <= 0.0 {
+            return;
+        }
+
+        let m = &self.motors;
+        let left_target = self.motor_power(m.left_pwm, m.left_a, m.left_b) * MAX_TRACK_SPEED;
+        let right_target = self.motor_power(m.right_pwm, m.right_a, m.right_b) * MAX_TRACK_SPEED;
+        let blend = (dt / MOTOR_LAG).min(1.0);
+        let old_speed = (self.left_speed + self.right_speed) / 2.0;
+        self.left_speed += (left_target - self.left_speed) * blend;
+        self.right_speed += (right_target - self.right_speed) * blend;
+
+        self.left_travel += self.left_speed * dt;
+        self.right_travel += self.right_speed * dt;
+        self.left_odometer += self.left_speed.abs() * dt;
+        self.right_odometer += self.right_speed.abs() * dt;
+
+        let speed = (self.left_speed + self.right_speed) / 2.0;
+        self.yaw_rate = (self.left_speed - self.right_speed) / TRACK_WIDTH;
+        self.yaw = (self.yaw + self.yaw_rate * dt) % (2.0 * PI);
+        if self.yaw < 0.0 {
+            self.yaw += 2.0 * PI;
+        }
+
+        let x = self.x + speed * self.yaw.cos() * dt;
+        let y = self.y + speed * self.yaw.sin() * dt;
+        if self.room.clearance(x, y) > TANK_RADIUS {
+            self.x = x;
+            self.y = y;
+            self.forward_accel = (speed - old_speed) / dt;
+        } else {
+            //ran into a wall, the tracks just slip
+            self.forward_accel = 0.0;
+        }
+    }
+
+    fn sonar(&self) -> Option<f32> {
+        let x = self.x + TANK_RADIUS * self.yaw.cos();
+        let y = self.y + TANK_RADIUS * self.yaw.sin();
+        self.room.ray_cast(x, y, self.yaw)
+            .map(|m| m * 100.0)
+            .filter(|&cm| cm <= SONAR_MAX_RANGE)
+    }
+}
+
+/// Handle to a simulated tank, hands out the simulated devices
+pub struct Simulation {
+    world: Arc<Mutex<World>>,
+}
+
+impl Simulation {
+    /// motors is the wiring of the simulated motor driver
+    pub fn new(room: Room, motors: MotorConfig) -> Simulation {
+        Simulation {
+            world: Arc::new(Mutex::new(World {
+                room,
+                motors,
+                channels: [(0, 4096); 16],
+                x: 0.0,
+                y: 0.0,
+                yaw: 0.0,
+                left_speed: 0.0,
+                right_speed: 0.0,
+                left_travel: 0.0,
+                right_travel: 0.0,
+                left_odometer: 0.0,
+                right_odometer: 0.0,
+                yaw_rate: 0.0,
+                forward_accel: 0.0,
+                last_step: None,
+            })),
+        }
+    }
+
+    pub fn pwm(&self) -> SimPwm {
+        SimPwm { world: Arc::clone(&self.world) }
+    }
+
+    pub fn imu(&self) -> SimImu {
+        SimImu { world: Arc::clone(&self.world) }
+    }
+
+    pub fn sonar(&self) -> SimSonar {
+        SimSonar { world: Arc::clone(&self.world) }
+    }
+
+    /// Encoders that count as if they were on the tracks.
+    /// Single channel ones (quadrature false) count up either way.
+    pub fn encoders(&self, ticks_per_meter: f32, quadrature: bool) -> SimEncoders {
+        SimEncoders { world: Arc::clone(&self.world), ticks_per_meter, quadrature }
+    }
+}
+
+/// Simulated PWM driver, drives the simulated motors
+pub struct SimPwm {
+    world: Arc<Mutex<World>>,
+}
+
+impl PwmOutput for SimPwm {
+    fn set_pwm_freq(&mut self, _freq_hz: f32) -> Result<(), LinuxI2CError> {
+        Ok(())
+    }
+
+    fn set_pwm(&mut self, channel: u8, on: u16, off: u16) -> Result<(), LinuxI2CError> {
+        let mut world = self.world.lock().unwrap();
+        let slot = world.channels.get_mut(channel as usize)
+            .ok_or_else(|| pwm_error(pca9685::Error::Channel(channel)))?;
+        *slot = (on, off);
+        Ok(())
+    }
+
+    fn set_all_pwm_off(&mut self) -> Result<(), LinuxI2CError> {
+        self.world.lock().unwrap().channels = [(0, 4096); 16];
+        Ok(())
+    }
+}
+
+/// Simulated BNO055, reports in the same units and axes as the real one
+pub struct SimImu {
+    world: Arc<Mutex<World>>,
+}
+
+impl OrientationSensor for SimImu {
+    fn read(&mut self, time: Instant) -> Result<RawSensorState, LinuxI2CError> {
+        let mut world = self.world.lock().unwrap();
+        world.step(time);
+        let linear_accel = Vec3 { x: world.forward_accel, y: 0.0, z: 0.0 };
+        //the heading goes clockwise, but the gyro and magnetometer axes are
+        //right handed with z up, so turning clockwise is a negative z rate
+        Ok(RawSensorState {
+            time,
+            gyro: Vec3 { x: 0.0, y: 0.0, z: -world.yaw_rate.to_degrees() },
+            accel: Vec3 { z: GRAVITY, ..linear_accel },
+            linear_accel,
+            mag: Vec3 { x: MAG_FIELD * world.yaw.cos(), y: MAG_FIELD * world.yaw.sin(), z: 0.0 },
+            orientation: Vec3 { x: world.yaw, y: 0.0, z: 0.0 },
+            temp: 22.0,
+        })
+    }
+}
+
+/// Simulated sonar, looks straight out the front of the simulated tank
+pub struct SimSonar {
+    world: Arc<Mutex<World>>,
+}
+
+impl RangeSensor for SimSonar {
+    fn measure(&mut self) -> Result<Option<f32>, sysfs_gpio::Error> {
+        let distance = self.world.lock().unwrap().sonar();
+        //roughly how long the echo would take to come back
+        let echo_cm = distance.unwrap_or(SONAR_MAX_RANGE);
+        sleep(Duration::from_micros((echo_cm * 58.0) as u64));
+        Ok(distance)
+    }
+}
+
+/// Simulated track encoders, they keep counting if the tracks slip
+pub struct SimEncoders {
+    world: Arc<Mutex<World>>,
+    ticks_per_meter: f32,
+    quadrature: bool,
+}
+
+impl TrackEncoders for SimEncoders {
+    fn ticks(&mut self) -> Result<(i64, i64), sysfs_gpio::Error> {
+        let world = self.world.lock().unwrap();
+        let (left, right) = if self.quadrature {
+            (world.left_travel, world.right_travel)
+        } else {
+            (world.left_odometer, world.right_odometer)
+        };
+        Ok(((left * self.ticks_per_meter) as i64, (right * self.ticks_per_meter) as i64))
+    }
+}
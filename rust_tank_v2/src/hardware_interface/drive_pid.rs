@@ -2,51 +2,106 @@ use floating_duration::TimeAsFloat;
 
 use super::sensor_processing::SensorState;
 use super::real_time::RTCommand;
+use super::{PidTelemetry, clamp};
+use ::config::{MotorConfig, PidConfig};
 
 const BIAS: f32 = 0.001;
-
-const MOT_LPWM: u8 = 15;
-const MOT_LA: u8 = 14;
-const MOT_LB: u8 = 13;
-const MOT_RPWM: u8 = 10;
-const MOT_RA: u8 = 11;
-const MOT_RB: u8 = 12;
+/// Heading targets closer than this (in radians) don't count as a new target.
+/// Behaviors like WallFollow nudge the target a little every tick, which
+/// shouldn't throw away the integral.
+const TARGET_CHANGE_EPSILON: f32 = 5.0 * ::std::f32::consts::PI / 180.0;
 
 /// Data storage for the PID controller
+///
+/// The integral is clamped, and stops growing while the output is saturated
+/// in the same direction, so it can't wind up during long turns.
+/// The derivative is taken on the measured heading rather than the error,
+/// so changing the target doesn't cause a spike, and is low pass filtered.
+/// When the heading target steps to somewhere new the integral is reset,
+/// since it was built up holding a different heading.
 pub struct DrivePid {
     target_power: f32,
     target_rad: f32,
     //pid variables
     //see http://robotsforroboticists.com/pid-control/
     prev_error: f32,
+    /// Heading at the previous update, None until the first update
+    prev_actual: Option<f32>,
     integral: f32,
+    /// Low pass filtered derivative term
+    derivative: f32,
     k_porportional: f32,
     k_integral: f32,
     k_derivative: f32,
+    /// Largest magnitude of output
+    output_limit: f32,
+    /// Largest magnitude of integral
+    integral_limit: f32,
+    /// Time constant of the derivative filter, in seconds
+    derivative_filter: f32,
     output: f32,
+    motors: MotorConfig,
 }
 
 impl DrivePid {
-    pub fn new(k_porportional: f32,
-               k_integral: f32,
-               k_derivative: f32)
+    pub fn new(pid: &PidConfig,
+               motors: MotorConfig)
             -> DrivePid {
         DrivePid {
             target_power: 0.0,
             target_rad: 0.0,
-            k_porportional,
-            k_integral,
-            k_derivative,
+            k_porportional: pid.kp,
+            k_integral: pid.ki,
+            k_derivative: pid.kd,
+            output_limit: pid.output_limit,
+            integral_limit: pid.integral_limit,
+            derivative_filter: pid.derivative_filter,
             prev_error: 0.0,
+            prev_actual: None,
             integral: 0.0,
+            derivative: 0.0,
             output: 0.0,
+            motors,
         }
     }
 
     pub fn set_target(&mut self, power: f32, radians: f32) {
+        if Self::circular_difference(radians, self.target_rad).abs() > TARGET_CHANGE_EPSILON {
+            self.integral = 0.0;
+        }
         self.target_power = power;
         self.target_rad = radians;
-        //TODO should error & integral be reset?
+    }
+
+    /// Returns the (proportional, integral, derivative) gains
+    pub fn gains(&self) -> (f32, f32, f32) {
+        (self.k_porportional, self.k_integral, self.k_derivative)
+    }
+
+    pub fn set_gains(&mut self, k_porportional: f32, k_integral: f32, k_derivative: f32) {
+        self.k_porportional = k_porportional;
+        self.k_integral = k_integral;
+        self.k_derivative = k_derivative;
+    }
+
+    /// Forgets the accumulated error
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.prev_error = 0.0;
+        self.prev_actual = None;
+        self.derivative = 0.0;
+    }
+
+    pub fn telemetry(&self) -> PidTelemetry {
+        PidTelemetry {
+            kp: self.k_porportional,
+            ki: self.k_integral,
+            kd: self.k_derivative,
+            error: self.prev_error,
+            integral: self.integral,
+            derivative: self.derivative,
+            output: self.output,
+        }
     }
 
     pub fn target_power(&self) -> f32 {
@@ -63,61 +118,225 @@ impl DrivePid {
         let half = PI;
         let full = 2.0 * PI;
         let mut a = x - y;
-        let mut b = y - x;
         if a > half {
             a -= full;
         } else if a < -half {
             a += full;
         }
-        if b > half {
-            b -= full;
-        } else if b < -half {
-            b += full;
-        }
-        //let v = if a < b {-a} else {b};
-        //eprintln!("\n{} - {} = {}", x, y, v);
         a
     }
 
     pub fn update(&mut self,
                   sensors: &SensorState) {
         let dt = sensors.duration().as_fractional_secs() as f32;
-        let actual = sensors.yaw();
+        self.step(dt, sensors.yaw());
+    }
+
+    /// Advances the controller by dt seconds, given the measured heading
+    fn step(&mut self, dt: f32, actual: f32) {
+        if dt.is_nan() || dt <= 0.0 {
+            return;
+        }
         let error = Self::circular_difference(self.target_rad, actual);
-        self.integral += error * dt;
-        let derivative = (error - self.prev_error) / dt;
-        self.output = self.k_porportional * error
+
+        //derivative on measurement, the target jumping shouldn't kick the output
+        let raw_derivative = match self.prev_actual {
+            Some(prev) => -Self::circular_difference(actual, prev) / dt,
+            None => 0.0,
+        };
+        let alpha = dt / (self.derivative_filter + dt);
+        self.derivative += alpha * (raw_derivative - self.derivative);
+
+        //only integrate when it wouldn't push a saturated output further
+        let saturated = self.output.abs() >= self.output_limit;
+        if !saturated || error.signum() != self.output.signum() {
+            self.integral = clamp(self.integral + error * dt, self.integral_limit);
+        }
+
+        self.output = clamp(self.k_porportional * error
             + self.k_integral * self.integral
-            + self.k_derivative * derivative + BIAS;
+            + self.k_derivative * self.derivative + BIAS, self.output_limit);
         self.prev_error = error;
+        self.prev_actual = Some(actual);
+    }
+
+    /// Power of the (left, right) track, from -1 to 1.
+    /// A positive output turns clockwise, towards a larger heading.
+    pub fn motor_powers(&self) -> (f32, f32) {
+        let left = clamp(self.target_power + self.output, 1.0);
+        let right = clamp(self.target_power - self.output, 1.0);
+        (left, right)
     }
 
     pub fn get_pwm_commands(&self) -> Vec<super::RTCommand> {
         //if self.target_power == 0.0 && self.target_deg_per_s == 0.0 {
-        //    return vec![RTCommand::SetPwmOff(MOT_LPWM), RTCommand::SetPwmOff(MOT_RPWM)];
+        //    return vec![RTCommand::SetPwmOff(m.left_pwm), RTCommand::SetPwmOff(m.right_pwm)];
         //}
+        let m = &self.motors;
         let mut commands = Vec::with_capacity(6);
-        //calculate relative power of each motor, clamped to [-1 to 1]
-        let lpow = (self.target_power - self.output).max(-1.0).min(1.0);
-        let rpow = (self.target_power + self.output).max(-1.0).min(1.0);
+        let (lpow, rpow) = self.motor_powers();
 
         //setup left motor commands
-        commands.push(RTCommand::SetPwmOn(if lpow > 0.0 {MOT_LA} else {MOT_LB}));
-        commands.push(RTCommand::SetPwmOff(if lpow > 0.0 {MOT_LB} else {MOT_LA}));
+        commands.push(RTCommand::SetPwmOn(if lpow > 0.0 {m.left_a} else {m.left_b}));
+        commands.push(RTCommand::SetPwmOff(if lpow > 0.0 {m.left_b} else {m.left_a}));
         commands.push(RTCommand::SetPwm{
-            pwm: MOT_LPWM, on: 0, off: (4095.0 * lpow.abs()).max(1000.0) as u16,
+            pwm: m.left_pwm, on: 0, off: (4095.0 * lpow.abs()).max(1000.0) as u16,
         });
 
         //setup right motor commands
         //180 degrees out of phase
-        commands.push(RTCommand::SetPwmOn(if rpow > 0.0 {MOT_RA} else {MOT_RB}));
-        commands.push(RTCommand::SetPwmOff(if rpow > 0.0 {MOT_RB} else {MOT_RA}));
+        commands.push(RTCommand::SetPwmOn(if rpow > 0.0 {m.right_a} else {m.right_b}));
+        commands.push(RTCommand::SetPwmOff(if rpow > 0.0 {m.right_b} else {m.right_a}));
         let off = (2048.0 + (4095.0 * rpow.abs()).max(1000.0)) as u16 % 4098 ;
 
         commands.push(RTCommand::SetPwm{
-            pwm: MOT_RPWM, on: 2048, off,
+            pwm: m.right_pwm, on: 2048, off,
         });
 
         commands
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    fn pid(kp: f32, ki: f32, kd: f32) -> DrivePid {
+        let config = PidConfig { kp, ki, kd, ..PidConfig::default() };
+        DrivePid::new(&config, MotorConfig::default())
+    }
+
+    #[test]
+    fn integral_is_clamped() {
+        let mut pid = pid(0.0, 1.0, 0.0);
+        pid.set_target(0.0, 1.0);
+        for _ in 0..600 {
+            pid.step(DT, 0.0);
+        }
+        assert!(pid.integral <= pid.integral_limit + 1e-6);
+        assert!(pid.output <= pid.output_limit);
+    }
+
+    #[test]
+    fn integral_stops_while_saturated() {
+        let mut pid = pid(10.0, 1.0, 0.0);
+        pid.set_target(0.0, 1.0);
+        pid.step(DT, 0.0);
+        let integral = pid.integral;
+        assert_eq!(pid.output, pid.output_limit);
+        pid.step(DT, 0.0);
+        assert_eq!(pid.integral, integral);
+        //error changing sign lets the integral unwind
+        pid.step(DT, 1.5);
+        assert!(pid.integral < integral);
+    }
+
+    #[test]
+    fn output_is_limited() {
+        let mut config = PidConfig { kp: 100.0, ..PidConfig::default() };
+        config.output_limit = 0.3;
+        let mut pid = DrivePid::new(&config, MotorConfig::default());
+        pid.set_target(0.0, 2.0);
+        pid.step(DT, 0.0);
+        assert_eq!(pid.output, 0.3);
+        pid.set_target(0.0, 4.0);
+        pid.step(DT, 6.0);
+        assert_eq!(pid.output, -0.3);
+    }
+
+    #[test]
+    fn target_change_does_not_kick_derivative() {
+        let mut pid = pid(0.0, 0.0, 1.0);
+        pid.set_target(0.0, 0.0);
+        pid.step(DT, 0.5);
+        pid.step(DT, 0.5);
+        pid.set_target(0.0, 2.0);
+        pid.step(DT, 0.5);
+        assert!(pid.derivative.abs() < 1e-6);
+    }
+
+    #[test]
+    fn derivative_is_filtered() {
+        let mut pid = pid(0.0, 0.0, 1.0);
+        pid.step(DT, 0.0);
+        //heading jumps by 0.1 rad in one update
+        pid.step(DT, 0.1);
+        let raw = -0.1 / DT;
+        assert!(pid.derivative < 0.0);
+        assert!(pid.derivative > raw);
+        //and settles back toward zero once the heading holds still
+        let first = pid.derivative;
+        pid.step(DT, 0.1);
+        assert!(pid.derivative.abs() < first.abs());
+    }
+
+    #[test]
+    fn derivative_handles_wrap_around() {
+        use std::f32::consts::PI;
+        let mut pid = pid(0.0, 0.0, 1.0);
+        pid.derivative_filter = 0.0;
+        pid.step(DT, 2.0 * PI - 0.01);
+        pid.step(DT, 0.01);
+        assert!((pid.derivative - (-0.02 / DT)).abs() < 0.1);
+    }
+
+    #[test]
+    fn new_target_resets_integral() {
+        let mut pid = pid(0.0, 1.0, 0.0);
+        pid.set_target(0.0, 0.5);
+        for _ in 0..10 {
+            pid.step(DT, 0.0);
+        }
+        assert!(pid.integral > 0.0);
+        //changing only the power keeps it
+        pid.set_target(0.5, 0.5);
+        assert!(pid.integral > 0.0);
+        pid.set_target(0.5, 1.0);
+        assert_eq!(pid.integral, 0.0);
+    }
+
+    #[test]
+    fn gains_can_be_changed_and_reset() {
+        let mut pid = pid(2.0, 1.0, 0.0);
+        assert_eq!(pid.gains(), (2.0, 1.0, 0.0));
+        pid.set_gains(3.0, 0.5, 0.25);
+        assert_eq!(pid.gains(), (3.0, 0.5, 0.25));
+        let telemetry = pid.telemetry();
+        assert_eq!((telemetry.kp, telemetry.ki, telemetry.kd), (3.0, 0.5, 0.25));
+
+        pid.set_target(0.0, 1.0);
+        pid.step(DT, 0.0);
+        pid.step(DT, 0.1);
+        pid.reset();
+        let telemetry = pid.telemetry();
+        assert_eq!((telemetry.error, telemetry.integral, telemetry.derivative), (0.0, 0.0, 0.0));
+        assert!(pid.prev_actual.is_none());
+        //the gains are kept
+        assert_eq!(pid.gains(), (3.0, 0.5, 0.25));
+    }
+
+    #[test]
+    fn drifting_target_keeps_integral() {
+        let mut pid = pid(0.0, 1.0, 0.0);
+        pid.set_target(0.0, 0.5);
+        let mut target = 0.5;
+        for _ in 0..30 {
+            //about what WallFollow drifts by each tick
+            target += 0.005;
+            pid.set_target(0.5, target);
+            pid.step(DT, 0.0);
+        }
+        assert!(pid.integral > 0.5 * DT * 30.0);
+    }
+
+    #[test]
+    fn zero_dt_is_ignored() {
+        let mut pid = pid(1.0, 1.0, 1.0);
+        pid.set_target(0.0, 1.0);
+        pid.step(0.0, 0.0);
+        assert_eq!(pid.output, 0.0);
+        assert!(pid.integral.is_finite() && pid.derivative.is_finite());
+    }
+}
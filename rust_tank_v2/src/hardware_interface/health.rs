@@ -0,0 +1,176 @@
+//! Errors from the devices, and how the real time threads decide when to
+//! reset a misbehaving device or give up on it.
+
+use std::fmt;
+use std::sync::mpsc::{Sender, SendError};
+
+use i2cdev::linux::LinuxI2CError;
+use sysfs_gpio;
+
+use super::real_time::RTResponse;
+use super::Device;
+
+/// Errors in a row before a device is reinitialized
+const ERRORS_BEFORE_REINIT: u32 = 3;
+/// Reinitializations in a row, without a good reading between them, before giving up
+const MAX_REINITS: u32 = 3;
+
+/// Errors that can occur while bringing up or running the hardware
+#[derive(Debug)]
+pub enum DeviceError {
+    I2C(LinuxI2CError),
+    Gpio(sysfs_gpio::Error),
+}
+
+impl From<LinuxI2CError> for DeviceError {
+    fn from(e: LinuxI2CError) -> DeviceError {
+        DeviceError::I2C(e)
+    }
+}
+
+impl From<sysfs_gpio::Error> for DeviceError {
+    fn from(e: sysfs_gpio::Error) -> DeviceError {
+        DeviceError::Gpio(e)
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceError::I2C(ref e) => write!(f, "I2C error: {}", e),
+            DeviceError::Gpio(ref e) => write!(f, "GPIO error: {}", e),
+        }
+    }
+}
+
+/// A change in how a device is doing
+#[derive(Debug)]
+pub enum DeviceHealth {
+    /// Working again after errors
+    Recovered,
+    /// Something went wrong, it will be tried again
+    Error(DeviceError),
+    /// Reset after too many errors in a row
+    Reinitialized,
+    /// Given up on, the motors have been turned off
+    Failed(DeviceError),
+    /// The thread running the device ended unexpectedly, the motors have been turned off
+    Stopped,
+}
+
+impl fmt::Display for DeviceHealth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceHealth::Recovered => write!(f, "working again"),
+            DeviceHealth::Error(ref e) => write!(f, "{}", e),
+            DeviceHealth::Reinitialized => write!(f, "reinitialized after repeated errors"),
+            DeviceHealth::Failed(ref e) => write!(f, "failed, motors stopped: {}", e),
+            DeviceHealth::Stopped => write!(f, "real time threads stopped, motors stopped"),
+        }
+    }
+}
+
+/// What to do about a device after an error
+#[derive(Debug, PartialEq)]
+pub enum Next {
+    Retry,
+    Reinit,
+    GiveUp,
+}
+
+/// Counts a device's errors in a row, and reports its health to the main thread
+pub struct HealthTracker {
+    device: Device,
+    errors: u32,
+    reinits: u32,
+}
+
+impl HealthTracker {
+    pub fn new(device: Device) -> HealthTracker {
+        HealthTracker { device, errors: 0, reinits: 0 }
+    }
+
+    /// Call after the device worked
+    pub fn ok(&mut self, tx: &Sender<RTResponse>) -> Result<(), SendError<RTResponse>> {
+        let was_failing = self.errors > 0 || self.reinits > 0;
+        self.errors = 0;
+        self.reinits = 0;
+        if was_failing {
+            self.report(DeviceHealth::Recovered, tx)?;
+        }
+        Ok(())
+    }
+
+    /// Call after the device failed, reports the error and says what to do next.
+    /// On GiveUp the device is reported as failed, and shouldn't be used again.
+    pub fn error<E: Into<DeviceError>>(&mut self, e: E, tx: &Sender<RTResponse>)
+            -> Result<Next, SendError<RTResponse>> {
+        self.errors += 1;
+        let next = if self.errors < ERRORS_BEFORE_REINIT {
+            Next::Retry
+        } else if self.reinits < MAX_REINITS {
+            self.errors = 0;
+            self.reinits += 1;
+            Next::Reinit
+        } else {
+            Next::GiveUp
+        };
+        let health = match next {
+            Next::GiveUp => DeviceHealth::Failed(e.into()),
+            _ => DeviceHealth::Error(e.into()),
+        };
+        self.report(health, tx)?;
+        Ok(next)
+    }
+
+    pub fn report(&self, health: DeviceHealth, tx: &Sender<RTResponse>) -> Result<(), SendError<RTResponse>> {
+        tx.send(RTResponse::Health(self.device, health))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn error() -> DeviceError {
+        DeviceError::Gpio(sysfs_gpio::Error::Unexpected(String::from("test")))
+    }
+
+    #[test]
+    fn retries_then_reinits_then_gives_up() {
+        let (tx, rx) = mpsc::channel();
+        let mut health = HealthTracker::new(Device::Imu);
+        let mut steps = vec![];
+        for _ in 0..(ERRORS_BEFORE_REINIT * (MAX_REINITS + 1)) {
+            steps.push(health.error(error(), &tx).unwrap());
+        }
+        assert_eq!(steps.iter().filter(|&n| *n == Next::Reinit).count(), MAX_REINITS as usize);
+        assert_eq!(steps.last(), Some(&Next::GiveUp));
+        match rx.try_iter().last() {
+            Some(RTResponse::Health(Device::Imu, DeviceHealth::Failed(_))) => (),
+            _ => panic!("expected the last report to be a failure"),
+        }
+    }
+
+    #[test]
+    fn good_reading_resets_counts() {
+        let (tx, rx) = mpsc::channel();
+        let mut health = HealthTracker::new(Device::Pwm);
+        for _ in 0..(ERRORS_BEFORE_REINIT * MAX_REINITS) {
+            health.error(error(), &tx).unwrap();
+        }
+        health.ok(&tx).unwrap();
+        match rx.try_iter().last() {
+            Some(RTResponse::Health(Device::Pwm, DeviceHealth::Recovered)) => (),
+            _ => panic!("expected a recovery report"),
+        }
+        assert_eq!(health.error(error(), &tx).unwrap(), Next::Retry);
+        //only reported when it had been failing
+        health.ok(&tx).unwrap();
+        health.ok(&tx).unwrap();
+        assert_eq!(rx.try_iter()
+            .filter(|r| matches!(*r, RTResponse::Health(_, DeviceHealth::Recovered)))
+            .count(), 1);
+    }
+}
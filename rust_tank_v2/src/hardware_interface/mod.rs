@@ -1,18 +1,34 @@
-/// Handles communication between time sensitive components (I2C bus)
-/// and the rest of the program
+//! Handles communication between time sensitive components (I2C bus)
+//! and the rest of the program
 
 use std::sync::mpsc::{Receiver, TryRecvError, Sender};
 use std::thread::{JoinHandle};
 
-use i2cdev::linux::LinuxI2CError;
+use i2cdev::linux::LinuxI2CDevice;
+use i2cdev_bno055::{BNO055, BNO055_DEFAULT_ADDR};
+use pca9685::PCA9685;
 
 mod real_time;
+mod health;
 mod sensor_processing;
 mod drive_pid;
+mod speed_control;
+mod controller;
+pub mod devices;
 
-pub use self::real_time::{RTCommand, RTResponse, RawSensorState, Vec3};
+pub use self::real_time::{RTCommand, RTResponse};
+pub use self::health::{DeviceError, DeviceHealth};
+pub use tank_protocol::state::{RawSensorState, EncoderState, LoopStats, Vec3, Pose, PidTelemetry, Device, SensorReport};
+pub use tank_protocol::timestamp;
+use self::devices::{PwmOutput, OrientationSensor, RangeSensor, TrackEncoders, HcSr04, GpioEncoders, pwm_error};
 pub use self::sensor_processing::SensorState;
+pub use self::controller::Controller;
 use ::tcp_interface::TcpInterface;
+use ::config::Config;
+use ::recorder::{Recorder, Entry};
+use ::csv_log::CsvLog;
+use ::tcp_interface::Requester;
+use ::tcp_interface::messages::Command;
 
 
 /// Interface to the real time thread
@@ -22,18 +38,30 @@ pub struct RTHandle {
     tx: Sender<RTCommand>,
     i2c_handle: JoinHandle<()>,
     sonar_handle: JoinHandle<()>,
-    sensor_state: SensorState,
-    drive_pid: drive_pid::DrivePid,
+    /// Only running if there are encoders
+    encoder_handle: Option<JoinHandle<()>>,
+    controller: Controller,
+    /// Where to record everything going in and out, if anywhere
+    recorder: Option<Recorder>,
+    /// Where to write a CSV row each tick, if anywhere
+    csv: Option<CsvLog>,
+    /// Set once a device has been given up on, the motors are kept off after that
+    failed: Option<Device>,
 }
 
+#[derive(Debug)]
 pub enum RTEvent {
     /// Something got too close to the front of the tank
     SonarProximity,
     SteepIncline,
     TargetAngleReached,
     TargetTimeReached,
-    /// Some non-fatal i2c error
-    Err(LinuxI2CError),
+    /// The real time thread turned the motors off, since no drive commands
+    /// arrived for a while
+    WatchdogTripped,
+    /// A device started or stopped having problems.
+    /// After a Failed the motors stay off.
+    Health(Device, DeviceHealth),
 }
 
 impl RTHandle {
@@ -41,100 +69,276 @@ impl RTHandle {
     /// portions of the controller, and the timing critical portions. This sets
     /// up the initial state of the I2C devices, and starts the
     /// thread that controls them.
-    pub fn initialize() -> Result<RTHandle, LinuxI2CError> {
+    pub fn initialize(config: &Config) -> Result<RTHandle, DeviceError> {
+        // initialize PWM hardware
+        let mut pca = PCA9685::open_default().map_err(pwm_error)?;
+        pca.set_verify(config.motors.verify_pwm);
 
-        let (i2c_handle, sonar_handle, tx, rx)
-            = real_time::create()?;
+        // initialize MPU hardware
+        let bno = LinuxI2CDevice::new("/dev/i2c-1", BNO055_DEFAULT_ADDR)?;
+        let mut bno = BNO055::new(bno)?;
+        OrientationSensor::reinit(&mut bno)?;
 
-        //TODO tune drive
-        let drive_pid = drive_pid::DrivePid::new(2.0, 1.0, 0.0);
+        let sonar = HcSr04::new(config.sonar.trigger_pin, config.sonar.echo_pin)?;
+
+        let encoders = if config.encoders.enabled {
+            let e = &config.encoders;
+            let encoders = GpioEncoders::new(e.left_a, e.left_b, e.right_a, e.right_b)?;
+            Some(Box::new(encoders) as Box<dyn TrackEncoders>)
+        } else {
+            None
+        };
+
+        RTHandle::with_devices(pca, bno, sonar, encoders, config)
+    }
+
+    /// Same as initialize, but runs against the given devices instead of
+    /// the ones on the tank.
+    pub fn with_devices<P, O, S>(pwm: P, imu: O, sonar: S, encoders: Option<Box<dyn TrackEncoders>>,
+                                 config: &Config) -> Result<RTHandle, DeviceError>
+            where P: PwmOutput + 'static,
+                  O: OrientationSensor + 'static,
+                  S: RangeSensor + 'static {
+
+        let (i2c_handle, sonar_handle, encoder_handle, tx, rx)
+            = real_time::create(pwm, imu, sonar, encoders, config)?;
 
         Ok(RTHandle {
             rx, tx,
-            i2c_handle, sonar_handle,
-            sensor_state: SensorState::default(),
-            drive_pid,
+            i2c_handle, sonar_handle, encoder_handle,
+            controller: Controller::new(config),
+            recorder: None,
+            csv: None,
+            failed: None,
         })
     }
 
     /// Goes through all state updates received, and updates the current state
     /// Returns any IO errors that occured on the other thread since last call to update
-    /// Blocks until a message is received
+    /// Blocks until a message is received, unless the real time threads have all ended
     pub fn update(&mut self, send_updates: bool, tcp_interface: &mut TcpInterface) -> Vec<RTEvent> {
 
         let mut events = vec![];
         let mut next = match self.rx.recv() {
             Ok(rss) => rss,
             Err(_) => {
-                panic!("Real time thread aborted!");
+                //every real time thread is gone, they turned the motors off on the way out
+                if self.failed.is_none() {
+                    self.failed = Some(Device::Pwm);
+                    events.push(RTEvent::Health(Device::Pwm, DeviceHealth::Stopped));
+                }
+                return events;
             }
         };
         'queue: loop {
             match next {
-                RTResponse::I2C(Ok(new_state)) => {
-                    let event = self.sensor_state.update(new_state, self.drive_pid.target_power());
-                    self.drive_pid.update(&self.sensor_state);
+                RTResponse::I2C(new_state) => {
+                    if self.recorder.is_some() {
+                        self.record(Entry::Imu(new_state.clone()));
+                    }
+                    let event = self.controller.update_imu(new_state);
+                    let controller = &self.controller;
+                    if let Some(Err(e)) = self.csv.as_mut().map(|c| c.write_row(controller)) {
+                        eprintln!("Stopped the CSV log: {}", e);
+                        self.csv = None;
+                    }
                     if send_updates {
-                        tcp_interface.send_state(&self.sensor_state);
+                        tcp_interface.send_state(self.controller.sensor_state());
+                    }
+                    if tcp_interface.auto_send_pid() {
+                        tcp_interface.send_pid(self.controller.pid_telemetry());
                     }
                     if let Some(e) = event {
                         events.push(e);
                     }
                 },
-                RTResponse::I2C(Err(err)) => {
-                    events.push(RTEvent::Err(err));
-                },
                 RTResponse::Sonar(cm, time) => {
-                    if let Some(event) = self.sensor_state.set_sonar((cm, time)) {
+                    self.record(Entry::Sonar { cm, time });
+                    if let Some(event) = self.controller.update_sonar(cm, time) {
                         events.push(event);
                     }
-                }
+                },
+                RTResponse::Encoders(state) => {
+                    self.record(Entry::Encoders(state));
+                    self.controller.update_encoders(state);
+                },
+                RTResponse::Timing(stats) => {
+                    self.controller.sensor_state_mut().set_loop_stats(stats);
+                },
+                RTResponse::WatchdogTripped => {
+                    events.push(RTEvent::WatchdogTripped);
+                },
+                RTResponse::Health(device, health) => {
+                    match health {
+                        DeviceHealth::Failed(_) | DeviceHealth::Stopped if self.failed.is_none() => {
+                            self.failed = Some(device);
+                            self.stop();
+                        },
+                        _ => (),
+                    }
+                    events.push(RTEvent::Health(device, health));
+                },
             }
             next = match self.rx.try_recv() {
                 Ok(rss) => rss,
                 Err(TryRecvError::Empty) => break 'queue,
-                Err(TryRecvError::Disconnected) => {
-                    panic!("Real time thread aborted!");
-                },
+                //picked up by the next call
+                Err(TryRecvError::Disconnected) => break 'queue,
             };
         }
         //now let things do updates that aren't retroactive
-        for msg in self.drive_pid.get_pwm_commands() {
-            self.send_command(msg);
+        if self.failed.is_none() {
+            let commands = self.controller.pwm_commands();
+            if self.recorder.is_some() {
+                self.record(Entry::Motors(commands.clone()));
+            }
+            for msg in commands {
+                //only fails once the i2c thread has ended, see send_command
+                let _ = self.tx.send(msg);
+            }
+        }
+        if self.recorder.is_some() {
+            for event in &events {
+                self.record(Entry::Event(event.into()));
+            }
+        }
+        if let Some(Err(e)) = self.recorder.as_mut().map(|r| r.flush()) {
+            eprintln!("Stopped recording: {}", e);
+            self.recorder = None;
+        }
+        if let Some(Err(e)) = self.csv.as_mut().map(|c| c.flush()) {
+            eprintln!("Stopped the CSV log: {}", e);
+            self.csv = None;
         }
         events
     }
 
     /// Sends a command to an I2C device
     fn send_command(&mut self, command: RTCommand) {
-        //shouldn't panic unless the other thread terminates
-        if let Err(_) =self.tx.send(command) {
-            //TODO ensure motors are stopped
-            //If the real time thread ends (or drops the communication pipe
-            //then we cannot recover, except maybe make the HW enter a safe state.
-            panic!("Real time thread aborted!");
+        self.record(Entry::Command(command));
+        //only fails once the i2c thread has ended, which turned the motors off
+        //on the way out, so there is nothing more to do
+        let _ = self.tx.send(command);
+    }
+
+    /// Records everything going in and out from now on
+    pub fn record_to(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Writes a CSV row every tick from now on, replacing any log already going
+    pub fn start_csv(&mut self, log: CsvLog) {
+        self.csv = Some(log);
+    }
+
+    /// Stops the CSV log, returns false if there wasn't one
+    pub fn stop_csv(&mut self) -> bool {
+        match self.csv.take() {
+            Some(mut log) => {
+                if let Err(e) = log.flush() {
+                    eprintln!("Failed to finish the CSV log: {}", e);
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Adds a command received over TCP to the recording, if there is one
+    pub fn record_command(&mut self, from: Requester, command: &Command) {
+        if self.recorder.is_some() {
+            self.record(Entry::Tcp { from, command: command.clone() });
         }
     }
 
-    pub fn set_drive(&mut self, power: f32, turn: f32) {
-        self.drive_pid.set_target(power, turn);
+    fn record(&mut self, entry: Entry) {
+        let result = match self.recorder {
+            Some(ref mut recorder) => recorder.record(entry),
+            None => return,
+        };
+        if let Err(e) = result {
+            eprintln!("Stopped recording: {}", e);
+            self.recorder = None;
+        }
+    }
+
+    /// The device that was given up on, if any. The motors stay off once one has.
+    pub fn failed(&self) -> Option<Device> {
+        self.failed
+    }
+
+    /// Sets the forward speed (in m/s) and heading (in radians) to hold
+    pub fn set_drive(&mut self, speed: f32, turn: f32) {
+        self.record(Entry::Drive { speed, turn });
+        self.controller.set_drive(speed, turn);
+    }
+
+    /// Returns the (proportional, integral, derivative) gains of the drive PID
+    pub fn pid_gains(&self) -> (f32, f32, f32) {
+        self.controller.pid_gains()
+    }
+
+    pub fn set_pid_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        self.record(Entry::PidGains { kp, ki, kd });
+        self.controller.set_pid_gains(kp, ki, kd);
+    }
+
+    /// Clears the integral and error history of the drive PID
+    pub fn reset_pid(&mut self) {
+        self.record(Entry::ResetPid);
+        self.controller.reset_pid();
+    }
+
+    pub fn pid_telemetry(&self) -> PidTelemetry {
+        self.controller.pid_telemetry()
+    }
+
+    /// The current (speed, turn) the drive is aiming for
+    pub fn drive_target(&self) -> (f32, f32) {
+        self.controller.drive_target()
     }
 
     pub fn sensor_state(&mut self) -> &mut SensorState {
-        &mut self.sensor_state
+        self.controller.sensor_state_mut()
     }
 
     /// Stops all the motors
     pub fn stop(&mut self) {
-        self.drive_pid.set_target(0.0, 0.0);
+        self.record(Entry::Stop);
+        self.controller.stop();
         self.send_command(RTCommand::StopAllMotors);
     }
 
     pub fn close(mut self) {
         self.send_command(RTCommand::StopAllMotors);
         self.send_command(RTCommand::End);
-        self.i2c_handle.join().expect("Real time I2C thread paniced!");
-        self.sonar_handle.join().expect("Real time Sonar thread paniced!");
+        if let Some(Err(e)) = self.recorder.as_mut().map(|r| r.flush()) {
+            eprintln!("Failed to finish the recording: {}", e);
+        }
+        self.stop_csv();
+        let RTHandle { rx, i2c_handle, sonar_handle, encoder_handle, .. } = self;
+        //the sonar and encoder threads only end once they have no one to send to
+        drop(rx);
+        if i2c_handle.join().is_err() {
+            eprintln!("Real time I2C thread paniced!");
+        }
+        if sonar_handle.join().is_err() {
+            eprintln!("Real time Sonar thread paniced!");
+        }
+        if let Some(Err(_)) = encoder_handle.map(|h| h.join()) {
+            eprintln!("Real time encoder thread paniced!");
+        }
+    }
+}
+
+/// Limits value to between -limit and limit
+fn clamp(value: f32, limit: f32) -> f32 {
+    if value > limit {
+        limit
+    } else if value < -limit {
+        -limit
+    } else {
+        value
     }
 }
 
@@ -1,25 +1,21 @@
 
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, TryRecvError, Sender};
+use std::sync::mpsc::{Receiver, TryRecvError, Sender, SendError};
 use std::thread;
 use std::thread::sleep;
 use std::thread::{JoinHandle};
-use std::time::{SystemTime, Duration};
+use std::time::{Duration, Instant};
 
-use super::on_export;
-use sysfs_gpio::{Direction, Pin, Edge};
+use floating_duration::TimeAsFloat;
+use i2cdev::linux::LinuxI2CError;
 
-use i2cdev::linux::{LinuxI2CError, LinuxI2CDevice};
-
-use i2cdev_bno055::{BNO055, BNO055_DEFAULT_ADDR, BNO055OperationMode};
-
-use pca9685::PCA9685;
-use i2csensors::{Accelerometer, Gyroscope, Magnetometer, Thermometer};
-use i2csensors::Vec3 as iVec3;
-
-pub const PWM_FREQ: f32 = 120.0;
+use super::devices::{PwmOutput, OrientationSensor, RangeSensor, TrackEncoders};
+use super::health::{DeviceHealth, HealthTracker, Next};
+use super::{Device, RawSensorState, EncoderState, LoopStats};
+use ::config::Config;
 
 /// Possible commands for i2d devices
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum RTCommand {
     SetPwm {
         pwm: u8,
@@ -36,51 +32,113 @@ pub enum RTCommand {
 
 /// Values that are sent from the sonar/i2c threads
 pub enum RTResponse {
-    I2C(Result<RawSensorState, LinuxI2CError>),
-    Sonar(f32, SystemTime),//in cm
+    I2C(RawSensorState),
+    Sonar(f32, Instant),//in cm
+    Encoders(EncoderState),
+    /// No drive command arrived in time, so all the motors were turned off
+    WatchdogTripped,
+    /// A device started or stopped having problems
+    Health(Device, DeviceHealth),
+    /// How steady the i2c loop has been since the last report
+    Timing(LoopStats),
 }
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone)]
-pub struct Vec3 {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
+/// The i2c, sonar and encoder threads, and the channels to talk to them
+pub type RTThreads = (JoinHandle<()>, JoinHandle<()>, Option<JoinHandle<()>>, Sender<RTCommand>, Receiver<RTResponse>);
+
+/// How often the i2c loop reports its timing
+const TIMING_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps track of the i2c loop's timing between reports
+struct LoopTimer {
+    target: Duration,
+    last_start: Option<Instant>,
+    report_start: Instant,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+    periods: u32,
+    overruns: u32,
+    total_overruns: u64,
 }
-impl From<iVec3> for Vec3 {
-    fn from(t: iVec3) -> Vec3 {
-        Vec3 {
-            x: t.x,
-            y: t.y,
-            z: t.z
+
+impl LoopTimer {
+    fn new(target: Duration, now: Instant) -> LoopTimer {
+        LoopTimer {
+            target,
+            last_start: None,
+            report_start: now,
+            min: Duration::default(),
+            max: Duration::default(),
+            total: Duration::default(),
+            periods: 0,
+            overruns: 0,
+            total_overruns: 0,
         }
     }
-}
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct RawSensorState {
-    pub time: SystemTime,
-    pub gyro: Vec3,
-    pub accel: Vec3,
-    pub mag: Vec3,
-    pub orientation: Vec3,
-    pub temp: f32,
-    //TODO should PWM state be included?
-}
-impl Default for RawSensorState {
-    fn default() -> RawSensorState {
-        RawSensorState {
-            time: SystemTime::now(),
-            gyro: Vec3::default(),
-            accel: Vec3::default(),
-            mag: Vec3::default(),
-            orientation: Vec3::default(),
-            temp: 0.0,
+    /// Marks the start of a loop
+    fn start(&mut self, now: Instant) {
+        if let Some(last) = self.last_start {
+            let period = now - last;
+            if self.periods == 0 || period < self.min {
+                self.min = period;
+            }
+            if period > self.max {
+                self.max = period;
+            }
+            self.total += period;
+            self.periods += 1;
         }
+        self.last_start = Some(now);
+    }
+
+    /// Marks the end of a loop's work, returns how long to sleep until the next
+    fn finish(&mut self, now: Instant) -> Duration {
+        let elapsed = self.last_start.map(|start| now - start).unwrap_or_default();
+        if self.target > elapsed {
+            self.target - elapsed
+        } else {
+            self.overruns += 1;
+            self.total_overruns += 1;
+            Duration::new(0, 1000000)
+        }
+    }
+
+    /// The timing since the last report, once another is due
+    fn report(&mut self, now: Instant) -> Option<LoopStats> {
+        if now - self.report_start < TIMING_REPORT_INTERVAL || self.periods == 0 {
+            return None;
+        }
+        let stats = LoopStats {
+            min_period: self.min.as_fractional_secs() as f32,
+            max_period: self.max.as_fractional_secs() as f32,
+            mean_period: self.total.as_fractional_secs() as f32 / self.periods as f32,
+            overruns: self.overruns,
+            total_overruns: self.total_overruns,
+        };
+        self.report_start = now;
+        self.min = Duration::default();
+        self.max = Duration::default();
+        self.total = Duration::default();
+        self.periods = 0;
+        self.overruns = 0;
+        Some(stats)
     }
 }
 
-pub fn create() -> Result<(JoinHandle<()>, JoinHandle<()>, Sender<RTCommand>, Receiver<RTResponse>), LinuxI2CError> {
+/// Sets up the pwm driver and starts the real time threads.
+/// The i2c thread runs at the configured loop rate, the encoder thread
+/// only runs if there are encoders.
+pub fn create<P, O, S>(mut pwm: P, imu: O, sonar: S, encoders: Option<Box<dyn TrackEncoders>>, config: &Config)
+        -> Result<RTThreads, LinuxI2CError>
+        where P: PwmOutput + 'static,
+              O: OrientationSensor + 'static,
+              S: RangeSensor + 'static {
 
+    // initialize PWM hardware
+    pwm.set_all_pwm_off()?;
+    pwm.set_pwm_freq(config.motors.pwm_freq)?;
 
     // setup communication channels
     let (i2c_tx, rx) = mpsc::channel();
@@ -88,119 +146,392 @@ pub fn create() -> Result<(JoinHandle<()>, JoinHandle<()>, Sender<RTCommand>, Re
     let (tx, i2c_rx) = mpsc::channel();
     // setup the real time thread
     //TODO consider setting system thread priority
-    let i2c_handle = thread::spawn(|| rt_i2c_loop(i2c_tx, i2c_rx));
-    let sonar_handle = thread::spawn(|| rt_sonar_loop(sonar_tx));
+    let target_interval = Duration::from_nanos((1e9 / config.real_time.loop_hz) as u64);
+    let settings = I2CSettings {
+        pwm_freq: config.motors.pwm_freq,
+        target_interval,
+        watchdog_timeout: Duration::from_nanos((config.safety.watchdog_timeout * 1e9) as u64),
+    };
+    let encoder_handle = encoders.map(|encoders| {
+        let encoder_tx = i2c_tx.clone();
+        let ticks_per_meter = config.encoders.ticks_per_meter;
+        let interval = Duration::from_nanos((1e9 / config.encoders.sample_hz) as u64);
+        thread::spawn(move || rt_encoder_loop(encoders, ticks_per_meter, interval, encoder_tx))
+    });
+    let i2c_handle = thread::spawn(move || rt_i2c_loop(pwm, imu, settings, i2c_tx, i2c_rx));
+    let sonar_handle = thread::spawn(move || rt_sonar_loop(sonar, sonar_tx));
 
-    Ok((i2c_handle, sonar_handle, tx, rx))
+    Ok((i2c_handle, sonar_handle, encoder_handle, tx, rx))
 }
 
-fn rt_i2c_loop(tx: Sender<RTResponse>,
-               rx: Receiver<RTCommand>) {
-    let target_interval = Duration::new(0,16666667);
+/// Timing and setup of the i2c thread
+struct I2CSettings {
+    pwm_freq: f32,
+    target_interval: Duration,
+    /// How long to go without a drive command before turning the motors off
+    watchdog_timeout: Duration,
+}
 
-    // initialize PWM hardware
-    let mut pca = PCA9685::default().unwrap();
-    pca.set_all_pwm_off().unwrap();
-    pca.set_pwm_freq(PWM_FREQ).unwrap();
+/// Turns the motors off when dropped, so they stop however the i2c thread ends
+struct MotorsOff<P: PwmOutput>(P);
 
-    // initialize MPU hardware
-    let bno = LinuxI2CDevice::new("/dev/i2c-1", BNO055_DEFAULT_ADDR).unwrap();
-    let mut bno = BNO055::new(bno).unwrap();
-    bno.reset().unwrap();
-    bno.set_external_crystal(true).unwrap();
-    bno.set_mode(BNO055OperationMode::Ndof).unwrap();
+impl<P: PwmOutput> Drop for MotorsOff<P> {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.set_all_pwm_off() {
+            eprintln!("Failed to turn the motors off: {}", e);
+        }
+    }
+}
+
+/// Tells the main thread if the i2c thread panics. The other real time
+/// threads keep the channel open, so it wouldn't notice otherwise.
+struct ReportPanic(Sender<RTResponse>);
+
+impl Drop for ReportPanic {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.0.send(RTResponse::Health(Device::Pwm, DeviceHealth::Stopped));
+        }
+    }
+}
+
+/// Sets the pwm driver up from scratch, with all the motors off
+fn reinit_pwm<P: PwmOutput>(pwm: &mut P, freq_hz: f32) -> Result<(), LinuxI2CError> {
+    pwm.reinit()?;
+    pwm.set_all_pwm_off()?;
+    pwm.set_pwm_freq(freq_hz)
+}
+
+fn rt_i2c_loop<P: PwmOutput, O: OrientationSensor>(pwm: P,
+               imu: O,
+               settings: I2CSettings,
+               tx: Sender<RTResponse>,
+               rx: Receiver<RTCommand>) {
+    //dropped after the motors are off
+    let _report = ReportPanic(tx.clone());
+    let mut pwm = MotorsOff(pwm);
+    //an error here only means main hung up, there's no one left to tell
+    let _ = run_i2c(&mut pwm.0, imu, &settings, &tx, &rx);
+}
 
+/// Runs the I2C devices, retrying and reinitializing them after errors.
+/// If no drive command arrives for the watchdog timeout the motors are
+/// turned off, in case the main thread has stalled.
+/// Returns when told to end, or after giving up on a device.
+fn run_i2c<P: PwmOutput, O: OrientationSensor>(pwm: &mut P,
+           mut imu: O,
+           settings: &I2CSettings,
+           tx: &Sender<RTResponse>,
+           rx: &Receiver<RTCommand>) -> Result<(), SendError<RTResponse>> {
+
+    let mut pwm_health = HealthTracker::new(Device::Pwm);
+    let mut imu_health = HealthTracker::new(Device::Imu);
+    let mut last_command = Instant::now();
+    let mut watchdog_tripped = false;
+    let mut timer = LoopTimer::new(settings.target_interval, Instant::now());
+    //channel updates are collected and sent together, the driver can batch them
+    let mut updates: Vec<(u8, u16, u16)> = Vec::with_capacity(16);
     loop {
-        let time = SystemTime::now();
-        if let Err(_) = tx.send(RTResponse::I2C(collect_data(&mut bno, &mut pca, time.clone()))) {
-            return; //Main thread ended / dropped the handle
+        let time = Instant::now();
+        timer.start(time);
+        match imu.read(time) {
+            Ok(state) => {
+                imu_health.ok(tx)?;
+                tx.send(RTResponse::I2C(state))?;
+            },
+            Err(e) => match imu_health.error(e, tx)? {
+                Next::Retry => (),
+                Next::Reinit => match imu.reinit() {
+                    Ok(()) => imu_health.report(DeviceHealth::Reinitialized, tx)?,
+                    Err(e) => imu_health.report(DeviceHealth::Error(e.into()), tx)?,
+                },
+                Next::GiveUp => return Ok(()),
+            },
         }
         'commands: loop {
-            if let Err(e) = match rx.try_recv() {
+            let command = match rx.try_recv() {
                 Err(TryRecvError::Empty) => break 'commands, //nothing to do
-                Err(TryRecvError::Disconnected) => return, //Main thread ended / dropped the handle
-                Ok(RTCommand::SetPwm {pwm, on, off}) => pca.set_pwm(pwm, on, off),
-                Ok(RTCommand::SetPwmOff(pwm)) => pca.set_pwm_off(pwm),
-                Ok(RTCommand::SetPwmOn(pwm)) => pca.set_pwm_on(pwm),
-                Ok(RTCommand::StopAllMotors) => pca.set_all_pwm_off(),
-                Ok(RTCommand::End) => return, //Main thread asked us to stop
-            } {
-                if let Err(_) = tx.send(RTResponse::I2C(Err(e))) { return; } // main dropped its rx
+                Err(TryRecvError::Disconnected) => return Ok(()), //Main thread ended / dropped the handle
+                Ok(RTCommand::End) => return Ok(()), //Main thread asked us to stop
+                Ok(command) => command,
+            };
+            last_command = Instant::now();
+            watchdog_tripped = false;
+            match command {
+                RTCommand::SetPwm {pwm: channel, on, off} => updates.push((channel, on, off)),
+                RTCommand::SetPwmOff(channel) => updates.push((channel, 0, 4096)),
+                RTCommand::SetPwmOn(channel) => updates.push((channel, 4096, 0)),
+                RTCommand::StopAllMotors | RTCommand::End => {
+                    //anything waiting would only be turned off again
+                    updates.clear();
+                    let result = pwm.set_all_pwm_off();
+                    if !pwm_result(result, pwm, &mut pwm_health, settings, tx)? {
+                        return Ok(());
+                    }
+                },
             }
         }
-        //Sync
-        let elapsed = SystemTime::now().duration_since(time);
-        let elapsed = elapsed.unwrap_or(Duration::new(0, 1000));//tiny amount of time
-        if target_interval > elapsed {
-            thread::sleep(target_interval - elapsed);
-        } else {
-            thread::sleep(Duration::new(0, 1000000));
-        }
-
-    }
-}
-
-fn collect_data(bno: &mut BNO055<LinuxI2CDevice>, _pca: &mut PCA9685, time: SystemTime) -> Result<RawSensorState, LinuxI2CError> {
-    let orientation = Vec3::from(bno.get_euler()?);
-    let accel = Vec3::from(bno.acceleration_reading()?);
-    let gyro = Vec3::from(bno.angular_rate_reading()?);
-    let mag = Vec3::from(bno.magnetic_reading()?);
-    let temp = bno.temperature_celsius()?;
-    Ok(RawSensorState {
-        orientation, accel, mag, time, gyro, temp,
-    })
-}
-
-fn rt_sonar_loop(tx: Sender<RTResponse>) {
-    let trigger_pin = Pin::new(18);
-    let echo_pin = Pin::new(25);
-    trigger_pin
-        .with_exported(|| {
-            echo_pin.with_exported(|| {
-                on_export::wait();
-                trigger_pin.set_direction(Direction::Out).unwrap();
-                echo_pin.set_direction(Direction::In).unwrap();
-                echo_pin.set_edge(Edge::BothEdges).unwrap();
-                let mut echo_pin_puller = echo_pin.get_poller().unwrap();
-
-                'sonar: loop {
-                    //run trigger
-                    //println!("Sending trigger");
-                    trigger_pin.set_value(1).unwrap();
-                    sleep(Duration::from_millis(1));
-                    trigger_pin.set_value(0).unwrap();
-
-                    //wait for signal
-                    if echo_pin_puller.poll(500).unwrap() == None {
-                        //println!("No read - trying again");
-                        continue;
-                    }
-                    let start = SystemTime::now();
-
-                    //wait for end
-                    match echo_pin_puller.poll(500) {
-                        Ok(Some(_)) => {
-                            //calculate how long
-                            let end = SystemTime::now();
-                            let time = end.duration_since(start).unwrap();
-
-                            //Convert to distance
-                            let distance_cm = (time.subsec_nanos() as f32 * 34300.0) / 2000000000.0;
-                            //println!("Distance is {} cm", distance_cm);
-                            if let Err(_) = tx.send(RTResponse::Sonar(distance_cm, end)) {
-                                break 'sonar; //send only fails when the other end hung up
-                            }
-
-                            //let sonar sleep a little
-                            sleep(Duration::from_micros(10000));
-                        },
-                        Err(e) => panic!("Something weird happened {:?}", e),
-                        Ok(None) => (), //println!("Echo timed out"),
+        if !updates.is_empty() {
+            let result = pwm.set_pwms(&updates);
+            updates.clear();
+            if !pwm_result(result, pwm, &mut pwm_health, settings, tx)? {
+                return Ok(());
+            }
+        }
+        if !watchdog_tripped && last_command.elapsed() > settings.watchdog_timeout {
+            watchdog_tripped = true;
+            match pwm.set_all_pwm_off() {
+                Ok(()) => tx.send(RTResponse::WatchdogTripped)?,
+                //leave the watchdog armed so it tries again
+                Err(e) => {
+                    watchdog_tripped = false;
+                    if pwm_health.error(e, tx)? == Next::GiveUp {
+                        return Ok(());
                     }
-                }
+                },
+            }
+        }
+        //Sync
+        let now = Instant::now();
+        if let Some(stats) = timer.report(now) {
+            tx.send(RTResponse::Timing(stats))?;
+        }
+        thread::sleep(timer.finish(now));
 
-                Ok(())
-            })
-        })
-        .unwrap();
-}
\ No newline at end of file
+    }
+}
+
+/// Keeps track of how a pwm update went, reinitializing the driver if it keeps failing.
+/// Returns false once the driver has been given up on.
+fn pwm_result<P: PwmOutput>(result: Result<(), LinuxI2CError>,
+              pwm: &mut P,
+              health: &mut HealthTracker,
+              settings: &I2CSettings,
+              tx: &Sender<RTResponse>) -> Result<bool, SendError<RTResponse>> {
+    match result {
+        Ok(()) => health.ok(tx)?,
+        Err(e) => match health.error(e, tx)? {
+            Next::Retry => (),
+            Next::Reinit => match reinit_pwm(pwm, settings.pwm_freq) {
+                Ok(()) => health.report(DeviceHealth::Reinitialized, tx)?,
+                Err(e) => health.report(DeviceHealth::Error(e.into()), tx)?,
+            },
+            Next::GiveUp => return Ok(false),
+        },
+    }
+    Ok(true)
+}
+
+fn rt_sonar_loop<S: RangeSensor>(sonar: S, tx: Sender<RTResponse>) {
+    //an error here only means main hung up, there's no one left to tell
+    let _ = run_sonar(sonar, &tx);
+}
+
+/// Measures continuously, returns after giving up on the sonar
+fn run_sonar<S: RangeSensor>(mut sonar: S, tx: &Sender<RTResponse>) -> Result<(), SendError<RTResponse>> {
+    let mut health = HealthTracker::new(Device::Sonar);
+    loop {
+        match sonar.measure() {
+            Ok(Some(distance_cm)) => {
+                health.ok(tx)?;
+                tx.send(RTResponse::Sonar(distance_cm, Instant::now()))?;
+            },
+            Ok(None) => health.ok(tx)?, //No echo, try again
+            Err(e) => match health.error(e, tx)? {
+                Next::Retry => (),
+                Next::Reinit => match sonar.reinit() {
+                    Ok(()) => health.report(DeviceHealth::Reinitialized, tx)?,
+                    Err(e) => health.report(DeviceHealth::Error(e.into()), tx)?,
+                },
+                Next::GiveUp => return Ok(()),
+            },
+        }
+        //let sonar sleep a little
+        sleep(Duration::from_micros(10000));
+    }
+}
+
+fn rt_encoder_loop(encoders: Box<dyn TrackEncoders>,
+                   ticks_per_meter: f32,
+                   interval: Duration,
+                   tx: Sender<RTResponse>) {
+    //an error here only means main hung up, there's no one left to tell
+    let _ = run_encoders(encoders, ticks_per_meter, interval, &tx);
+}
+
+/// Reads the tick counts every interval, returns after giving up on the encoders
+fn run_encoders(mut encoders: Box<dyn TrackEncoders>,
+                ticks_per_meter: f32,
+                interval: Duration,
+                tx: &Sender<RTResponse>) -> Result<(), SendError<RTResponse>> {
+    let mut health = HealthTracker::new(Device::Encoders);
+    let mut last: Option<EncoderState> = None;
+    loop {
+        let time = Instant::now();
+        match encoders.ticks() {
+            Ok((left_ticks, right_ticks)) => {
+                let state = encoder_state(last, (left_ticks, right_ticks), time, ticks_per_meter);
+                last = Some(state);
+                health.ok(tx)?;
+                tx.send(RTResponse::Encoders(state))?;
+            },
+            Err(e) => match health.error(e, tx)? {
+                Next::Retry | Next::Reinit => (),
+                Next::GiveUp => return Ok(()),
+            },
+        }
+        sleep(interval);
+    }
+}
+
+/// Works out the speed of each track from how far the tick counts moved
+/// since the last reading, the speeds are 0 for the first reading
+fn encoder_state(last: Option<EncoderState>, (left_ticks, right_ticks): (i64, i64), time: Instant,
+                 ticks_per_meter: f32) -> EncoderState {
+    let (left_velocity, right_velocity) = match last {
+        Some(last) if time > last.time => {
+            let dt = (time - last.time).as_fractional_secs() as f32;
+            ((left_ticks - last.left_ticks) as f32 / ticks_per_meter / dt,
+             (right_ticks - last.right_ticks) as f32 / ticks_per_meter / dt)
+        },
+        _ => (0.0, 0.0),
+    };
+    EncoderState { time, left_ticks, right_ticks, left_velocity, right_velocity }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::devices::{Simulation, Room, FakePwm, FakeImu};
+    use super::super::sensor_processing::SensorState;
+    use ::config::MotorConfig;
+
+    const TARGET: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn loop_timing() {
+        let start = Instant::now();
+        let mut timer = LoopTimer::new(TARGET, start);
+        let mut now = start;
+        //loops of 10ms, with one taking 30ms of work
+        for i in 0..100 {
+            timer.start(now);
+            let work = if i == 50 { Duration::from_millis(30) } else { Duration::from_millis(4) };
+            let sleep = timer.finish(now + work);
+            now += work + sleep;
+        }
+        let stats = timer.report(now).unwrap();
+        assert_eq!(stats.overruns, 1);
+        assert_eq!(stats.total_overruns, 1);
+        assert!((stats.min_period - 0.01).abs() < 1e-6);
+        assert!((stats.max_period - 0.031).abs() < 1e-6);
+        assert!(stats.mean_period > 0.01 && stats.mean_period < 0.011);
+
+        //the next report starts over, apart from the total
+        timer.start(now + TARGET);
+        timer.finish(now + TARGET * 5);
+        timer.start(now + TARGET * 5);
+        let stats = timer.report(now + Duration::from_secs(2)).unwrap();
+        assert_eq!((stats.overruns, stats.total_overruns), (1, 2));
+    }
+
+    #[test]
+    fn encoder_speeds() {
+        let start = Instant::now();
+        let first = encoder_state(None, (100, -50), start, 1000.0);
+        assert_eq!((first.left_velocity, first.right_velocity), (0.0, 0.0));
+        //50 ticks in 0.1s at 1000 ticks per meter
+        let second = encoder_state(Some(first), (150, -100), start + Duration::from_millis(100), 1000.0);
+        assert!((second.left_velocity - 0.5).abs() < 1e-4);
+        assert!((second.right_velocity + 0.5).abs() < 1e-4);
+        assert_eq!((second.left_ticks, second.right_ticks), (150, -100));
+        //no time to measure a speed over
+        let again = encoder_state(Some(second), (200, 0), second.time, 1000.0);
+        assert_eq!((again.left_velocity, again.right_velocity), (0.0, 0.0));
+    }
+
+    #[test]
+    fn encoders_drive_the_pose() {
+        let motors = MotorConfig::default();
+        let sim = Simulation::new(Room::rectangle(20.0, 20.0), motors.clone());
+        let (mut pwm, mut imu, mut encoders) = (sim.pwm(), sim.imu(), sim.encoders(1000.0, true));
+        for &(channel, on) in &[(motors.left_a, true), (motors.left_b, false), (motors.left_pwm, true),
+                                (motors.right_a, true), (motors.right_b, false), (motors.right_pwm, true)] {
+            if on { pwm.set_pwm_on(channel).unwrap() } else { pwm.set_pwm_off(channel).unwrap() }
+        }
+
+        //no drive power, so anything the pose knows of the tracks moving comes from the encoders
+        let mut sensors = SensorState::default();
+        let mut last = None;
+        let start = Instant::now();
+        for i in 0..100 {
+            let time = start + Duration::from_millis(20 * i);
+            let raw = imu.read(time).unwrap();
+            let state = encoder_state(last, encoders.ticks().unwrap(), time, 1000.0);
+            last = Some(state);
+            sensors.set_encoders(state);
+            sensors.update(raw, 0.0);
+        }
+        let last = last.unwrap();
+        let travel = last.left_ticks as f32 / 1000.0;
+        assert!(travel > 0.5);
+        let pose = sensors.pose();
+        assert!((pose.x - travel).abs() < 0.02, "pose at {} after {}m", pose.x, travel);
+        assert!((pose.velocity - last.left_velocity).abs() < 0.01);
+        assert!(pose.y.abs() < 1e-3);
+    }
+
+    #[test]
+    fn watchdog_turns_the_motors_off() {
+        let settings = I2CSettings {
+            pwm_freq: 120.0,
+            target_interval: Duration::from_millis(5),
+            watchdog_timeout: Duration::from_millis(30),
+        };
+        let (tx, responses) = mpsc::channel();
+        let (commands, rx) = mpsc::channel();
+        let mut pwm = FakePwm::default();
+        pwm.channels[15] = (0, 2048);
+        let i2c = thread::spawn(move || {
+            run_i2c(&mut pwm, FakeImu, &settings, &tx, &rx).unwrap();
+            pwm
+        });
+        //long enough for the watchdog to trip, and to have tripped again if it was going to
+        sleep(Duration::from_millis(150));
+        commands.send(RTCommand::End).unwrap();
+        let pwm = i2c.join().unwrap();
+
+        assert!(pwm.channels.iter().all(|&c| c == (0, 4096)));
+        let trips = responses.try_iter().filter_map(|r| match r {
+            RTResponse::WatchdogTripped => Some(()),
+            _ => None,
+        }).count();
+        assert_eq!(trips, 1);
+    }
+
+    /// Stand in for an IMU with a bug in it
+    struct PanickingImu;
+
+    impl OrientationSensor for PanickingImu {
+        fn read(&mut self, _time: Instant) -> Result<RawSensorState, LinuxI2CError> {
+            panic!("testing the i2c thread panicking");
+        }
+    }
+
+    #[test]
+    fn panics_are_reported() {
+        let settings = I2CSettings {
+            pwm_freq: 120.0,
+            target_interval: Duration::from_millis(5),
+            watchdog_timeout: Duration::from_millis(500),
+        };
+        let (tx, responses) = mpsc::channel();
+        let (_commands, rx) = mpsc::channel();
+        let i2c = thread::spawn(move || rt_i2c_loop(FakePwm::default(), PanickingImu, settings, tx, rx));
+        assert!(i2c.join().is_err());
+        match responses.try_recv() {
+            Ok(RTResponse::Health(Device::Pwm, DeviceHealth::Stopped)) => (),
+            _ => panic!("expected the i2c thread to report stopping"),
+        }
+    }
+}
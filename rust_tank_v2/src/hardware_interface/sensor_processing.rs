@@ -1,67 +1,135 @@
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
-use super::real_time::{RawSensorState, Vec3};
+use floating_duration::TimeAsFloat;
+
+use super::{RawSensorState, Vec3, EncoderState, LoopStats, Pose, SensorReport};
 use super::RTEvent;
+use ::config::Config;
 
-use std::cmp::Ord;
 use std::f32::consts::PI;
 
-const SONAR_TOO_CLOSE: f32 = 7.5;
-const PITCH_TOO_NEG: f32 = -0.2;
-const SONAR_SAMPLES: f32 = 16.0;
+//const SONAR_SAMPLES: f32 = 16.0;
 const ANGLE_EPSILON: f32 = PI / 32.0;
+/// How much the pose estimate trusts the accelerometer over the commanded power
+const ACCEL_TRUST: f32 = 0.9;
+/// Gaps between updates longer than this (in seconds) are not integrated
+const MAX_POSE_DT: f32 = 0.25;
+
+/// Advances the pose estimate by dt seconds.
+/// Forward velocity is a complementary filter between the integrated
+/// forward acceleration and the velocity implied by the drive power.
+fn integrate(pose: &mut Pose, dt: f32, heading: f32, forward_accel: f32, power_velocity: f32) {
+    let accel_velocity = pose.velocity + forward_accel * dt;
+    let velocity = ACCEL_TRUST * accel_velocity + (1.0 - ACCEL_TRUST) * power_velocity;
+    advance(pose, dt, heading, velocity);
+}
+
+/// Advances the pose estimate by dt seconds at a known forward velocity
+fn advance(pose: &mut Pose, dt: f32, heading: f32, velocity: f32) {
+    pose.velocity = velocity;
+    //average the old and new heading so turns don't skew the position
+    let mid = pose.heading + wrap_angle(heading - pose.heading) / 2.0;
+    pose.x += pose.velocity * mid.cos() * dt;
+    pose.y += pose.velocity * mid.sin() * dt;
+    pose.heading = heading;
+}
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Wraps an angle in radians into the range [-PI, PI)
+fn wrap_angle(radians: f32) -> f32 {
+    let mut a = radians % (PI * 2.0);
+    if a >= PI { a -= PI * 2.0; }
+    if a < -PI { a += PI * 2.0; }
+    a
+}
+
+#[derive(Clone)]
 pub struct SensorState {
     /// The time between the most recent update and the previous
     duration: Duration,
     /// Time at which this sensor state was last updated.
-    time: SystemTime,
+    time: Instant,
     raw_state: RawSensorState,
     roll: f32,
     yaw: f32,
     pitch: f32,
+    /// Drive power applied to the motors, from -1 to 1
     speed: f32,
+    /// Yaw when the first sensor reading arrived, the pose is relative to this
+    start_yaw: Option<f32>,
+    pose: Pose,
+    /// Latest reading of the track encoders, if there are any
+    encoders: Option<EncoderState>,
     /// Sonar distance in CM
-    sonar: (f32, SystemTime),
-    target_time: Option<SystemTime>,
+    sonar: f32,
+    /// When the sonar was last read
+    sonar_time: Instant,
+    target_time: Option<Instant>,
     target_angle: Option<f32>,
+    /// Timing of the real time loop
+    loop_stats: LoopStats,
+    /// Sonar distance in CM that counts as something being too close
+    sonar_too_close: f32,
+    /// Pitch in radians that counts as too steep a climb
+    pitch_too_neg: f32,
+    /// Approximate ground speed of the tank at full drive power, in m/s
+    max_speed: f32,
+    /// False for single channel encoders, which can't tell which way the tracks turn
+    encoders_directional: bool,
 }
 
 impl Default for SensorState {
     fn default() -> SensorState {
+        SensorState::new(&Config::default())
+    }
+}
+
+impl SensorState {
+    pub fn new(config: &Config) -> SensorState {
         SensorState {
-            time: SystemTime::now(),
+            time: Instant::now(),
             duration: Duration::default(),
             raw_state: RawSensorState::default(),
             roll: 0.0,
             yaw: 0.0,
             pitch: 0.0,
             speed: 0.0,
-            sonar: (0.0, SystemTime::now()),
+            start_yaw: None,
+            pose: Pose::default(),
+            encoders: None,
+            sonar: 0.0,
+            sonar_time: Instant::now(),
             target_time: None,
             target_angle: None,
+            loop_stats: LoopStats::default(),
+            sonar_too_close: config.sonar.too_close,
+            pitch_too_neg: config.safety.pitch_too_neg,
+            max_speed: config.speed.max_speed,
+            encoders_directional: config.encoders.quadrature(),
         }
     }
-}
 
-impl SensorState {
     /// Returns Ok when no issue, Err when sonar is too close
-    pub fn update(&mut self, new_state: RawSensorState, speed: f32) -> Option<RTEvent> {
+    pub fn update(&mut self, new_state: RawSensorState, power: f32) -> Option<RTEvent> {
         //TODO do processing on state
         //TODO consider rolling average for most values.
-        let dt = new_state.time.duration_since(self.raw_state.time)
-            .unwrap_or(Duration::new(0, 16666667));
-        let angles = new_state.orientation.clone();
+        //the first reading has nothing to measure from, this also keeps
+        //replays of a recording from depending on when they were started
+        let dt = if self.start_yaw.is_none() || new_state.time < self.raw_state.time {
+            Duration::default()
+        } else {
+            new_state.time - self.raw_state.time
+        };
+        let angles = new_state.orientation;
         self.yaw = angles.x;
         self.pitch = angles.z;
         self.roll = angles.y;
         self.duration = dt;
-        self.time = new_state.time.clone();
+        self.time = new_state.time;
         self.raw_state = new_state;
-        self.speed = speed;
+        self.speed = power;
+        self.update_pose();
 
-        if self.pitch < PITCH_TOO_NEG {
+        if self.pitch < self.pitch_too_neg {
             return Some(RTEvent::SteepIncline);
         }
 
@@ -84,6 +152,27 @@ impl SensorState {
         None
     }
 
+    fn update_pose(&mut self) {
+        let start_yaw = *self.start_yaw.get_or_insert(self.yaw);
+        let heading = wrap_angle(self.yaw - start_yaw);
+        let dt = self.duration.as_fractional_secs() as f32;
+        if dt > MAX_POSE_DT {
+            //too long since the last reading to say anything useful
+            self.pose.heading = heading;
+            return;
+        }
+        //the encoders know the track speed far better than the accelerometer
+        match self.encoder_velocity() {
+            Some(velocity) => advance(&mut self.pose, dt, heading, velocity),
+            None => {
+                //The BNO055 is mounted with x pointing out the front of the tank
+                let forward_accel = self.raw_state.linear_accel.x;
+                let power_velocity = self.speed * self.max_speed;
+                integrate(&mut self.pose, dt, heading, forward_accel, power_velocity);
+            },
+        }
+    }
+
     pub fn pitch(&self) -> f32 {
         self.pitch
     }
@@ -100,7 +189,7 @@ impl SensorState {
         &self.duration
     }
 
-    pub fn time(&self) -> &SystemTime {
+    pub fn time(&self) -> &Instant {
         &self.time
     }
 
@@ -108,11 +197,16 @@ impl SensorState {
         self.speed
     }
 
+    /// Estimated position and heading relative to the start point
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
     pub fn clear_target_time(&mut self) {
         self.target_time = None;
     }
 
-    pub fn set_target_time(&mut self, time: SystemTime) {
+    pub fn set_target_time(&mut self, time: Instant) {
         self.target_time = Some(time);
     }
 
@@ -124,11 +218,11 @@ impl SensorState {
         self.target_angle = Some(radians);
     }
 
-    pub fn set_sonar(&mut self, sonar: (f32, SystemTime)) -> Option<RTEvent> {
-        //self.sonar.0 = self.sonar.0 + sonar.0 / SONAR_SAMPLES - self.sonar.0 / SONAR_SAMPLES;
-        self.sonar.0 = (self.sonar.0 + sonar.0) / 2.0;
-        self.sonar.1 = sonar.1;
-        if self.sonar.0 < SONAR_TOO_CLOSE {
+    pub fn set_sonar(&mut self, sonar: (f32, Instant)) -> Option<RTEvent> {
+        //self.sonar = self.sonar + sonar.0 / SONAR_SAMPLES - self.sonar / SONAR_SAMPLES;
+        self.sonar = (self.sonar + sonar.0) / 2.0;
+        self.sonar_time = sonar.1;
+        if self.sonar < self.sonar_too_close {
             Some(RTEvent::SonarProximity)
         } else {
             None
@@ -136,7 +230,39 @@ impl SensorState {
     }
 
     pub fn sonar(&self) -> f32 {
-        self.sonar.0
+        self.sonar
+    }
+
+    pub fn set_loop_stats(&mut self, stats: LoopStats) {
+        self.loop_stats = stats;
+    }
+
+    /// How steady the real time loop was running, as of its last report
+    pub fn loop_stats(&self) -> LoopStats {
+        self.loop_stats
+    }
+
+    /// Single channel encoder speeds are given the direction of the drive power
+    pub fn set_encoders(&mut self, mut encoders: EncoderState) {
+        if !self.encoders_directional && self.speed < 0.0 {
+            encoders.left_velocity = -encoders.left_velocity;
+            encoders.right_velocity = -encoders.right_velocity;
+        }
+        self.encoders = Some(encoders);
+    }
+
+    /// Latest reading of the track encoders, None if there aren't any
+    pub fn encoders(&self) -> Option<EncoderState> {
+        self.encoders
+    }
+
+    /// Forward speed of the tank from the encoders, in m/s.
+    /// None without encoders, or if their last reading is too old to trust.
+    pub fn encoder_velocity(&self) -> Option<f32> {
+        let time = self.time;
+        self.encoders.as_ref()
+            .filter(|e| time < e.time || (time - e.time).as_fractional_secs() as f32 <= MAX_POSE_DT)
+            .map(|e| (e.left_velocity + e.right_velocity) / 2.0)
     }
 
     /// Returns the value from the gyro after conversion into deg/s
@@ -150,4 +276,30 @@ impl SensorState {
     pub fn accel(&self) -> Vec3 {
         self.raw_state.accel
     }
+
+    /// The latest reading from the IMU, as it came from the real time thread
+    pub fn raw(&self) -> &RawSensorState {
+        &self.raw_state
+    }
+
+    /// The state as sent to TCP clients
+    pub fn report(&self) -> SensorReport {
+        SensorReport {
+            duration: self.duration,
+            time: self.time,
+            raw_state: self.raw_state.clone(),
+            roll: self.roll,
+            yaw: self.yaw,
+            pitch: self.pitch,
+            speed: self.speed,
+            start_yaw: self.start_yaw,
+            pose: self.pose,
+            encoders: self.encoders,
+            sonar: self.sonar,
+            sonar_time: self.sonar_time,
+            target_time: self.target_time,
+            target_angle: self.target_angle,
+            loop_stats: self.loop_stats,
+        }
+    }
 }
\ No newline at end of file
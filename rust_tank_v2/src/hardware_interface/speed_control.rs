@@ -0,0 +1,213 @@
+use floating_duration::TimeAsFloat;
+
+use super::sensor_processing::SensorState;
+use super::clamp;
+use ::config::SpeedConfig;
+
+/// Closes the loop on forward speed.
+///
+/// The power that should give the target speed on flat ground is fed
+/// forward, and a PI term on the encoder speed makes up the difference
+/// on carpet or slopes. The integral is clamped, and held while the power
+/// is saturated, the same as the heading PID.
+///
+/// Without fresh encoder readings only the feed forward is used. The pose
+/// velocity can't stand in for them, as without encoders it is itself
+/// mostly worked out from the drive power.
+pub struct SpeedControl {
+    /// Target speed in m/s
+    target: f32,
+    /// Speed at full power on flat ground, in m/s
+    max_speed: f32,
+    k_porportional: f32,
+    k_integral: f32,
+    integral_limit: f32,
+    integral: f32,
+    /// Drive power, ranges from -1 to 1
+    power: f32,
+}
+
+impl SpeedControl {
+    pub fn new(config: &SpeedConfig) -> SpeedControl {
+        SpeedControl {
+            target: 0.0,
+            max_speed: config.max_speed,
+            k_porportional: config.kp,
+            k_integral: config.ki,
+            integral_limit: config.integral_limit,
+            integral: 0.0,
+            power: 0.0,
+        }
+    }
+
+    /// Sets the speed to aim for, in m/s.
+    /// Clamped to what the tank can do, stopping takes effect straight away.
+    pub fn set_target(&mut self, speed: f32) {
+        let speed = speed.max(-self.max_speed).min(self.max_speed);
+        if speed == 0.0 || speed.signum() != self.target.signum() {
+            self.integral = 0.0;
+        }
+        if speed == 0.0 {
+            self.power = 0.0;
+        }
+        self.target = speed;
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// The drive power needed to hold the target speed
+    pub fn power(&self) -> f32 {
+        self.power
+    }
+
+    pub fn update(&mut self, sensors: &SensorState) {
+        match sensors.encoder_velocity() {
+            Some(measured) => {
+                let dt = sensors.duration().as_fractional_secs() as f32;
+                self.step(dt, measured);
+            },
+            None => self.feed_forward(),
+        }
+    }
+
+    /// Sets the power that should give the target speed on flat ground,
+    /// forgetting any correction built up from measured speeds
+    fn feed_forward(&mut self) {
+        //the target is clamped to max_speed, so this is within -1 to 1
+        self.integral = 0.0;
+        self.power = self.target / self.max_speed;
+    }
+
+    /// Advances the controller by dt seconds, given the measured speed
+    fn step(&mut self, dt: f32, measured: f32) {
+        if self.target == 0.0 {
+            //don't fight the estimate drifting while stopped
+            self.power = 0.0;
+            return;
+        }
+        if dt.is_nan() || dt <= 0.0 {
+            return;
+        }
+        let error = self.target - measured;
+        let saturated = self.power.abs() >= 1.0;
+        if !saturated || error.signum() != self.power.signum() {
+            self.integral = clamp(self.integral + error * dt, self.integral_limit);
+        }
+        self.power = clamp(self.target / self.max_speed
+            + self.k_porportional * error
+            + self.k_integral * self.integral, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use super::super::{EncoderState, RawSensorState};
+
+    const DT: f32 = 1.0 / 60.0;
+
+    fn control() -> SpeedControl {
+        SpeedControl::new(&SpeedConfig::default())
+    }
+
+    #[test]
+    fn feeds_forward_target() {
+        let mut control = control();
+        control.set_target(0.25);
+        control.step(DT, 0.25);
+        assert!((control.power() - 0.25 / control.max_speed).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pushes_harder_when_slow() {
+        let mut control = control();
+        control.set_target(0.25);
+        control.step(DT, 0.25);
+        let flat = control.power();
+        for _ in 0..60 {
+            control.step(DT, 0.1);
+        }
+        assert!(control.power() > flat);
+        assert!(control.integral > 0.0);
+    }
+
+    #[test]
+    fn closes_the_loop_on_encoders() {
+        let mut control = control();
+        control.set_target(0.25);
+        let mut sensors = SensorState::default();
+        let start = Instant::now();
+        for i in 0..30 {
+            let time = start + Duration::from_millis(i * 17);
+            sensors.update(RawSensorState { time, ..RawSensorState::default() }, control.power());
+            sensors.set_encoders(EncoderState {
+                time, left_ticks: 0, right_ticks: 0, left_velocity: 0.05, right_velocity: 0.05,
+            });
+            control.update(&sensors);
+        }
+        assert!(control.power() > 0.25 / control.max_speed + 0.2);
+    }
+
+    #[test]
+    fn feeds_forward_without_encoders() {
+        let mut control = control();
+        control.set_target(0.25);
+        let mut sensors = SensorState::default();
+        let start = Instant::now();
+        for i in 0..30 {
+            let time = start + Duration::from_millis(i * 17);
+            sensors.update(RawSensorState { time, ..RawSensorState::default() }, control.power());
+            control.update(&sensors);
+        }
+        assert_eq!(control.power(), 0.25 / control.max_speed);
+        assert_eq!(control.integral, 0.0);
+    }
+
+    #[test]
+    fn target_is_clamped() {
+        let mut control = control();
+        control.set_target(10.0);
+        assert_eq!(control.target(), control.max_speed);
+        control.set_target(-10.0);
+        assert_eq!(control.target(), -control.max_speed);
+    }
+
+    #[test]
+    fn power_is_limited() {
+        let mut control = control();
+        control.set_target(control.max_speed);
+        for _ in 0..600 {
+            control.step(DT, 0.0);
+        }
+        assert_eq!(control.power(), 1.0);
+        assert!(control.integral <= control.integral_limit);
+    }
+
+    #[test]
+    fn stop_is_immediate() {
+        let mut control = control();
+        control.set_target(0.3);
+        for _ in 0..10 {
+            control.step(DT, 0.1);
+        }
+        control.set_target(0.0);
+        assert_eq!(control.power(), 0.0);
+        control.step(DT, 0.2);
+        assert_eq!(control.power(), 0.0);
+        assert_eq!(control.integral, 0.0);
+    }
+
+    #[test]
+    fn reversing_resets_integral() {
+        let mut control = control();
+        control.set_target(0.3);
+        for _ in 0..10 {
+            control.step(DT, 0.1);
+        }
+        control.set_target(-0.3);
+        assert_eq!(control.integral, 0.0);
+    }
+}
@@ -0,0 +1,49 @@
+//! Everything but the terminal UI, so other crates can run the tank in
+//! process. The TCP message types are in tank_protocol.
+extern crate pca9685;
+extern crate mpu6050;
+extern crate floating_duration;
+extern crate i2cdev;
+extern crate i2cdev_bno055;
+extern crate i2csensors;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate sysfs_gpio;
+extern crate toml;
+extern crate tank_protocol;
+
+pub mod hardware_interface;
+pub mod tcp_interface;
+pub mod mission;
+pub mod behavior;
+pub mod config;
+pub mod recorder;
+pub mod csv_log;
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects what gets written through any of its clones, so a test can
+    /// hand one to a writer and read back what it wrote
+    #[derive(Clone, Default)]
+    pub struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Shared {
+        pub fn contents(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
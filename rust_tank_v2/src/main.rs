@@ -1,19 +1,15 @@
-#![feature(duration_float)]
-extern crate pca9685;
-extern crate mpu6050;
-extern crate termion;
 extern crate floating_duration;
-extern crate i2cdev;
-extern crate i2cdev_bno055;
-extern crate i2csensors;
-extern crate serde;
-#[macro_use] extern crate serde_derive;
-extern crate serde_json;
-extern crate sysfs_gpio;
+extern crate rust_tank_v2;
+extern crate termion;
 
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
 use std::thread;
 use std::time::Duration;
 
+use floating_duration::TimeAsFloat;
 use termion::event::Key;
 
 use std::f32::consts::PI;
@@ -21,53 +17,139 @@ use std::f32::consts::PI;
 mod terminal;
 use terminal::{ InputError};
 
-mod hardware_interface;
-use hardware_interface::{RTHandle};
-mod tcp_interface;
-use tcp_interface::{TcpInterface, messages::Command};
-use hardware_interface::RTEvent;
-use std::time::SystemTime;
+use rust_tank_v2::hardware_interface::{RTHandle};
+use rust_tank_v2::hardware_interface::devices::{FakePwm, FakeImu, FakeSonar, Simulation, Room, TrackEncoders};
+use rust_tank_v2::tcp_interface::{TcpInterface, messages::{Command, Response}};
+use rust_tank_v2::mission::MissionExecutor;
+use rust_tank_v2::behavior;
+use rust_tank_v2::behavior::{BehaviorKind, DriveTarget};
+use rust_tank_v2::config;
+use rust_tank_v2::config::Config;
+use rust_tank_v2::hardware_interface::RTEvent;
+use rust_tank_v2::recorder;
+use rust_tank_v2::recorder::Recorder;
+use rust_tank_v2::csv_log::CsvLog;
 //Old modules below
 
 
 fn main() {
 
 
-    //initialize hardware
-    let mut hw_interface = RTHandle::initialize()
-        .expect("Failed to initialize HW interface");
-    let mut tcp_interface = TcpInterface::new("0.0.0.0:27272")
-        .expect("Failed to initialize TCP interface");
+    let args: Vec<String> = std::env::args().collect();
+
+    //look into a recording instead of driving
+    if let Some(i) = args.iter().position(|a| a == "--replay") {
+        let path = args.get(i + 1).expect("--replay needs a file path");
+        let result = File::open(path)
+            .and_then(|file| recorder::replay(BufReader::new(file), io::stdout()));
+        if let Err(e) = result {
+            eprintln!("Failed to replay {}: {}", path, e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    match run(&mut hw_interface, &mut tcp_interface) {
-        Err(e) => println!("{}", e),
-        _ => (),
+    let config_path = args.iter().position(|a| a == "--config")
+        .map(|i| args.get(i + 1).expect("--config needs a file path").as_str());
+    let mut config = match Config::load_or_default(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     };
 
+    //record to a new file in the given directory
+    let recorder = args.iter().position(|a| a == "--record").map(|i| {
+        let dir = args.get(i + 1).expect("--record needs a directory");
+        match Recorder::create(dir, &config) {
+            Ok((recorder, path)) => {
+                eprintln!("Recording to {}", path.display());
+                recorder
+            },
+            Err(e) => {
+                eprintln!("Failed to start recording in {}: {}", dir, e);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    //initialize hardware, or stand ins for it when not running on the tank
+    let mut hw_interface = if args.iter().any(|a| a == "--fake") {
+        RTHandle::with_devices(FakePwm::default(), FakeImu, FakeSonar::default(), None, &config)
+    } else if args.iter().any(|a| a == "--sim") {
+        let room = match args.iter().position(|a| a == "--room") {
+            Some(i) => {
+                let path = args.get(i + 1).expect("--room needs a file path");
+                Room::load(path).expect("Failed to load room")
+            },
+            None => Room::rectangle(4.0, 3.0),
+        };
+        let sim = Simulation::new(room, config.motors.clone());
+        let encoders = if config.encoders.enabled {
+            let encoders = sim.encoders(config.encoders.ticks_per_meter, config.encoders.quadrature());
+            Some(Box::new(encoders) as Box<dyn TrackEncoders>)
+        } else {
+            None
+        };
+        RTHandle::with_devices(sim.pwm(), sim.imu(), sim.sonar(), encoders, &config)
+    } else {
+        RTHandle::initialize(&config)
+    }.expect("Failed to initialize HW interface");
+    if let Some(recorder) = recorder {
+        hw_interface.record_to(recorder);
+    }
+    if let Some(i) = args.iter().position(|a| a == "--csv") {
+        let file = args.get(i + 1).expect("--csv needs a file name");
+        match CsvLog::create(&config.csv.dir, file, config.csv.columns.clone()) {
+            Ok(log) => hw_interface.start_csv(log),
+            Err(e) => eprintln!("Failed to start the CSV log {}: {}", file, e),
+        }
+    }
+    let mut tcp_interface = TcpInterface::new(config.tcp.bind.as_str())
+        .expect("Failed to initialize TCP interface");
+
+    //gains are only saved to a config file that was loaded
+    let config_path = config_path.or_else(|| {
+        if Path::new(config::DEFAULT_PATH).is_file() { Some(config::DEFAULT_PATH) } else { None }
+    });
+    if let Err(e) = run(&mut hw_interface, &mut tcp_interface, &mut config, config_path) {
+        println!("{}", e);
+    }
+
     hw_interface.close();
 }
 
-fn run(hw_interface: &mut RTHandle, tcp_interface: &mut TcpInterface) -> std::io::Result<()> {
+fn run(hw_interface: &mut RTHandle, tcp_interface: &mut TcpInterface,
+       config: &mut Config, config_path: Option<&str>) -> std::io::Result<()> {
     let (mut input, mut output) = terminal::new()?;
 
     output.draw_static()?;
 
     let mut speed = 0.0;
     let mut turn = 0.0;
-    let mut degrees = 0;
+    let degrees = 0;
+    let mut mission = MissionExecutor::new();
+    let mut behavior = behavior::create(BehaviorKind::Bounce);
+    //true while the tank is driving because the controlling TCP client told it to
+    let mut remote_drive = false;
+    //true while a behavior the TCP client picked is in charge, it can start driving by itself
+    let mut remote_behavior = false;
 
     loop {
         match input.next() {
             Ok(Some(k)) => match k {
                 Key::Char('x') => break,
                 Key::Char('w') => {
-                    speed += 0.25;
-                    if speed > 1.0 {speed = 1.0; }
+                    remote_drive = false;
+                    speed += config.speed.max_speed / 4.0;
+                    if speed > config.speed.max_speed {speed = config.speed.max_speed; }
                     hw_interface.set_drive(speed, turn);
                 },
                 Key::Char('s') => {
-                    speed -= 0.25;
-                    if speed < -1.0 {speed = -1.0; }
+                    remote_drive = false;
+                    speed -= config.speed.max_speed / 4.0;
+                    if speed < -config.speed.max_speed {speed = -config.speed.max_speed; }
                     hw_interface.set_drive(speed, turn);
                 },
                 Key::Char('d') => {
@@ -104,56 +186,163 @@ fn run(hw_interface: &mut RTHandle, tcp_interface: &mut TcpInterface) -> std::io
             Ok(None) => (),
             Err(InputError::Disconnected) => break,
         }
-        while let Some(c) = tcp_interface.next_command() {
+        while let Some((from, c)) = tcp_interface.next_command() {
+            hw_interface.record_command(from, &c);
             match c {
                 Command::StopNow => {
+                    remote_drive = false;
                     speed = 0.0;
                     hw_interface.set_drive(speed, turn);
+                    mission.abort("stopped", hw_interface, tcp_interface);
+                },
+                Command::Move { speed, target_yaw, end } => {
+                    remote_drive = true;
+                    mission.push(from, speed, target_yaw, end);
                 },
                 Command::GetSensorState => {
-                    tcp_interface.send_state(hw_interface.sensor_state());
+                    let state = hw_interface.sensor_state().report();
+                    tcp_interface.reply(from, Response::SensorState(Box::new(state)));
+                },
+                Command::SetBehavior(kind) => {
+                    remote_behavior = kind != BehaviorKind::Manual;
+                    remote_drive = remote_drive || remote_behavior;
+                    behavior = behavior::create(kind);
+                    if !mission.is_active() {
+                        hw_interface.sensor_state().clear_target_time();
+                        hw_interface.sensor_state().clear_target_angle();
+                        let drive = DriveTarget { speed, turn };
+                        if let Some(target) = behavior.start(hw_interface.sensor_state(), drive) {
+                            speed = target.speed;
+                            turn = target.turn;
+                            hw_interface.set_drive(speed, turn);
+                        }
+                    }
+                },
+                Command::GetBehavior => {
+                    tcp_interface.reply(from, Response::UserMsg(format!("Current behavior: {}", behavior.name())));
+                },
+                Command::GetPid => {
+                    tcp_interface.reply(from, Response::PidState(hw_interface.pid_telemetry()));
+                },
+                Command::SetPid { kp, ki, kd } => {
+                    hw_interface.set_pid_gains(kp, ki, kd);
+                },
+                Command::ResetPid => {
+                    hw_interface.reset_pid();
+                },
+                Command::SavePid => {
+                    let (kp, ki, kd) = hw_interface.pid_gains();
+                    config.pid.kp = kp;
+                    config.pid.ki = ki;
+                    config.pid.kd = kd;
+                    tcp_interface.reply(from, match config_path {
+                        Some(path) => match config.save_pid_gains(path) {
+                            Ok(()) => Response::UserMsg(format!("Saved PID gains to {}", path)),
+                            Err(e) => Response::BadCommand(format!("Failed to save PID gains to {}: {}", path, e)),
+                        },
+                        None => Response::BadCommand(String::from("No config file to save the PID gains to, \
+                                                                   start the tank with --config <path>")),
+                    });
+                },
+                Command::StartCsv { file, columns } => {
+                    let columns = columns.unwrap_or_else(|| config.csv.columns.clone());
+                    tcp_interface.reply(from, match CsvLog::create(&config.csv.dir, &file, columns) {
+                        Ok(log) => {
+                            hw_interface.start_csv(log);
+                            Response::UserMsg(format!("Writing CSV to {} in {}", file, config.csv.dir))
+                        },
+                        Err(e) => Response::BadCommand(format!("Failed to start the CSV log {}: {}", file, e)),
+                    });
+                },
+                Command::StopCsv => {
+                    let msg = if hw_interface.stop_csv() { "Stopped the CSV log" } else { "No CSV log to stop" };
+                    tcp_interface.reply(from, Response::UserMsg(String::from(msg)));
+                },
+            }
+        }
+
+        mission.update(hw_interface, tcp_interface);
+        //the mission may have changed the drive
+        let (s, t) = hw_interface.drive_target();
+        speed = s;
+        turn = t;
+
+        let remote_motion = speed != 0.0 || remote_behavior;
+        if remote_drive && remote_motion && config.safety.heartbeat_timeout > 0.0 {
+            let quiet = tcp_interface.time_since_heard()
+                .map(|t| t.as_fractional_secs() > config.safety.heartbeat_timeout as f64)
+                .unwrap_or(true);
+            if quiet {
+                eprintln!("Lost the controlling TCP client, stopping");
+                if mission.is_active() {
+                    mission.abort("no heartbeat from client", hw_interface, tcp_interface);
+                } else {
+                    tcp_interface.send(Response::UserMsg(String::from("Stopped, no heartbeat from client")));
+                }
+                if remote_behavior {
+                    //or it would just drive off again
+                    behavior = behavior::create(BehaviorKind::Manual);
+                    remote_behavior = false;
                 }
-                c => eprintln!("Unimplemented command: {:?}", c),
+                remote_drive = false;
+                speed = 0.0;
+                hw_interface.set_drive(speed, turn);
             }
         }
 
         for event in hw_interface.update(tcp_interface.auto_send_state(), tcp_interface) {
-            match event {
-                RTEvent::SonarProximity => {
-                    if speed > 0.0 {
-                        //TODO remove hacky autopilot
-                        speed = -0.5;
-                        hw_interface.set_drive(speed, turn);
-                        hw_interface.sensor_state().set_target_time(SystemTime::now() + Duration::from_millis(750));
-                        eprintln!("Detected nearby object");
+            if mission.handle_event(&event, hw_interface, tcp_interface) {
+                let (s, t) = hw_interface.drive_target();
+                speed = s;
+                turn = t;
+                continue;
+            }
+            let abort_reason = match event {
+                RTEvent::Health(device, ref health) => {
+                    //printed under the display, stderr would draw over it
+                    output.print_error(format!("{:?}: {}", device, health))?;
+                    tcp_interface.send(Response::DeviceHealth { device, health: health.to_string() });
+                    if hw_interface.failed().is_some() {
+                        //the motors are already off, make sure nothing tries to drive them
+                        mission.abort("device failed", hw_interface, tcp_interface);
+                        remote_drive = false;
+                        speed = 0.0;
                     }
+                    continue;
+                },
+                RTEvent::WatchdogTripped => {
+                    eprintln!("Motor watchdog tripped, the main loop stalled");
+                    tcp_interface.send(Response::UserMsg(String::from("Motor watchdog tripped")));
+                    continue;
                 },
-                RTEvent::SteepIncline => {
+                RTEvent::SonarProximity => Some("obstacle ahead"),
+                RTEvent::SteepIncline => Some("too steep"),
+                _ => None,
+            };
+            if mission.is_active() {
+                if let Some(reason) = abort_reason {
                     if speed > 0.0 {
-                        //TODO remove hacky autopilot
-                        speed = -0.5;
+                        //don't leave the tank driving into whatever stopped the mission
+                        mission.abort(reason, hw_interface, tcp_interface);
+                        speed = 0.0;
                         hw_interface.set_drive(speed, turn);
-                        hw_interface.sensor_state().set_target_time(SystemTime::now() + Duration::from_millis(750));
-                        eprintln!("Too steep a climb");
                     }
-                },
-                RTEvent::Err(err) => {
-                    eprintln!("{}", err);
-                },
-                RTEvent::TargetAngleReached => {
-                    //TODO tell tcp that angle was reached
-                    speed = 0.75;
-                    hw_interface.set_drive(speed, turn);
-                },
-                RTEvent::TargetTimeReached => {
-                    //TODO tell tcp that time was reached
-                    //TODO remove hacky code
-                    speed = 0.0;
-                    turn = (turn + PI * 55.0 / 180.0);
-                    if turn > PI * 2.0 {turn -= PI * 2.0; }
-                    hw_interface.set_drive(speed, turn);
-                    hw_interface.sensor_state().set_target_angle(turn);
                 }
+                continue;
+            }
+            let drive = DriveTarget { speed, turn };
+            if let Some(target) = behavior.handle_event(&event, hw_interface.sensor_state(), drive) {
+                speed = target.speed;
+                turn = target.turn;
+                hw_interface.set_drive(speed, turn);
+            }
+        }
+        if !mission.is_active() {
+            let drive = DriveTarget { speed, turn };
+            if let Some(target) = behavior.update(hw_interface.sensor_state(), drive) {
+                speed = target.speed;
+                turn = target.turn;
+                hw_interface.set_drive(speed, turn);
             }
         }
         output.draw_motors(speed, turn, degrees)?;
@@ -0,0 +1,263 @@
+//! Runs queued Move commands one after another.
+//! A step with an end condition runs until the real time thread reports
+//! that condition was reached, a step without one just sets the drive and
+//! finishes straight away.
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::time::Instant;
+
+use hardware_interface::{RTHandle, RTEvent};
+use tcp_interface::{TcpInterface, Requester, ClientId};
+use tcp_interface::messages::{EndCondition, Response};
+
+/// A single Move of the mission
+struct Step {
+    number: usize,
+    /// Who asked for the move, they are told about it with their request id
+    from: Requester,
+    speed: f32,
+    /// In radians, None keeps the current heading
+    target_yaw: Option<f32>,
+    end: Option<EndCondition>,
+}
+
+pub struct MissionExecutor {
+    queue: VecDeque<Step>,
+    current: Option<Step>,
+    next_number: usize,
+}
+
+impl Default for MissionExecutor {
+    fn default() -> MissionExecutor {
+        MissionExecutor::new()
+    }
+}
+
+impl MissionExecutor {
+    pub fn new() -> MissionExecutor {
+        MissionExecutor {
+            queue: VecDeque::new(),
+            current: None,
+            next_number: 1,
+        }
+    }
+
+    /// True while there is a step running or waiting to run
+    pub fn is_active(&self) -> bool {
+        self.current.is_some() || !self.queue.is_empty()
+    }
+
+    /// Adds a move to the end of the mission. target_yaw is in degrees.
+    pub fn push(&mut self, from: Requester, speed: f64, target_yaw: Option<f64>, end: Option<EndCondition>) {
+        if !self.is_active() {
+            self.next_number = 1;
+        }
+        let target_yaw = target_yaw.map(|yaw| {
+            let yaw = (yaw as f32).to_radians() % (2.0 * PI);
+            if yaw < 0.0 { yaw + 2.0 * PI } else { yaw }
+        });
+        self.queue.push_back(Step {
+            number: self.next_number,
+            from,
+            speed: speed as f32,
+            target_yaw,
+            end,
+        });
+        self.next_number += 1;
+    }
+
+    /// Starts the next step, if nothing is running
+    pub fn update(&mut self, hw_interface: &mut RTHandle, tcp_interface: &mut TcpInterface) {
+        while self.current.is_none() {
+            match self.queue.pop_front() {
+                Some(step) => self.start(step, hw_interface, tcp_interface),
+                None => return,
+            }
+        }
+    }
+
+    /// Finishes the current step if the event is its end condition.
+    /// Returns true when the event was used up by the mission.
+    pub fn handle_event(&mut self, event: &RTEvent,
+                        hw_interface: &mut RTHandle, tcp_interface: &mut TcpInterface) -> bool {
+        match *event {
+            RTEvent::TargetTimeReached | RTEvent::TargetAngleReached => {
+                match self.current.take() {
+                    Some(step) => {
+                        self.finish(step, hw_interface, tcp_interface);
+                        self.update(hw_interface, tcp_interface);
+                        true
+                    },
+                    None => false,
+                }
+            },
+            _ => false,
+        }
+    }
+
+    /// Drops the running step and everything queued after it.
+    /// Does not change the drive, that is up to the caller.
+    pub fn abort(&mut self, reason: &str, hw_interface: &mut RTHandle, tcp_interface: &mut TcpInterface) {
+        if !self.is_active() {
+            return;
+        }
+        if self.current.is_some() {
+            hw_interface.sensor_state().clear_target_time();
+            hw_interface.sensor_state().clear_target_angle();
+        }
+        //let anyone waiting on a particular step know it isn't coming,
+        //and everyone else that the mission is over
+        let waiting: Vec<Requester> = self.current.take().into_iter().chain(self.queue.drain(..))
+            .map(|step| step.from)
+            .filter(|from| from.id.is_some())
+            .collect();
+        let skip: Vec<ClientId> = waiting.iter().map(|from| from.client).collect();
+        tcp_interface.send_except(&skip, Response::MissionAborted(String::from(reason)));
+        for from in waiting {
+            tcp_interface.reply(from, Response::MissionAborted(String::from(reason)));
+        }
+    }
+
+    fn start(&mut self, step: Step, hw_interface: &mut RTHandle, tcp_interface: &mut TcpInterface) {
+        let turn = step.target_yaw.unwrap_or(hw_interface.drive_target().1);
+        let end_time = match step.end {
+            Some(EndCondition::Time(duration)) => match Instant::now().checked_add(duration) {
+                Some(time) => Some(time),
+                None => {
+                    //a step that can't end is dropped along with the rest, rather than panicking
+                    self.current = Some(step);
+                    self.abort("the step's time is too long", hw_interface, tcp_interface);
+                    hw_interface.set_drive(0.0, turn);
+                    return;
+                },
+            },
+            _ => None,
+        };
+        hw_interface.set_drive(step.speed, turn);
+        hw_interface.sensor_state().clear_target_time();
+        hw_interface.sensor_state().clear_target_angle();
+        tcp_interface.announce(step.from, Response::StepStarted { step: step.number, remaining: self.queue.len() });
+
+        match step.end {
+            Some(EndCondition::Time(_)) => {
+                if let Some(time) = end_time {
+                    hw_interface.sensor_state().set_target_time(time);
+                }
+                self.current = Some(step);
+            },
+            Some(EndCondition::AngleReached) => {
+                hw_interface.sensor_state().set_target_angle(turn);
+                self.current = Some(step);
+            },
+            //keeps going until the next step, or until told otherwise
+            None => self.finish(step, hw_interface, tcp_interface),
+        }
+    }
+
+    fn finish(&mut self, step: Step, hw_interface: &mut RTHandle, tcp_interface: &mut TcpInterface) {
+        tcp_interface.announce(step.from, Response::StepComplete { step: step.number, remaining: self.queue.len() });
+        if self.queue.is_empty() {
+            if step.end.is_some() {
+                let turn = hw_interface.drive_target().1;
+                hw_interface.set_drive(0.0, turn);
+            }
+            tcp_interface.send(Response::MissionComplete);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use config::Config;
+    use hardware_interface::devices::{FakePwm, FakeImu, FakeSonar};
+
+    const FROM: Requester = Requester { client: 1, id: None };
+
+    fn tank() -> (RTHandle, TcpInterface) {
+        let hw = RTHandle::with_devices(FakePwm::default(), FakeImu, FakeSonar::default(), None, &Config::default())
+            .unwrap();
+        let tcp = TcpInterface::new("127.0.0.1:0").unwrap();
+        (hw, tcp)
+    }
+
+    fn timed() -> Option<EndCondition> {
+        Some(EndCondition::Time(Duration::from_secs(60)))
+    }
+
+    #[test]
+    fn steps_run_in_order() {
+        let (mut hw, mut tcp) = tank();
+        let mut mission = MissionExecutor::default();
+        mission.push(FROM, 0.1, None, timed());
+        mission.push(FROM, 0.2, Some(90.0), Some(EndCondition::AngleReached));
+        mission.push(FROM, 0.3, None, timed());
+
+        mission.update(&mut hw, &mut tcp);
+        assert_eq!(hw.drive_target().0, 0.1);
+        //events that aren't an end condition don't move it on
+        assert!(!mission.handle_event(&RTEvent::SonarProximity, &mut hw, &mut tcp));
+        assert_eq!(hw.drive_target().0, 0.1);
+
+        assert!(mission.handle_event(&RTEvent::TargetTimeReached, &mut hw, &mut tcp));
+        assert_eq!(hw.drive_target(), (0.2, PI / 2.0));
+        assert!(mission.handle_event(&RTEvent::TargetAngleReached, &mut hw, &mut tcp));
+        //keeps the heading of the step before
+        assert_eq!(hw.drive_target(), (0.3, PI / 2.0));
+        assert!(mission.handle_event(&RTEvent::TargetTimeReached, &mut hw, &mut tcp));
+        //the last timed step stops the tank
+        assert_eq!(hw.drive_target().0, 0.0);
+        assert!(!mission.is_active());
+        hw.close();
+    }
+
+    #[test]
+    fn steps_without_an_end_finish_straight_away() {
+        let (mut hw, mut tcp) = tank();
+        let mut mission = MissionExecutor::new();
+        mission.push(FROM, 0.1, None, None);
+        mission.push(FROM, 0.2, Some(-90.0), None);
+        assert!(mission.is_active());
+
+        mission.update(&mut hw, &mut tcp);
+        assert!(!mission.is_active());
+        //and the last one keeps driving
+        assert_eq!(hw.drive_target(), (0.2, 1.5 * PI));
+        hw.close();
+    }
+
+    #[test]
+    fn abort_drops_the_queue() {
+        let (mut hw, mut tcp) = tank();
+        let mut mission = MissionExecutor::new();
+        for _ in 0..3 {
+            mission.push(FROM, 0.2, None, timed());
+        }
+        mission.update(&mut hw, &mut tcp);
+        mission.abort("stopped", &mut hw, &mut tcp);
+        assert!(!mission.is_active());
+        //nothing is left to finish or start
+        assert!(!mission.handle_event(&RTEvent::TargetTimeReached, &mut hw, &mut tcp));
+        mission.update(&mut hw, &mut tcp);
+        assert!(!mission.is_active());
+
+        //a new mission numbers its steps from the start again
+        mission.push(FROM, 0.1, None, timed());
+        assert_eq!(mission.queue[0].number, 1);
+        hw.close();
+    }
+
+    #[test]
+    fn endless_times_abort() {
+        let (mut hw, mut tcp) = tank();
+        let mut mission = MissionExecutor::new();
+        mission.push(FROM, 0.2, None, Some(EndCondition::Time(Duration::new(u64::MAX, 0))));
+        mission.push(FROM, 0.2, None, timed());
+        mission.update(&mut hw, &mut tcp);
+        assert!(!mission.is_active());
+        assert_eq!(hw.drive_target().0, 0.0);
+        hw.close();
+    }
+}
@@ -0,0 +1,293 @@
+//! Flight recorder: logs everything going in and out of the drive control,
+//! so odd behavior can be looked into, and replayed, off the tank.
+//!
+//! A recording is a json object per line. The first line holds the config
+//! and wall clock time the recording started at, every line after that is
+//! stamped with the seconds since then.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use floating_duration::TimeAsFloat;
+use serde_json;
+
+use hardware_interface::{Controller, RTCommand, RTEvent, RawSensorState, EncoderState, Device};
+use hardware_interface::timestamp;
+use tcp_interface::Requester;
+use tcp_interface::messages::Command;
+use config::Config;
+
+/// Something that happened, as recorded
+#[derive(Serialize, Deserialize)]
+pub enum Entry {
+    /// Always the first entry
+    Start { time: SystemTime, config: Config },
+    Imu(RawSensorState),
+    /// Sonar distance in cm
+    Sonar { cm: f32, #[serde(with = "timestamp")] time: Instant },
+    Encoders(EncoderState),
+    /// A drive target was set, speed in m/s and turn in radians
+    Drive { speed: f32, turn: f32 },
+    /// The motors were stopped
+    Stop,
+    PidGains { kp: f32, ki: f32, kd: f32 },
+    ResetPid,
+    /// Commands sent to drive the motors after an update
+    Motors(Vec<RTCommand>),
+    /// Any other command sent to the real time thread
+    Command(RTCommand),
+    Event(RecordedEvent),
+    /// A command received over TCP
+    Tcp { from: Requester, command: Command },
+}
+
+/// An RTEvent, with the device errors written out since they can't be stored
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum RecordedEvent {
+    SonarProximity,
+    SteepIncline,
+    TargetAngleReached,
+    TargetTimeReached,
+    WatchdogTripped,
+    Health { device: Device, health: String },
+}
+
+impl From<&RTEvent> for RecordedEvent {
+    fn from(event: &RTEvent) -> RecordedEvent {
+        match *event {
+            RTEvent::SonarProximity => RecordedEvent::SonarProximity,
+            RTEvent::SteepIncline => RecordedEvent::SteepIncline,
+            RTEvent::TargetAngleReached => RecordedEvent::TargetAngleReached,
+            RTEvent::TargetTimeReached => RecordedEvent::TargetTimeReached,
+            RTEvent::WatchdogTripped => RecordedEvent::WatchdogTripped,
+            RTEvent::Health(device, ref health) =>
+                RecordedEvent::Health { device, health: health.to_string() },
+        }
+    }
+}
+
+/// A line of the recording
+#[derive(Serialize, Deserialize)]
+struct Record {
+    /// Seconds since the recording started
+    t: f64,
+    entry: Entry,
+}
+
+pub struct Recorder {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Starts a recording in a new file in dir, named after the time
+    pub fn create<P: AsRef<Path>>(dir: P, config: &Config) -> io::Result<(Recorder, PathBuf)> {
+        fs::create_dir_all(&dir)?;
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let path = dir.as_ref().join(format!("flight-{}.jsonl", secs));
+        let file = BufWriter::new(File::create(&path)?);
+        Ok((Recorder::new(Box::new(file), config)?, path))
+    }
+
+    /// Starts a recording written to out
+    pub fn new(out: Box<dyn Write + Send>, config: &Config) -> io::Result<Recorder> {
+        let mut recorder = Recorder { out, start: Instant::now() };
+        recorder.record(Entry::Start { time: SystemTime::now(), config: config.clone() })?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, entry: Entry) -> io::Result<()> {
+        let record = Record { t: self.start.elapsed().as_fractional_secs(), entry };
+        serde_json::to_writer(&mut self.out, &record)?;
+        self.out.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// How a replay went
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub imu_readings: usize,
+    /// Times the motors were driven
+    pub motor_updates: usize,
+    /// Times the replayed motor commands differed from the recorded ones
+    pub mismatches: usize,
+}
+
+/// Feeds a recording back through the sensor processing and drive control,
+/// writing what happened along the way to out.
+///
+/// The drive targets are replayed as recorded, missions and behaviors
+/// aren't run again. After each update the motor commands are checked
+/// against the recorded ones, any difference means the replay has stopped
+/// matching what the tank did.
+pub fn replay<R: BufRead, W: Write>(recording: R, mut out: W) -> io::Result<ReplaySummary> {
+    let mut lines = recording.lines();
+    let first = lines.next()
+        .ok_or_else(|| invalid_data("the recording is empty"))??;
+    let mut controller = match parse(&first)?.entry {
+        Entry::Start { time, config } => {
+            let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            writeln!(out, "Recording started {} seconds after the unix epoch", secs)?;
+            Controller::new(&config)
+        },
+        _ => return Err(invalid_data("the recording doesn't start with the config")),
+    };
+
+    let mut summary = ReplaySummary::default();
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Record { t, entry } = parse(&line)?;
+        let event = match entry {
+            Entry::Start { .. } => return Err(invalid_data("a second start in the recording")),
+            Entry::Imu(state) => {
+                summary.imu_readings += 1;
+                controller.update_imu(state)
+            },
+            Entry::Sonar { cm, time } => controller.update_sonar(cm, time),
+            Entry::Encoders(state) => {
+                controller.update_encoders(state);
+                None
+            },
+            Entry::Drive { speed, turn } => {
+                writeln!(out, "{:10.3}  drive {:.2} m/s, heading {:.2} rad", t, speed, turn)?;
+                controller.set_drive(speed, turn);
+                None
+            },
+            Entry::Stop => {
+                writeln!(out, "{:10.3}  stop", t)?;
+                controller.stop();
+                None
+            },
+            Entry::PidGains { kp, ki, kd } => {
+                writeln!(out, "{:10.3}  pid gains {} {} {}", t, kp, ki, kd)?;
+                controller.set_pid_gains(kp, ki, kd);
+                None
+            },
+            Entry::ResetPid => {
+                writeln!(out, "{:10.3}  pid reset", t)?;
+                controller.reset_pid();
+                None
+            },
+            Entry::Motors(recorded) => {
+                summary.motor_updates += 1;
+                let replayed = controller.pwm_commands();
+                if replayed != recorded {
+                    summary.mismatches += 1;
+                    writeln!(out, "{:10.3}  motors differ, recorded {:?} replayed {:?}", t, recorded, replayed)?;
+                }
+                None
+            },
+            Entry::Command(command) => {
+                writeln!(out, "{:10.3}  command {:?}", t, command)?;
+                None
+            },
+            Entry::Event(event) => {
+                writeln!(out, "{:10.3}  recorded event {:?}", t, event)?;
+                None
+            },
+            Entry::Tcp { from, command } => {
+                writeln!(out, "{:10.3}  tcp client {}: {:?}", t, from.client, command)?;
+                None
+            },
+        };
+        if let Some(event) = event {
+            writeln!(out, "{:10.3}  replayed event {:?}", t, RecordedEvent::from(&event))?;
+        }
+    }
+
+    let pose = controller.sensor_state().pose();
+    writeln!(out, "Replayed {} IMU readings, {} of {} motor updates differed. Ended at ({:.2}, {:.2}) m",
+             summary.imu_readings, summary.mismatches, summary.motor_updates, pose.x, pose.y)?;
+    Ok(summary)
+}
+
+fn parse(line: &str) -> io::Result<Record> {
+    serde_json::from_str(line).map_err(|e| invalid_data(&e.to_string()))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Duration;
+    use hardware_interface::Vec3;
+    use tests::Shared;
+
+    /// Drives a controller through a turn, recording as the tank would
+    fn record_turn(config: &Config) -> Vec<u8> {
+        let buffer = Shared::default();
+        let mut recorder = Recorder::new(Box::new(buffer.clone()), config).unwrap();
+        let mut controller = Controller::new(config);
+
+        controller.set_drive(0.3, 1.0);
+        recorder.record(Entry::Drive { speed: 0.3, turn: 1.0 }).unwrap();
+        let start = Instant::now();
+        for i in 0..60 {
+            let state = RawSensorState {
+                time: start + Duration::from_millis(i * 16),
+                orientation: Vec3 { x: i as f32 * 0.01, y: 0.0, z: 0.0 },
+                linear_accel: Vec3 { x: 0.1, y: 0.0, z: 0.0 },
+                ..RawSensorState::default()
+            };
+            recorder.record(Entry::Imu(state.clone())).unwrap();
+            controller.update_imu(state);
+            if i % 10 == 0 {
+                let time = start + Duration::from_millis(i * 16);
+                recorder.record(Entry::Sonar { cm: 100.0 - i as f32, time }).unwrap();
+                controller.update_sonar(100.0 - i as f32, time);
+            }
+            recorder.record(Entry::Motors(controller.pwm_commands())).unwrap();
+        }
+        buffer.contents()
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let config = Config::default();
+        let recording = record_turn(&config);
+        let mut out = vec![];
+        let summary = replay(Cursor::new(recording), &mut out).unwrap();
+        assert_eq!(summary.imu_readings, 60);
+        assert_eq!(summary.motor_updates, 60);
+        assert_eq!(summary.mismatches, 0, "{}", String::from_utf8_lossy(&out));
+    }
+
+    #[test]
+    fn replay_finds_differences() {
+        //replaying with different gains than the tank had should show up
+        let mut config = Config::default();
+        let recording = record_turn(&config);
+        config.pid.kp *= 2.0;
+        let mut lines: Vec<String> = String::from_utf8(recording).unwrap()
+            .lines().map(String::from).collect();
+        let mut start: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        start["entry"]["Start"]["config"] = serde_json::to_value(&config).unwrap();
+        lines[0] = start.to_string();
+        let summary = replay(Cursor::new(lines.join("\n")), io::sink()).unwrap();
+        assert!(summary.mismatches > 0);
+    }
+
+    #[test]
+    fn rejects_recordings_without_start() {
+        let recording = "{\"t\":0.0,\"entry\":\"Stop\"}\n";
+        assert!(replay(Cursor::new(recording), io::sink()).is_err());
+        assert!(replay(Cursor::new(""), io::sink()).is_err());
+    }
+}
@@ -1,15 +1,16 @@
-pub mod messages;
+pub use tank_protocol::messages;
 
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, TryRecvError, Sender};
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
-use std::thread::{JoinHandle};
-use std::collections::VecDeque;
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::io;
 use std::io::{BufReader, BufRead, Write};
+use std::time::{Duration, Instant};
 
-use ::hardware_interface::SensorState;
+use ::hardware_interface::{SensorState, PidTelemetry};
+use ::csv_log::parse_columns;
 
 use std::sync::{Arc, Mutex};
 
@@ -17,197 +18,596 @@ use serde_json;
 
 use self::messages::*;
 
-/// Various state flags used by the tcpInterface
-struct TcpInterfaceState {
+/// Identifies a connected client
+pub type ClientId = usize;
+
+/// Who sent a command, so replies can find their way back
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Requester {
+    pub client: ClientId,
+    /// Id of the json request, echoed in the replies
+    pub id: Option<u64>,
+}
+
+/// Various settings each client picks for itself
+struct ClientSettings {
     human_readable: bool,
     auto_send_state: bool,
+    auto_send_pid: bool,
 }
-impl Default for TcpInterfaceState {
-    fn default() -> TcpInterfaceState {
-        TcpInterfaceState {
+impl Default for ClientSettings {
+    fn default() -> ClientSettings {
+        ClientSettings {
             human_readable: true,
             auto_send_state: false,
+            auto_send_pid: false,
         }
     }
 }
 
+struct Client {
+    settings: ClientSettings,
+    /// Responses for the client's writer thread, with the id of the request they answer
+    tx: Sender<(Option<u64>, Response)>,
+}
+
+/// Everyone connected, and who is driving
+struct Clients {
+    clients: HashMap<ClientId, Client>,
+    next_id: ClientId,
+    /// The client allowed to drive the tank, the rest can only watch
+    controller: Option<ClientId>,
+    /// When the controlling client last sent anything
+    last_heard: Option<Instant>,
+}
+
+impl Clients {
+    fn settings(&mut self, client: ClientId) -> Option<&mut ClientSettings> {
+        self.clients.get_mut(&client).map(|c| &mut c.settings)
+    }
+    /// Sends a response to every client the filter picks, and forgets
+    /// clients whose writer has gone away
+    fn send_where<F: Fn(&ClientSettings) -> bool>(&mut self, response: &Response, filter: F) {
+        self.clients.retain(|_, client| {
+            !filter(&client.settings) || client.tx.send((None, response.clone())).is_ok()
+        });
+    }
+}
+
 pub struct TcpInterface {
-    state: Arc<Mutex<TcpInterfaceState>>,
-    command_queue: VecDeque<Command>,
-    rx: Receiver<Command>,
-    tx: Sender<Response>,
+    clients: Arc<Mutex<Clients>>,
+    command_queue: VecDeque<(Requester, Command)>,
+    rx: Receiver<(Requester, Command)>,
+    addr: SocketAddr,
 }
 
 impl TcpInterface {
     pub fn new<A: ToSocketAddrs>(addr: A) -> Result<TcpInterface, io::Error> {
         let listener = TcpListener::bind(addr)?;
-        let (tx, handler_rx) = mpsc::channel();
-        let handler_rx_loopback = tx.clone();
+        let addr = listener.local_addr()?;
         let (handler_tx, rx) = mpsc::channel();
-        let state = Arc::new(Mutex::new(TcpInterfaceState::default()));
-        let handler_state = Arc::clone(&state);
+        let clients = Arc::new(Mutex::new(Clients {
+            clients: HashMap::new(),
+            next_id: 1,
+            controller: None,
+            last_heard: None,
+        }));
+        let handler_clients = Arc::clone(&clients);
 
         //Start tcp thread
-        let join_handle = thread::spawn(move || tcp_handler(listener, handler_state, handler_rx, handler_rx_loopback, handler_tx));
+        thread::spawn(move || tcp_handler(listener, handler_clients, handler_tx));
 
         let command_queue = VecDeque::new();
-        return Ok(TcpInterface { state, command_queue, rx, tx})
+        Ok(TcpInterface { clients, command_queue, rx, addr })
     }
-    pub fn next_command(&mut self) -> Option<Command> {
-        while let Ok(c) = self.rx.try_recv() {
+    /// The next command from any client, along with who sent it
+    pub fn next_command(&mut self) -> Option<(Requester, Command)> {
+        while let Ok((from, c)) = self.rx.try_recv() {
             if let Command::StopNow = c {
-                return Some(Command::StopNow);
+                self.command_queue.clear();
+                return Some((from, Command::StopNow));
             }
-            self.command_queue.push_back(c);
+            self.command_queue.push_back((from, c));
         }
 
         self.command_queue.pop_front()
     }
+    /// Sends the sensor state to the clients that asked for it automatically
     pub fn send_state(&mut self, sensor_state: &SensorState) {
-        self.tx.send(Response::SensorState(sensor_state.clone()))
-            .expect("TCP send channel broken");
+        let response = Response::SensorState(Box::new(sensor_state.report()));
+        self.clients.lock().unwrap().send_where(&response, |s| s.auto_send_state);
+    }
+    /// Sends the PID terms to the clients that asked for them automatically
+    pub fn send_pid(&mut self, telemetry: PidTelemetry) {
+        let response = Response::PidState(telemetry);
+        self.clients.lock().unwrap().send_where(&response, |s| s.auto_send_pid);
+    }
+    /// Sends a response to every client
+    pub fn send(&mut self, response: Response) {
+        self.clients.lock().unwrap().send_where(&response, |_| true);
     }
+    /// Sends a response to every client, tagged with the request id
+    /// for the client whose command it answers
+    pub fn announce(&mut self, to: Requester, response: Response) {
+        self.clients.lock().unwrap().clients.retain(|&client, c| {
+            let id = if client == to.client { to.id } else { None };
+            c.tx.send((id, response.clone())).is_ok()
+        });
+    }
+    /// Sends a response to every client but the ones given, who are
+    /// answered separately
+    pub fn send_except(&mut self, skip: &[ClientId], response: Response) {
+        self.clients.lock().unwrap().clients.retain(|client, c| {
+            skip.contains(client) || c.tx.send((None, response.clone())).is_ok()
+        });
+    }
+    /// Answers a command, does nothing if the client has disconnected
+    pub fn reply(&mut self, to: Requester, response: Response) {
+        if let Some(c) = self.clients.lock().unwrap().clients.get(&to.client) {
+            let _ = c.tx.send((to.id, response));
+        }
+    }
+    /// True if any client wants the sensor state sent automatically
     pub fn auto_send_state(&self) -> bool {
-        self.state.lock().unwrap().auto_send_state
+        self.clients.lock().unwrap().clients.values().any(|c| c.settings.auto_send_state)
+    }
+    /// True if any client wants the PID terms sent automatically
+    pub fn auto_send_pid(&self) -> bool {
+        self.clients.lock().unwrap().clients.values().any(|c| c.settings.auto_send_pid)
+    }
+    /// The address being listened on, useful when bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// How long since the controlling client last sent anything,
+    /// None if no one is in control
+    pub fn time_since_heard(&self) -> Option<Duration> {
+        self.clients.lock().unwrap().last_heard.map(|t| t.elapsed())
     }
 }
 
 const HELP_PROMPT: &str = "\
 Available commands:
   help                       print this text
-  stopnow                    stop the tank immediately
+  stopnow                    stop the tank immediately, any client can do this
+  heartbeat                  let the tank know the client is still there
+  control                    take control of the tank, if no one else has it
+  release                    give up control of the tank
   sensornow                  send current sensor state
   humanreadable [true|false] set the response to be human readable
   autosensor [true|false]    set to auto send sensor state
+  move <speed> [yaw <deg>] [for <secs> | until angle]
+                             drive at speed (in m/s), optionally turning to
+                             yaw, until the time runs out or yaw is reached.
+                             moves are queued and run one after another
+  move {json}                same as above, e.g.
+                             {\"speed\": 0.5, \"target_yaw\": 90, \"end\": {\"Time\": 2}}
+  pid                        show the drive PID gains and terms
+  pid <kp> <ki> <kd>         set the drive PID gains
+  pid reset                  clear the drive PID integral
+  pid save                   save the drive PID gains to the config file
+  autopid [true|false]       set to auto send the drive PID terms
+  csv start <file> [columns] write a row of sensor and drive data each tick to
+                             a new file in the tank's CSV directory (csv.dir
+                             in the config), columns are any of time,
+                             orientation, gyro, accel, mag, temp, sonar,
+                             pid_output and motor_power, or those in the config
+  csv stop                   stop writing the CSV file
+  behavior [manual|bounce|wander|wallfollow]
+                             pick what the tank does on its own, or show
+                             the current behavior
+Only the controlling client can move the tank or change its settings.
+Requests can also be sent as a line of json, {\"id\": <number>, \"command\": <command>},
+where the command is a Command or SessionCommand, e.g.
+  {\"id\": 1, \"command\": {\"Move\": {\"speed\": 0.2, \"target_yaw\": null, \"end\": {\"Time\": 2}}}}
+  {\"id\": 2, \"command\": \"GetSensorState\"}
+  {\"id\": 3, \"command\": {\"AutoSendState\": true}}
+Replies to a request with an id carry the same id.
 ";
 const BAD_ARGUMENT_BOOL: &str = "Argument should be 'true' or 'false'";
+const BAD_ARGUMENT_PID: &str = "Arguments should be 'reset', 'save' or three gains '<kp> <ki> <kd>'";
+const BAD_ARGUMENT_BEHAVIOR: &str = "Argument should be 'manual', 'bounce', 'wander' or 'wallfollow'";
+const BAD_ARGUMENT_CSV: &str = "Arguments should be 'start <file> [columns]' or 'stop'";
+const NOT_IN_CONTROL: &str = "Another client has control, send 'control' once they release it";
 
 fn tcp_handler(listener: TcpListener,
-               interface_state: Arc<Mutex<TcpInterfaceState>>,
-               rx: Receiver<Response>,
-               rx_loopback: Sender<Response>,
-               tx: Sender<Command>) {
-    //pack our rx device into a Arc<Mutex>
-    let rx = Arc::new(Mutex::new(rx));
-
+               clients: Arc<Mutex<Clients>>,
+               tx: Sender<(Requester, Command)>) {
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                let mut reader = BufReader::new(stream.try_clone().unwrap());
-                let mut writer = stream;
+                let reader = match stream.try_clone() {
+                    Ok(s) => BufReader::new(s),
+                    Err(e) => {
+                        eprintln!("TCP error: {}", e);
+                        continue;
+                    },
+                };
+                let (client_tx, client_rx) = mpsc::channel();
+                let id = {
+                    let mut clients = clients.lock().unwrap();
+                    let id = clients.next_id;
+                    clients.next_id += 1;
+                    clients.clients.insert(id, Client { settings: ClientSettings::default(), tx: client_tx.clone() });
+                    //the first one in drives
+                    if clients.controller.is_none() {
+                        clients.controller = Some(id);
+                        clients.last_heard = Some(Instant::now());
+                    }
+                    let msg = match clients.controller {
+                        Some(c) if c == id => String::from("You have control of the tank"),
+                        _ => String::from("Another client has control, you are watching"),
+                    };
+                    let _ = client_tx.send((None, Response::UserMsg(msg)));
+                    id
+                };
+                eprintln!("Client {} connected", id);
 
-                //mask values so that these can be 'owned' by spawned threads and be used
-                //by the next
-                let mut rx = Arc::clone(&rx);
+                let reader_clients = Arc::clone(&clients);
+                let writer_clients = Arc::clone(&clients);
                 let tx = tx.clone();
-                let rx_loopback = rx_loopback.clone();
-                let reader_state = Arc::clone(&interface_state);
-                let writer_state = Arc::clone(&interface_state);
-
-                let read_handle = thread::spawn( move || {
-                    loop {
-                        let mut buff= String::new();
-                        match reader.read_line(&mut buff) {
-                            Err(e) => eprintln!("Error found: {}", e),
-                            Ok(0) => {
-                                eprintln!("EOF reached");
-                                return;
-                            },
-                            Ok(_) => {
-                                //echo to error
-                                eprintln!("received: \"{}\"", &buff);
-                                buff = buff.trim().to_lowercase();
-                                let mut parts = buff.split_whitespace();
-
-                                //check command against list
-                                match parts.next() {
-                                    Some(x) if x == "help" =>
-                                        rx_loopback.send(Response::Ok)
-                                        .and_then(|()| rx_loopback.send(Response::UserMsg(String::from(HELP_PROMPT)))),
-                                    Some(x) if x == "stopnow" => {
-                                        tx.send(Command::StopNow).unwrap();
-                                        rx_loopback.send(Response::Ok)
-                                    },
-                                    Some(x) if x == "sensornow" => {
-                                        tx.send(Command::GetSensorState).unwrap();
-                                        rx_loopback.send(Response::Ok)
-                                    },
-                                    Some(x) if x == "humanreadable" => {
-                                        match parts.next().and_then(|p| p.parse::<bool>().ok()) {
-                                            Some(s) => {
-                                                let mut state = reader_state.lock().unwrap();
-                                                state.human_readable = s;
-                                                rx_loopback.send(Response::Ok)
-                                            },
-                                            None => {
-                                                rx_loopback.send(Response::BadCommand(format!("{}", buff))).unwrap();
-                                                rx_loopback.send(Response::UserMsg(String::from(BAD_ARGUMENT_BOOL)))
-                                            }
-                                        }
-                                    },
-                                    Some(x) if x == "autosensor" => {
-                                        match parts.next().and_then(|p| p.parse::<bool>().ok()) {
-                                            Some(s) => {
-                                                let mut state = reader_state.lock().unwrap();
-                                                state.auto_send_state = s;
-                                                rx_loopback.send(Response::Ok)
-                                            },
-                                            None => {
-                                                rx_loopback.send(Response::BadCommand(format!("{}", buff))).unwrap();
-                                                rx_loopback.send(Response::UserMsg(String::from(BAD_ARGUMENT_BOOL)))
-                                            }
-                                        }
-                                    },
-                                    _ => {
-                                        rx_loopback.send(Response::BadCommand(format!("{}", buff))).unwrap();
-                                        rx_loopback.send(Response::UserMsg(String::from(HELP_PROMPT)))
-                                    },
-                                }.expect("TCP send channel broken");
-                            },
-                        }
-                    }
-                });
-                let write_handle = thread::spawn( move || {
-                    //since we should be the 'only' thread using the rx, we will mask it with the unwrapped version
-                    let rx = rx.lock().unwrap();
-                    'write_loop: loop {
-                        let r = rx.recv().expect("TCP send channel broken");
-                        let human_readable = {
-                            let lock = writer_state.lock().unwrap();
-                            lock.human_readable
-                        };
-                        let msg = if human_readable {
-                            match r {
-                                Response::UserMsg(s) => s,
-                                Response::Ok => String::from("Ok"),
-                                Response::BadCommand(s) => format!("Invalid Command: \"{}\"", s),
-                                Response::SensorState(s) => format!("DT: {}\tSpeed: {}\tHeading: {}\tSonar: {}cm",
-                                                                    s.duration().as_float_secs(),
-                                                                    s.speed(),
-                                                                    s.yaw(),
-                                                                    s.sonar()),
-                                r => serde_json::to_string(&r).unwrap(),
-                            }
-                        } else {
-                            serde_json::to_string(&r).unwrap()
-                        };
-                        if let Err(e) = write!(&mut writer, "{}\n", msg) {
-                            eprintln!("TCP error: {}", e);
-                            break 'write_loop;
-                        }
+                thread::spawn(move || {
+                    //an error only means the writer has gone, the client is leaving anyway
+                    let _ = read_loop(id, reader, &reader_clients, &tx, &client_tx);
+                    eprintln!("Client {} disconnected", id);
+                    let mut clients = reader_clients.lock().unwrap();
+                    clients.clients.remove(&id);
+                    if clients.controller == Some(id) {
+                        clients.controller = None;
+                        clients.last_heard = None;
                     }
                 });
+                thread::spawn(move || write_loop(id, stream, &writer_clients, client_rx));
+            },
+            Err(e) => {
+                eprintln!("{:?}", e);
+            }
+        }
+    }
+}
 
-                if let Err(_) = read_handle.join() {
-                    eprintln!("TCP Read thread panicked");
+/// Commands that only the controlling client may send
+fn needs_control(command: &Command) -> bool {
+    match *command {
+        Command::Move { .. } | Command::SetBehavior(_) |
+        Command::SetPid { .. } | Command::ResetPid | Command::SavePid |
+        Command::StartCsv { .. } | Command::StopCsv => true,
+        Command::StopNow | Command::GetSensorState | Command::GetBehavior | Command::GetPid => false,
+    }
+}
+
+/// What a client asked for
+enum Action {
+    /// Passed on to main
+    Tank(Command),
+    /// Handled by the client's reader
+    Session(SessionCommand),
+}
+
+/// Parses a line of json into a request and its id.
+/// Fails with the reason and the id, if the line got far enough to have one.
+fn parse_json(line: &str) -> Result<(Option<u64>, Action), (Option<u64>, String)> {
+    let request: messages::Request<serde_json::Value> = serde_json::from_str(line)
+        .map_err(|e| (None, format!("Invalid json request: {}", e)))?;
+    let id = request.id;
+    match serde_json::from_value::<Command>(request.command.clone()) {
+        Ok(c) => check_command(c).map(|c| (id, Action::Tank(c))).map_err(|e| (id, e)),
+        Err(e) => serde_json::from_value::<SessionCommand>(request.command)
+            .map(|c| (id, Action::Session(c)))
+            .map_err(|_| (id, format!("Invalid command: {}", e))),
+    }
+}
+
+/// Parses a line of text into a request
+fn parse_text(line: &str) -> Result<Action, String> {
+    let lower = line.to_lowercase();
+    let mut parts = lower.split_whitespace();
+    let bool_arg = |p: Option<&str>| p.and_then(|p| p.parse::<bool>().ok())
+        .ok_or_else(|| String::from(BAD_ARGUMENT_BOOL));
+
+    //check command against list
+    let request = match parts.next() {
+        Some("help") => Action::Session(SessionCommand::Help),
+        Some("stopnow") => Action::Tank(Command::StopNow),
+        Some("heartbeat") => Action::Session(SessionCommand::Heartbeat),
+        Some("control") => Action::Session(SessionCommand::TakeControl),
+        Some("release") => Action::Session(SessionCommand::ReleaseControl),
+        Some("sensornow") => Action::Tank(Command::GetSensorState),
+        Some("humanreadable") => Action::Session(SessionCommand::HumanReadable(bool_arg(parts.next())?)),
+        Some("autosensor") => Action::Session(SessionCommand::AutoSendState(bool_arg(parts.next())?)),
+        Some("autopid") => Action::Session(SessionCommand::AutoSendPid(bool_arg(parts.next())?)),
+        Some(x) if x == "move" => Action::Tank(parse_move(line[x.len()..].trim())?),
+        Some("behavior") => match parts.next() {
+            None => Action::Tank(Command::GetBehavior),
+            Some(name) => match BehaviorKind::from_name(name) {
+                Some(kind) => Action::Tank(Command::SetBehavior(kind)),
+                None => return Err(String::from(BAD_ARGUMENT_BEHAVIOR)),
+            },
+        },
+        Some("pid") => {
+            let args: Vec<&str> = parts.collect();
+            let gains: Vec<f32> = args.iter()
+                .filter_map(|p| p.parse::<f32>().ok())
+                .filter(|g| g.is_finite())
+                .collect();
+            Action::Tank(match args.as_slice() {
+                [] => Command::GetPid,
+                ["reset"] => Command::ResetPid,
+                ["save"] => Command::SavePid,
+                [_, _, _] if gains.len() == 3 =>
+                    Command::SetPid { kp: gains[0], ki: gains[1], kd: gains[2] },
+                _ => return Err(String::from(BAD_ARGUMENT_PID)),
+            })
+        },
+        Some("csv") => {
+            //file names are case sensitive, so take them from the line as sent
+            let args: Vec<&str> = line.split_whitespace().skip(1).collect();
+            Action::Tank(match args.split_first() {
+                Some((arg, [])) if arg.eq_ignore_ascii_case("stop") => Command::StopCsv,
+                Some((arg, rest)) if arg.eq_ignore_ascii_case("start") && !rest.is_empty() => {
+                    let columns = if rest.len() > 1 {
+                        Some(parse_columns(&rest[1..].join(" ").to_lowercase())?)
+                    } else {
+                        None
+                    };
+                    Command::StartCsv { file: rest[0].to_string(), columns }
+                },
+                _ => return Err(String::from(BAD_ARGUMENT_CSV)),
+            })
+        },
+        _ => return Err(String::from(HELP_PROMPT)),
+    };
+    Ok(request)
+}
+
+/// Reads lines from a client until it disconnects, passing commands on to main.
+/// Responses to the client go through loopback.
+fn read_loop(client: ClientId,
+             mut reader: BufReader<TcpStream>,
+             clients: &Arc<Mutex<Clients>>,
+             tx: &Sender<(Requester, Command)>,
+             loopback: &Sender<(Option<u64>, Response)>) -> Result<(), Gone> {
+    loop {
+        let mut buff= String::new();
+        match reader.read_line(&mut buff) {
+            Err(e) => {
+                eprintln!("Error found: {}", e);
+                return Ok(());
+            },
+            Ok(0) => {
+                eprintln!("EOF reached");
+                return Ok(());
+            },
+            Ok(_) => {
+                {
+                    let mut clients = clients.lock().unwrap();
+                    if clients.controller == Some(client) {
+                        clients.last_heard = Some(Instant::now());
+                    }
                 }
-                if let Err(_) = write_handle.join() {
-                    eprintln!("TCP Read thread panicked");
+                //echo to error
+                eprintln!("received from {}: \"{}\"", client, &buff);
+                let line = buff.trim();
+                let parsed = if line.starts_with('{') {
+                    parse_json(line)
+                } else {
+                    parse_text(line).map(|r| (None, r)).map_err(|e| (None, e))
+                };
+                match parsed {
+                    Ok((id, request)) => {
+                        let from = Requester { client, id };
+                        handle_request(from, request, clients, tx, loopback)?;
+                    },
+                    Err((id, e)) => {
+                        answer(loopback, id, Response::BadCommand(String::from(line)))?;
+                        answer(loopback, id, Response::UserMsg(e))?;
+                    },
                 }
             },
-            Err(e) => {
-                eprintln!("{:?}", e);
+        }
+    }
+}
+
+/// The client's writer thread has gone away
+struct Gone;
+
+/// Queues a response for the client's writer thread
+fn answer(loopback: &Sender<(Option<u64>, Response)>, id: Option<u64>, response: Response) -> Result<(), Gone> {
+    loopback.send((id, response)).map_err(|_| Gone)
+}
+
+/// Acts on a request from a client
+fn handle_request(from: Requester,
+                  action: Action,
+                  clients: &Arc<Mutex<Clients>>,
+                  tx: &Sender<(Requester, Command)>,
+                  loopback: &Sender<(Option<u64>, Response)>) -> Result<(), Gone> {
+    let id = from.id;
+    let not_in_control = |what: String| {
+        answer(loopback, id, Response::BadCommand(what))?;
+        answer(loopback, id, Response::UserMsg(String::from(NOT_IN_CONTROL)))
+    };
+    let mut clients = clients.lock().unwrap();
+    match action {
+        Action::Tank(c) => {
+            if needs_control(&c) && clients.controller != Some(from.client) {
+                return not_in_control(format!("{:?}", c));
             }
+            //main only goes away when the program is ending
+            let _ = tx.send((from, c));
+            answer(loopback, id, Response::Ok)
+        },
+        Action::Session(SessionCommand::Help) => {
+            answer(loopback, id, Response::Ok)?;
+            answer(loopback, id, Response::UserMsg(String::from(HELP_PROMPT)))
+        },
+        Action::Session(SessionCommand::Heartbeat) => answer(loopback, id, Response::Ok),
+        Action::Session(SessionCommand::TakeControl) => match clients.controller {
+            Some(c) if c != from.client => not_in_control(String::from("control")),
+            _ => {
+                clients.controller = Some(from.client);
+                clients.last_heard = Some(Instant::now());
+                answer(loopback, id, Response::Ok)
+            },
+        },
+        Action::Session(SessionCommand::ReleaseControl) => {
+            if clients.controller == Some(from.client) {
+                clients.controller = None;
+                clients.last_heard = None;
+            }
+            answer(loopback, id, Response::Ok)
+        },
+        Action::Session(SessionCommand::HumanReadable(v)) => {
+            if let Some(settings) = clients.settings(from.client) {
+                settings.human_readable = v;
+            }
+            answer(loopback, id, Response::Ok)
+        },
+        Action::Session(SessionCommand::AutoSendState(v)) => {
+            if let Some(settings) = clients.settings(from.client) {
+                settings.auto_send_state = v;
+            }
+            answer(loopback, id, Response::Ok)
+        },
+        Action::Session(SessionCommand::AutoSendPid(v)) => {
+            if let Some(settings) = clients.settings(from.client) {
+                settings.auto_send_pid = v;
+            }
+            answer(loopback, id, Response::Ok)
+        },
+    }
+}
+
+/// Writes responses to a client, in the format it asked for, until either
+/// side goes away
+fn write_loop(client: ClientId, mut writer: TcpStream, clients: &Arc<Mutex<Clients>>, rx: Receiver<(Option<u64>, Response)>) {
+    for (id, r) in rx.iter() {
+        let human_readable = match clients.lock().unwrap().clients.get(&client) {
+            Some(client) => client.settings.human_readable,
+            None => return, //disconnected
+        };
+        let msg = if human_readable {
+            match id {
+                Some(id) => format!("[{}] {}", id, r),
+                None => r.to_string(),
+            }
+        } else {
+            match id {
+                Some(id) => serde_json::to_string(&Reply { id, response: r }).unwrap(),
+                None => serde_json::to_string(&r).unwrap(),
+            }
+        };
+        if let Err(e) = writeln!(&mut writer, "{}", msg) {
+            eprintln!("TCP error: {}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::csv_log::Column;
+
+    #[test]
+    fn json_move_keeps_id() {
+        let line = r#"{"id": 7, "command": {"Move": {"speed": 0.2, "target_yaw": 90, "end": {"Time": 1.5}}}}"#;
+        match parse_json(line) {
+            Ok((Some(7), Action::Tank(Command::Move { speed, target_yaw: Some(yaw), end: Some(EndCondition::Time(t)) }))) => {
+                assert_eq!(speed, 0.2);
+                assert_eq!(yaw, 90.0);
+                assert_eq!(t, Duration::from_millis(1500));
+            },
+            _ => panic!("expected a move with id 7"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn json_session_commands() {
+        match parse_json(r#"{"id": 1, "command": {"AutoSendState": true}}"#) {
+            Ok((Some(1), Action::Session(SessionCommand::AutoSendState(true)))) => (),
+            _ => panic!("expected AutoSendState"),
+        }
+        match parse_json(r#"{"command": "Heartbeat"}"#) {
+            Ok((None, Action::Session(SessionCommand::Heartbeat))) => (),
+            _ => panic!("expected a heartbeat without an id"),
+        }
+    }
+
+    #[test]
+    fn json_errors_keep_id() {
+        match parse_json(r#"{"id": 3, "command": "Fly"}"#) {
+            Err((Some(3), _)) => (),
+            _ => panic!("expected an error with id 3"),
+        }
+        match parse_json(r#"{"id": 4, "command": {"Move": {"speed": 0.2, "target_yaw": null, "end": "AngleReached"}}}"#) {
+            Err((Some(4), _)) => (),
+            _ => panic!("expected 'until angle' without a yaw to be refused"),
+        }
+        assert!(parse_json("{not json").is_err());
+    }
+
+    #[test]
+    fn text_and_json_agree() {
+        match parse_text("PID 1 2 3") {
+            Ok(Action::Tank(Command::SetPid { kp, ki, kd })) => assert_eq!((kp, ki, kd), (1.0, 2.0, 3.0)),
+            _ => panic!("expected SetPid"),
+        }
+        match parse_json(r#"{"command": {"SetPid": {"kp": 1, "ki": 2, "kd": 3}}}"#) {
+            Ok((None, Action::Tank(Command::SetPid { kp, ki, kd }))) => assert_eq!((kp, ki, kd), (1.0, 2.0, 3.0)),
+            _ => panic!("expected SetPid"),
+        }
+        //too big for an f32, so it would come through as infinity
+        match parse_json(r#"{"id": 5, "command": {"SetPid": {"kp": 1e39, "ki": 0, "kd": 0}}}"#) {
+            Err((Some(5), e)) => assert_eq!(e, "Gains should be numbers"),
+            _ => panic!("expected infinite gains to be refused"),
+        }
+        assert!(parse_text("pid 1e39 0 0").is_err());
+        assert!(parse_text("autosensor maybe").is_err());
+    }
+
+    #[test]
+    fn text_pid_commands() {
+        match parse_text("pid") {
+            Ok(Action::Tank(Command::GetPid)) => (),
+            _ => panic!("expected GetPid"),
+        }
+        match parse_text("pid Reset") {
+            Ok(Action::Tank(Command::ResetPid)) => (),
+            _ => panic!("expected ResetPid"),
+        }
+        match parse_text("pid save") {
+            Ok(Action::Tank(Command::SavePid)) => (),
+            _ => panic!("expected SavePid"),
+        }
+        match parse_text("autopid TRUE") {
+            Ok(Action::Session(SessionCommand::AutoSendPid(true))) => (),
+            _ => panic!("expected AutoSendPid"),
+        }
+        match parse_text("autopid false") {
+            Ok(Action::Session(SessionCommand::AutoSendPid(false))) => (),
+            _ => panic!("expected AutoSendPid"),
+        }
+        for bad in &["pid 1 2", "pid 1 2 nan", "pid 1 2 3 4", "pid load"] {
+            assert_eq!(parse_text(bad).err(), Some(String::from(BAD_ARGUMENT_PID)), "{}", bad);
+        }
+        assert_eq!(parse_text("autopid").err(), Some(String::from(BAD_ARGUMENT_BOOL)));
+    }
+
+    #[test]
+    fn csv_files_keep_case() {
+        match parse_text("CSV start Run1.csv time,Gyro") {
+            Ok(Action::Tank(Command::StartCsv { file, columns })) => {
+                assert_eq!(file, "Run1.csv");
+                assert_eq!(columns, Some(vec![Column::Time, Column::Gyro]));
+            },
+            _ => panic!("expected StartCsv"),
+        }
+        match parse_text("csv start log.csv") {
+            Ok(Action::Tank(Command::StartCsv { columns: None, .. })) => (),
+            _ => panic!("expected StartCsv with the configured columns"),
+        }
+        assert!(parse_text("csv start").is_err());
+        assert!(parse_text("csv start log.csv roll").is_err());
+    }
+}
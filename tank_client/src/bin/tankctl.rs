@@ -0,0 +1,236 @@
+//! Ground station for the tank, speaks its TCP protocol from the command line
+extern crate serde_json;
+extern crate tank_client;
+extern crate tank_protocol;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{LineWriter, Write};
+use std::process;
+use std::time::{Duration, Instant};
+
+use tank_client::{TankClient, Command, Response, SensorReport, Error};
+use tank_protocol::messages::{parse_move, secs_duration};
+
+const DEFAULT_ADDR: &str = "raspberrypi.local:27272";
+/// How often to let the tank know we are still here while waiting on a move
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(200);
+/// How often watch prints the sensor state
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+const USAGE: &str = "\
+Usage: tankctl [--addr <host:port>] <command>
+       tankctl help
+
+Commands:
+  status                     print the current sensor state
+  stop                       stop the tank and drop any queued moves
+  move <speed> [yaw <deg>] [for <secs> | until angle]
+                             drive at speed (in m/s), optionally turning to yaw,
+                             and wait for the move to finish
+  watch                      print the sensor state as it changes, along with
+                             mission progress and device health
+  record <file> [secs]       write every sensor state to file as a line of json,
+                             '-' for stdout, for secs or until interrupted
+  tune                       print the drive PID gains and terms
+  tune <kp> <ki> <kd>        set the drive PID gains
+  tune reset                 clear the drive PID integral
+  tune save                  save the drive PID gains to the tank's config file
+  tune watch                 print the drive PID terms as they change
+
+The address defaults to raspberrypi.local:27272.
+Exits with status 1 if the tank refuses a command or can't be reached,
+and 2 if the arguments don't make sense.
+";
+
+/// What to do, parsed from the arguments
+enum Task {
+    Status,
+    Stop,
+    Move(Command),
+    Watch,
+    Record { path: String, secs: Option<f64> },
+    Tune(Tune),
+}
+
+enum Tune {
+    Show,
+    Set { kp: f32, ki: f32, kd: f32 },
+    Reset,
+    Save,
+    Watch,
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let addr = match args.iter().position(|a| a == "--addr") {
+        Some(i) if i + 1 < args.len() => {
+            let addr = args.remove(i + 1);
+            args.remove(i);
+            addr
+        },
+        Some(_) => usage_error("--addr needs an address"),
+        None => String::from(DEFAULT_ADDR),
+    };
+
+    if args.iter().any(|a| a == "help" || a == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+    let task = match parse_task(&args) {
+        Ok(task) => task,
+        Err(e) => usage_error(&e),
+    };
+
+    if let Err(e) = run(&addr, task) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn usage_error(e: &str) -> ! {
+    eprintln!("{}\n\n{}", e, USAGE);
+    process::exit(2);
+}
+
+fn parse_task(args: &[String]) -> Result<Task, String> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let task = match args.split_first() {
+        Some((&"status", [])) => Task::Status,
+        Some((&"stop", [])) => Task::Stop,
+        Some((&"move", rest)) => Task::Move(parse_move(&rest.join(" "))?),
+        Some((&"watch", [])) => Task::Watch,
+        Some((&"record", [path])) => Task::Record { path: path.to_string(), secs: None },
+        Some((&"record", [path, secs])) => {
+            let secs = secs.parse::<f64>().ok()
+                .filter(|s| *s >= 0.0 && s.is_finite())
+                .ok_or_else(|| String::from("Expected a number of seconds to record for"))?;
+            Task::Record { path: path.to_string(), secs: Some(secs) }
+        },
+        Some((&"tune", rest)) => Task::Tune(match rest {
+            [] => Tune::Show,
+            ["reset"] => Tune::Reset,
+            ["save"] => Tune::Save,
+            ["watch"] => Tune::Watch,
+            [kp, ki, kd] => {
+                let gains: Vec<f32> = [kp, ki, kd].iter()
+                    .filter_map(|p| p.parse::<f32>().ok())
+                    .filter(|g| g.is_finite())
+                    .collect();
+                if gains.len() != 3 {
+                    return Err(String::from("Gains should be three numbers '<kp> <ki> <kd>'"));
+                }
+                Tune::Set { kp: gains[0], ki: gains[1], kd: gains[2] }
+            },
+            _ => return Err(String::from("Expected 'reset', 'save', 'watch' or three gains")),
+        }),
+        Some((command, _)) => return Err(format!("Unexpected '{}'", command)),
+        None => return Err(String::from("Expected a command")),
+    };
+    Ok(task)
+}
+
+fn run(addr: &str, task: Task) -> Result<(), Error> {
+    let mut client = TankClient::connect(addr)?;
+    match task {
+        Task::Status => print_state(&client.sensor_state()?),
+        Task::Stop => client.stop()?,
+        Task::Move(command) => {
+            let waits = match command {
+                Command::Move { ref end, .. } => end.is_some(),
+                _ => false,
+            };
+            client.take_control()?;
+            let id = client.send(command)?;
+            if waits {
+                loop {
+                    //only the wait is cut short, the heartbeat gets as long as anything else
+                    client.set_timeout(Some(HEARTBEAT_INTERVAL));
+                    let result = client.await_completion(id);
+                    client.set_timeout(None);
+                    match result {
+                        Err(Error::Timeout) => client.heartbeat()?,
+                        r => break r?,
+                    }
+                }
+                println!("Move complete");
+            } else {
+                println!("Driving, until the next move or 'tankctl stop'");
+            }
+        },
+        Task::Watch => {
+            client.subscribe_state(true)?;
+            let mut last_print = None;
+            loop {
+                match client.next_broadcast()? {
+                    Response::SensorState(state) => {
+                        if last_print.map(|t: Instant| t.elapsed() >= WATCH_INTERVAL).unwrap_or(true) {
+                            print_state(&state);
+                            println!();
+                            last_print = Some(Instant::now());
+                        }
+                    },
+                    r => println!("{}", r),
+                }
+            }
+        },
+        Task::Record { path, secs } => {
+            let out: Box<dyn Write> = if path == "-" {
+                Box::new(io::stdout())
+            } else {
+                Box::new(File::create(&path)?)
+            };
+            let mut out = LineWriter::new(out);
+            let end = secs.map(|s| Instant::now() + secs_duration(s));
+            client.subscribe_state(true)?;
+            while end.map(|e| Instant::now() < e).unwrap_or(true) {
+                let state = client.next_state()?;
+                writeln!(out, "{}", serde_json::to_string(&state)?)?;
+            }
+        },
+        Task::Tune(Tune::Show) => println!("{}", Response::PidState(client.pid()?)),
+        Task::Tune(Tune::Set { kp, ki, kd }) => {
+            client.take_control()?;
+            client.send(Command::SetPid { kp, ki, kd })?;
+            println!("{}", Response::PidState(client.pid()?));
+        },
+        Task::Tune(Tune::Reset) => {
+            client.take_control()?;
+            client.send(Command::ResetPid)?;
+        },
+        Task::Tune(Tune::Save) => {
+            client.take_control()?;
+            println!("{}", client.query(Command::SavePid)?);
+        },
+        Task::Tune(Tune::Watch) => {
+            client.subscribe_pid(true)?;
+            loop {
+                println!("{}", Response::PidState(client.next_pid()?));
+            }
+        },
+    }
+    Ok(())
+}
+
+fn print_state(s: &SensorReport) {
+    let raw = &s.raw_state;
+    let pose = s.pose;
+    println!("Heading:     {:7.1} deg   pitch {:.1} deg   roll {:.1} deg",
+             s.yaw.to_degrees(), s.pitch.to_degrees(), s.roll.to_degrees());
+    println!("Position:    ({:.2}, {:.2}) m   moving {:.2} m/s", pose.x, pose.y, pose.velocity);
+    println!("Drive power: {:7.2}", s.speed);
+    println!("Sonar:       {:7.1} cm", s.sonar);
+    println!("Gyro:        ({:.2}, {:.2}, {:.2}) deg/s", raw.gyro.x, raw.gyro.y, raw.gyro.z);
+    println!("Accel:       ({:.2}, {:.2}, {:.2}) m/s^2", raw.accel.x, raw.accel.y, raw.accel.z);
+    println!("Mag:         ({:.2}, {:.2}, {:.2})", raw.mag.x, raw.mag.y, raw.mag.z);
+    println!("Temperature: {:7.1} C", raw.temp);
+    if let Some(e) = s.encoders {
+        println!("Tracks:      ({:.2}, {:.2}) m/s   ({}, {}) ticks",
+                 e.left_velocity, e.right_velocity, e.left_ticks, e.right_ticks);
+    }
+    let l = s.loop_stats;
+    println!("Loop period: {:7.1} ms   ({:.1} to {:.1} ms, {} overruns, {} in total)",
+             l.mean_period * 1000.0, l.min_period * 1000.0, l.max_period * 1000.0,
+             l.overruns, l.total_overruns);
+}
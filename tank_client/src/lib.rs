@@ -0,0 +1,367 @@
+//! Blocking client for the tank's TCP interface.
+//!
+//! Talks the json request protocol, so every request carries an id and the
+//! replies to it can be told apart from everything else the tank sends.
+//! Messages that arrive while waiting for something else are kept, in order,
+//! until they are asked for. Only the latest subscribed sensor state and PID
+//! terms are kept, and past MAX_BACKLOG the oldest messages that weren't
+//! asked for are dropped, so a client that never reads them doesn't grow
+//! without bound.
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate tank_protocol;
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+pub use tank_protocol::messages::{Command, SessionCommand, EndCondition, Response, Request, Reply};
+pub use tank_protocol::state::{SensorReport, PidTelemetry};
+
+/// Most messages kept while waiting for something else
+pub const MAX_BACKLOG: usize = 256;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The tank refused the request, with its reason
+    BadCommand(String),
+    /// The move was dropped before it finished, with the reason
+    Aborted(String),
+    /// Nothing came back in time
+    Timeout,
+    /// The tank closed the connection
+    Disconnected,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "IO error: {}", e),
+            Error::Json(ref e) => write!(f, "Json error: {}", e),
+            Error::BadCommand(ref reason) => write!(f, "Bad command: {}", reason),
+            Error::Aborted(ref reason) => write!(f, "Mission aborted: {}", reason),
+            Error::Timeout => write!(f, "Timed out waiting for the tank"),
+            Error::Disconnected => write!(f, "The tank closed the connection"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// A line from the tank
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Message {
+    /// Answers one of our requests
+    Reply(Reply),
+    /// Sent to everyone, or to us without being asked
+    Broadcast(Response),
+}
+
+impl Message {
+    /// The response, if it answers the request with this id
+    fn answers(&self, id: u64) -> Option<&Response> {
+        match *self {
+            Message::Reply(ref r) if r.id == id => Some(&r.response),
+            _ => None,
+        }
+    }
+}
+
+pub struct TankClient {
+    stream: TcpStream,
+    rx: Receiver<Message>,
+    /// Messages that arrived while waiting for something else
+    backlog: VecDeque<Message>,
+    next_id: u64,
+    timeout: Option<Duration>,
+}
+
+impl TankClient {
+    /// Connects to the tank and switches the connection to json
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TankClient> {
+        let stream = TcpStream::connect(addr)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => return,
+                };
+                //anything that isn't json was sent before we switched over
+                if let Ok(message) = serde_json::from_str(&line) {
+                    if tx.send(message).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        let mut client = TankClient {
+            stream,
+            rx,
+            backlog: VecDeque::new(),
+            next_id: 1,
+            timeout: None,
+        };
+        client.session(SessionCommand::HumanReadable(false))?;
+        Ok(client)
+    }
+
+    /// How long to wait for the tank before giving up, None waits forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sends a command, returning its id once the tank has accepted it
+    pub fn send(&mut self, command: Command) -> Result<u64> {
+        self.request(&command)
+    }
+
+    /// Sends a request about the connection itself
+    pub fn session(&mut self, command: SessionCommand) -> Result<()> {
+        self.request(&command).map(|_| ())
+    }
+
+    /// Sends a command and waits for the response it asked for.
+    /// Fails if the tank couldn't carry the command out.
+    pub fn query(&mut self, command: Command) -> Result<Response> {
+        let id = self.send(command)?;
+        match self.wait_for(|m| m.answers(id).is_some())? {
+            Message::Reply(Reply { response: Response::BadCommand(reason), .. }) =>
+                Err(Error::BadCommand(reason)),
+            Message::Reply(r) => Ok(r.response),
+            Message::Broadcast(_) => unreachable!(),
+        }
+    }
+
+    /// Stops the tank and drops any queued moves, any client can do this
+    pub fn stop(&mut self) -> Result<()> {
+        self.send(Command::StopNow).map(|_| ())
+    }
+
+    /// Lets the tank know we are still here
+    pub fn heartbeat(&mut self) -> Result<()> {
+        self.session(SessionCommand::Heartbeat)
+    }
+
+    /// Takes control of the tank, fails if another client has it
+    pub fn take_control(&mut self) -> Result<()> {
+        self.session(SessionCommand::TakeControl)
+    }
+
+    pub fn release_control(&mut self) -> Result<()> {
+        self.session(SessionCommand::ReleaseControl)
+    }
+
+    /// Queues a move, returning its id to wait on with await_completion.
+    /// speed is in m/s and target_yaw in degrees.
+    pub fn send_move(&mut self, speed: f64, target_yaw: Option<f64>, end: Option<EndCondition>) -> Result<u64> {
+        self.send(Command::Move { speed, target_yaw, end })
+    }
+
+    /// Waits for a move to finish. Fails if it was aborted.
+    pub fn await_completion(&mut self, id: u64) -> Result<()> {
+        let message = self.wait_for(|m| matches!(m.answers(id),
+            Some(&Response::StepComplete { .. }) | Some(&Response::MissionAborted(_))))?;
+        match message {
+            Message::Reply(Reply { response: Response::MissionAborted(reason), .. }) =>
+                Err(Error::Aborted(reason)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Asks for the current sensor state
+    pub fn sensor_state(&mut self) -> Result<SensorReport> {
+        match self.query(Command::GetSensorState)? {
+            Response::SensorState(state) => Ok(*state),
+            _ => Err(Error::BadCommand(String::from("expected a sensor state"))),
+        }
+    }
+
+    /// Starts or stops the tank sending its sensor state after every update
+    pub fn subscribe_state(&mut self, subscribe: bool) -> Result<()> {
+        self.session(SessionCommand::AutoSendState(subscribe))
+    }
+
+    /// Waits for the next sensor state sent while subscribed
+    pub fn next_state(&mut self) -> Result<SensorReport> {
+        let message = self.wait_for(|m| matches!(*m, Message::Broadcast(Response::SensorState(_))))?;
+        match message {
+            Message::Broadcast(Response::SensorState(state)) => Ok(*state),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Asks for the gains and current terms of the drive PID
+    pub fn pid(&mut self) -> Result<PidTelemetry> {
+        match self.query(Command::GetPid)? {
+            Response::PidState(pid) => Ok(pid),
+            _ => Err(Error::BadCommand(String::from("expected the PID state"))),
+        }
+    }
+
+    /// Starts or stops the tank sending the drive PID terms after every update
+    pub fn subscribe_pid(&mut self, subscribe: bool) -> Result<()> {
+        self.session(SessionCommand::AutoSendPid(subscribe))
+    }
+
+    /// Waits for the next drive PID terms sent while subscribed
+    pub fn next_pid(&mut self) -> Result<PidTelemetry> {
+        let message = self.wait_for(|m| matches!(*m, Message::Broadcast(Response::PidState(_))))?;
+        match message {
+            Message::Broadcast(Response::PidState(pid)) => Ok(pid),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Waits for the next response that wasn't asked for, like mission
+    /// progress or device health
+    pub fn next_broadcast(&mut self) -> Result<Response> {
+        match self.wait_for(|m| matches!(*m, Message::Broadcast(_)))? {
+            Message::Broadcast(response) => Ok(response),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Sends a request and waits for the tank to accept or refuse it
+    fn request<C: Serialize>(&mut self, command: &C) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let line = serde_json::to_string(&Request { id: Some(id), command })?;
+        writeln!(self.stream, "{}", line)?;
+
+        let message = self.wait_for(|m| matches!(m.answers(id),
+            Some(&Response::Ok) | Some(&Response::BadCommand(_))))?;
+        match message {
+            Message::Reply(Reply { response: Response::BadCommand(command), .. }) => {
+                //the reason follows in a message of its own
+                let reason = self.wait_for(|m| matches!(m.answers(id), Some(&Response::UserMsg(_))));
+                match reason {
+                    Ok(Message::Reply(Reply { response: Response::UserMsg(reason), .. })) =>
+                        Err(Error::BadCommand(reason)),
+                    _ => Err(Error::BadCommand(command)),
+                }
+            },
+            _ => Ok(id),
+        }
+    }
+
+    /// Takes the first message that matches, from the backlog or the tank,
+    /// keeping the rest for later
+    fn wait_for<F: Fn(&Message) -> bool>(&mut self, matches: F) -> Result<Message> {
+        if let Some(i) = self.backlog.iter().position(&matches) {
+            return Ok(self.backlog.remove(i).unwrap());
+        }
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        loop {
+            let message = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    match self.rx.recv_timeout(deadline - now) {
+                        Ok(m) => m,
+                        Err(RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+                        Err(RecvTimeoutError::Disconnected) => return Err(Error::Disconnected),
+                    }
+                },
+                None => self.rx.recv().map_err(|_| Error::Disconnected)?,
+            };
+            if matches(&message) {
+                return Ok(message);
+            }
+            keep(&mut self.backlog, message);
+        }
+    }
+}
+
+/// Adds a message to the backlog, dropping any it makes out of date
+fn keep(backlog: &mut VecDeque<Message>, message: Message) {
+    //states are sent every update, only the latest is any use
+    if let Message::Broadcast(ref response @ Response::SensorState(_))
+        | Message::Broadcast(ref response @ Response::PidState(_)) = message {
+        let kind = mem::discriminant(response);
+        backlog.retain(|m| match *m {
+            Message::Broadcast(ref r) => mem::discriminant(r) != kind,
+            Message::Reply(_) => true,
+        });
+    }
+    backlog.push_back(message);
+    if backlog.len() > MAX_BACKLOG {
+        //replies were asked for, so are kept while there is anything else to drop
+        let oldest = backlog.iter()
+            .position(|m| match *m {
+                Message::Broadcast(_) => true,
+                Message::Reply(_) => false,
+            })
+            .unwrap_or(0);
+        backlog.remove(oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(output: f32) -> Message {
+        Message::Broadcast(Response::PidState(PidTelemetry {
+            kp: 1.0, ki: 0.0, kd: 0.0, error: 0.0, integral: 0.0, derivative: 0.0, output,
+        }))
+    }
+
+    #[test]
+    fn only_the_latest_state_is_kept() {
+        let mut backlog = VecDeque::new();
+        keep(&mut backlog, pid(1.0));
+        keep(&mut backlog, Message::Reply(Reply { id: 1, response: Response::Ok }));
+        keep(&mut backlog, Message::Broadcast(Response::MissionComplete));
+        keep(&mut backlog, pid(2.0));
+        assert_eq!(backlog.len(), 3);
+        match backlog.back() {
+            Some(&Message::Broadcast(Response::PidState(p))) => assert_eq!(p.output, 2.0),
+            _ => panic!("expected the latest PID state last"),
+        }
+    }
+
+    #[test]
+    fn backlog_is_capped() {
+        let mut backlog = VecDeque::new();
+        keep(&mut backlog, Message::Reply(Reply { id: 1, response: Response::Ok }));
+        for _ in 0..MAX_BACKLOG * 2 {
+            keep(&mut backlog, Message::Broadcast(Response::MissionComplete));
+        }
+        assert_eq!(backlog.len(), MAX_BACKLOG);
+        //the reply outlives broadcasts that came after it
+        assert!(backlog[0].answers(1).is_some());
+    }
+}
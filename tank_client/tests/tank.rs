@@ -0,0 +1,204 @@
+//! Runs the client against a simulated tank in the same process
+extern crate rust_tank_v2;
+extern crate tank_client;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use rust_tank_v2::config::Config;
+use rust_tank_v2::hardware_interface::RTHandle;
+use rust_tank_v2::hardware_interface::devices::{Simulation, Room};
+use rust_tank_v2::mission::MissionExecutor;
+use rust_tank_v2::tcp_interface::TcpInterface;
+use tank_client::{TankClient, Command, EndCondition, Response, Error};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A simulated tank with just enough of a main loop to run missions
+struct Tank {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Tank {
+    fn start() -> Tank {
+        let running = Arc::new(AtomicBool::new(true));
+        let (tx, rx) = mpsc::channel();
+        let thread_running = Arc::clone(&running);
+        let handle = thread::spawn(move || {
+            let config = Config::default();
+            let sim = Simulation::new(Room::rectangle(4.0, 3.0), config.motors.clone());
+            let mut hw = RTHandle::with_devices(sim.pwm(), sim.imu(), sim.sonar(), None, &config)
+                .expect("Failed to start the simulation");
+            let mut tcp = TcpInterface::new("127.0.0.1:0")
+                .expect("Failed to start the TCP interface");
+            tx.send(tcp.local_addr()).unwrap();
+
+            let mut mission = MissionExecutor::new();
+            while thread_running.load(Ordering::SeqCst) {
+                while let Some((from, c)) = tcp.next_command() {
+                    match c {
+                        Command::StopNow => {
+                            hw.set_drive(0.0, 0.0);
+                            mission.abort("stopped", &mut hw, &mut tcp);
+                        },
+                        Command::Move { speed, target_yaw, end } =>
+                            mission.push(from, speed, target_yaw, end),
+                        Command::GetSensorState => {
+                            let state = hw.sensor_state().report();
+                            tcp.reply(from, Response::SensorState(Box::new(state)));
+                        },
+                        Command::SavePid =>
+                            tcp.reply(from, Response::BadCommand(String::from("No config file"))),
+                        _ => (),
+                    }
+                }
+                mission.update(&mut hw, &mut tcp);
+                let send_state = tcp.auto_send_state();
+                for event in hw.update(send_state, &mut tcp) {
+                    mission.handle_event(&event, &mut hw, &mut tcp);
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            hw.close();
+        });
+        let addr = rx.recv().unwrap();
+        Tank { addr, running, handle: Some(handle) }
+    }
+
+    fn connect(&self) -> TankClient {
+        let mut client = TankClient::connect(self.addr).unwrap();
+        client.set_timeout(Some(TIMEOUT));
+        client
+    }
+}
+
+impl Drop for Tank {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[test]
+fn move_completes() {
+    let tank = Tank::start();
+    let mut client = tank.connect();
+    let id = client.send_move(0.3, None, Some(EndCondition::Time(Duration::from_millis(300)))).unwrap();
+    client.await_completion(id).unwrap();
+    let state = client.sensor_state().unwrap();
+    assert!(state.pose.x > 0.0);
+}
+
+#[test]
+fn queued_moves_complete_in_order() {
+    let tank = Tank::start();
+    let mut client = tank.connect();
+    let first = client.send_move(0.2, None, Some(EndCondition::Time(Duration::from_millis(200)))).unwrap();
+    let second = client.send_move(-0.2, None, Some(EndCondition::Time(Duration::from_millis(200)))).unwrap();
+    assert!(second > first);
+    client.await_completion(second).unwrap();
+    //the first finished before the second, so it is waiting in the backlog
+    client.await_completion(first).unwrap();
+}
+
+#[test]
+fn stop_aborts_move() {
+    let tank = Tank::start();
+    let mut client = tank.connect();
+    let id = client.send_move(0.2, None, Some(EndCondition::Time(Duration::from_secs(10)))).unwrap();
+    client.stop().unwrap();
+    match client.await_completion(id) {
+        Err(Error::Aborted(_)) => (),
+        r => panic!("expected the move to be aborted, got {:?}", r.err()),
+    }
+}
+
+#[test]
+fn aborts_are_sent_once() {
+    let tank = Tank::start();
+    let mut client = tank.connect();
+    let mut watcher = tank.connect();
+    let id = client.send_move(0.2, None, Some(EndCondition::Time(Duration::from_secs(10)))).unwrap();
+    client.stop().unwrap();
+    assert!(client.await_completion(id).is_err());
+    //everyone else hears of it as a broadcast
+    loop {
+        if let Response::MissionAborted(_) = watcher.next_broadcast().unwrap() {
+            break;
+        }
+    }
+    //but the client was already told, in the reply
+    client.set_timeout(Some(Duration::from_millis(200)));
+    loop {
+        match client.next_broadcast() {
+            Ok(Response::MissionAborted(_)) => panic!("the abort was sent twice"),
+            Ok(_) => (),
+            Err(Error::Timeout) => break,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+#[test]
+fn subscribed_states_arrive() {
+    let tank = Tank::start();
+    let mut client = tank.connect();
+    client.subscribe_state(true).unwrap();
+    let first = client.next_state().unwrap();
+    let second = client.next_state().unwrap();
+    assert!(second.time > first.time);
+    client.subscribe_state(false).unwrap();
+}
+
+#[test]
+fn watchers_cannot_drive() {
+    let tank = Tank::start();
+    let mut driver = tank.connect();
+    let mut watcher = tank.connect();
+    match watcher.send_move(0.2, None, None) {
+        Err(Error::BadCommand(_)) => (),
+        r => panic!("expected the move to be refused, got {:?}", r),
+    }
+    assert!(watcher.take_control().is_err());
+    driver.release_control().unwrap();
+    watcher.take_control().unwrap();
+    let id = watcher.send_move(0.0, None, None).unwrap();
+    watcher.await_completion(id).unwrap();
+    //anyone can stop the tank
+    driver.stop().unwrap();
+}
+
+#[test]
+fn invalid_moves_are_refused() {
+    let tank = Tank::start();
+    let mut client = tank.connect();
+    match client.send_move(f64::NAN, None, None) {
+        Err(Error::BadCommand(_)) => (),
+        r => panic!("expected a NaN speed to be refused, got {:?}", r),
+    }
+    match client.send_move(0.2, None, Some(EndCondition::AngleReached)) {
+        Err(Error::BadCommand(_)) => (),
+        r => panic!("expected 'until angle' without a yaw to be refused, got {:?}", r),
+    }
+}
+
+#[test]
+fn failed_commands_are_errors() {
+    let tank = Tank::start();
+    let mut client = tank.connect();
+    client.take_control().unwrap();
+    match client.query(Command::SavePid) {
+        Err(Error::BadCommand(reason)) => assert_eq!(reason, "No config file"),
+        Err(e) => panic!("expected the save to be refused, got {}", e),
+        Ok(_) => panic!("expected the save to be refused"),
+    }
+}
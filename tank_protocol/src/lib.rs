@@ -0,0 +1,12 @@
+//! The messages the tank and its clients send each other over TCP.
+//! Kept apart from the tank itself, so clients don't need the i2c and gpio
+//! crates, which only build on linux.
+extern crate floating_duration;
+#[macro_use] extern crate lazy_static;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+
+pub mod messages;
+pub mod state;
+pub mod timestamp;
@@ -0,0 +1,360 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use floating_duration::TimeAsFloat;
+use serde_json;
+
+use state::{SensorReport, PidTelemetry, Device};
+
+/// Longest a timed move can run for, in seconds
+pub const MAX_MOVE_SECS: u64 = 3600;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Command {
+    /// STOP all motors, immediately
+    /// This also will clear all pending commands.
+    StopNow,
+    /// Ask the tank to give the current state of onboard sensors
+    // TODO should I split this up into multiple components?
+    GetSensorState,
+    /// Moves the tank in a strait line, until end condition is met.
+    /// speed is in m/s. Positive speeds for forward, negative for backward.
+    /// Target_yaw is the desired angle in degrees
+    Move{speed: f64, target_yaw: Option<f64>, end: Option<EndCondition>},
+    /// Switches to a different autonomous behavior
+    SetBehavior(BehaviorKind),
+    /// Ask which autonomous behavior is running
+    GetBehavior,
+    /// Ask for the gains and current terms of the drive PID
+    GetPid,
+    /// Change the gains of the drive PID
+    SetPid{kp: f32, ki: f32, kd: f32},
+    /// Clear the integral and error history of the drive PID
+    ResetPid,
+    /// Write the current PID gains to the config file
+    SavePid,
+    /// Start writing a CSV row each tick to a new file in the tank's CSV
+    /// directory, with the columns from the config unless given.
+    /// The file is a bare name, without any directories.
+    StartCsv{file: String, columns: Option<Vec<Column>>},
+    /// Stop writing the CSV log
+    StopCsv,
+}
+
+/// Requests about the connection itself, handled without bothering the tank
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SessionCommand {
+    /// List the available text commands
+    Help,
+    /// Let the tank know the client is still there
+    Heartbeat,
+    /// Take control of the tank, if no one else has it
+    TakeControl,
+    /// Give up control of the tank
+    ReleaseControl,
+    /// Send responses as text rather than json
+    HumanReadable(bool),
+    /// Send the sensor state after every update
+    AutoSendState(bool),
+    /// Send the drive PID terms after every update
+    AutoSendPid(bool),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum EndCondition {
+    /// Seconds to keep moving for
+    Time(#[serde(serialize_with = "secs_as_float", deserialize_with = "float_secs")] Duration),
+    AngleReached,
+}
+
+/// The behaviors that can be picked at runtime
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BehaviorKind {
+    Manual,
+    Bounce,
+    Wander,
+    WallFollow,
+}
+
+impl BehaviorKind {
+    pub const ALL: [BehaviorKind; 4] = [
+        BehaviorKind::Manual, BehaviorKind::Bounce, BehaviorKind::Wander, BehaviorKind::WallFollow,
+    ];
+
+    /// Looks up a behavior by the name used on the tcp interface
+    pub fn from_name(name: &str) -> Option<BehaviorKind> {
+        BehaviorKind::ALL.iter().cloned().find(|k| k.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BehaviorKind::Manual => "manual",
+            BehaviorKind::Bounce => "bounce",
+            BehaviorKind::Wander => "wander",
+            BehaviorKind::WallFollow => "wallfollow",
+        }
+    }
+}
+
+/// A group of columns that can be picked for the CSV log
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Column {
+    /// Seconds since the first row
+    Time,
+    /// Roll, pitch and yaw in radians
+    Orientation,
+    /// Rates in deg/s
+    Gyro,
+    /// In m/s^2
+    Accel,
+    Mag,
+    /// In degrees C
+    Temp,
+    /// Distance in cm
+    Sonar,
+    PidOutput,
+    /// Power of each track, from -1 to 1
+    MotorPower,
+}
+
+impl Column {
+    pub const ALL: [Column; 9] = [
+        Column::Time, Column::Orientation, Column::Gyro, Column::Accel, Column::Mag,
+        Column::Temp, Column::Sonar, Column::PidOutput, Column::MotorPower,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Time => "time",
+            Column::Orientation => "orientation",
+            Column::Gyro => "gyro",
+            Column::Accel => "accel",
+            Column::Mag => "mag",
+            Column::Temp => "temp",
+            Column::Sonar => "sonar",
+            Column::PidOutput => "pid_output",
+            Column::MotorPower => "motor_power",
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Column, String> {
+        Column::ALL.iter().cloned()
+            .find(|c| c.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Column::ALL.iter().map(|c| c.name()).collect();
+                format!("Unknown column '{}', expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Response {
+    /// Command was processed successfully
+    Ok,
+    /// Command failed to parse
+    BadCommand(String),
+    /// Current state of sensors
+    SensorState(Box<SensorReport>),
+    /// Raw text to be displayed to user
+    UserMsg(String),
+    /// A queued Move has started running
+    StepStarted { step: usize, remaining: usize },
+    /// A queued Move met its end condition
+    StepComplete { step: usize, remaining: usize },
+    /// The last queued Move finished
+    MissionComplete,
+    /// Gains and current terms of the drive PID
+    PidState(PidTelemetry),
+    /// The queued Moves were dropped before finishing, with the reason why
+    MissionAborted(String),
+    /// A device started or stopped having problems, with a description
+    DeviceHealth { device: Device, health: String },
+}
+
+impl fmt::Display for Response {
+    /// Formats the response for people, as sent to human readable clients
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Response::UserMsg(ref s) => write!(f, "{}", s),
+            Response::Ok => write!(f, "Ok"),
+            Response::BadCommand(ref s) => write!(f, "Invalid Command: \"{}\"", s),
+            Response::StepStarted { step, remaining } =>
+                write!(f, "Step {} started, {} more queued", step, remaining),
+            Response::StepComplete { step, remaining } =>
+                write!(f, "Step {} complete, {} more queued", step, remaining),
+            Response::PidState(p) => write!(f, "kp: {}\tki: {}\tkd: {}\tError: {:.4}\tIntegral: {:.4}\tDerivative: {:.4}\tOutput: {:.4}",
+                                            p.kp, p.ki, p.kd,
+                                            p.error, p.integral, p.derivative, p.output),
+            Response::MissionComplete => write!(f, "Mission complete"),
+            Response::MissionAborted(ref s) => write!(f, "Mission aborted: {}", s),
+            Response::DeviceHealth { device, ref health } => write!(f, "{:?}: {}", device, health),
+            Response::SensorState(ref s) => {
+                write!(f, "DT: {}\tSpeed: {}\tHeading: {}\tSonar: {}cm\tPos: ({:.2}, {:.2})m",
+                       s.duration.as_fractional_secs(),
+                       s.speed,
+                       s.yaw,
+                       s.sonar,
+                       s.pose.x,
+                       s.pose.y)?;
+                if let Some(e) = s.encoders {
+                    write!(f, "\tTracks: ({:.2}, {:.2})m/s", e.left_velocity, e.right_velocity)?;
+                }
+                let l = s.loop_stats;
+                write!(f, "\tLoop: {:.1}ms ({:.1}-{:.1}ms, {} overruns)",
+                       l.mean_period * 1000.0, l.min_period * 1000.0, l.max_period * 1000.0, l.overruns)
+            },
+        }
+    }
+}
+
+/// A request sent as a line of json, command is a Command or SessionCommand
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request<C> {
+    /// Echoed in the replies, when given
+    #[serde(default)]
+    pub id: Option<u64>,
+    pub command: C,
+}
+
+/// A response to a json request that had an id, sent in place of the bare response
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reply {
+    pub id: u64,
+    pub response: Response,
+}
+
+/// Writes a Duration as a floating point number of seconds
+fn secs_as_float<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+        where S: ::serde::Serializer {
+    serializer.serialize_f64(duration.as_fractional_secs())
+}
+
+/// Reads a Duration written as a floating point number of seconds
+fn float_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+        where D: ::serde::Deserializer<'de> {
+    use serde::Deserialize;
+    use serde::de::Error;
+
+    let secs = f64::deserialize(deserializer)?;
+    if !(secs >= 0.0 && secs.is_finite()) {
+        return Err(D::Error::custom("time should be a positive number of seconds"));
+    }
+    Ok(secs_duration(secs))
+}
+
+/// A Duration from a floating point number of seconds, which should be positive and finite.
+/// Times too long for a Duration come out as the longest one.
+pub fn secs_duration(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+/// Parses the arguments of a move command, given either as
+/// `<speed> [yaw <deg>] [for <secs> | until angle]` or as a json object
+pub fn parse_move(args: &str) -> Result<Command, String> {
+    let command = if args.starts_with('{') {
+        let fields: serde_json::Value = serde_json::from_str(args)
+            .map_err(|e| format!("Invalid json: {}", e))?;
+        let mut command = serde_json::Map::new();
+        command.insert(String::from("Move"), fields);
+        serde_json::from_value(serde_json::Value::Object(command))
+            .map_err(|e| format!("Invalid move: {}", e))?
+    } else {
+        let args = args.to_lowercase();
+        let mut parts = args.split_whitespace();
+        let speed = parts.next().and_then(|p| p.parse::<f64>().ok())
+            .ok_or_else(|| String::from("Expected a speed in m/s"))?;
+        let mut target_yaw = None;
+        let mut end = None;
+        while let Some(part) = parts.next() {
+            match part {
+                "yaw" => {
+                    let yaw = parts.next().and_then(|p| p.parse::<f64>().ok())
+                        .ok_or_else(|| String::from("Expected an angle in degrees after 'yaw'"))?;
+                    target_yaw = Some(yaw);
+                },
+                "for" => {
+                    let secs = parts.next().and_then(|p| p.parse::<f64>().ok())
+                        .filter(|s| *s >= 0.0 && s.is_finite())
+                        .ok_or_else(|| String::from("Expected a number of seconds after 'for'"))?;
+                    end = Some(EndCondition::Time(secs_duration(secs)));
+                },
+                "until" => match parts.next() {
+                    Some("angle") => end = Some(EndCondition::AngleReached),
+                    _ => return Err(String::from("Expected 'angle' after 'until'")),
+                },
+                p => return Err(format!("Unexpected '{}'", p)),
+            }
+        }
+        Command::Move { speed, target_yaw, end }
+    };
+
+    check_command(command)
+}
+
+/// Catches commands that parse but make no sense
+pub fn check_command(command: Command) -> Result<Command, String> {
+    match command {
+        Command::Move { speed, .. } if !speed.is_finite() =>
+            Err(String::from("Speed should be a number of m/s")),
+        Command::Move { target_yaw: Some(yaw), .. } if !yaw.is_finite() =>
+            Err(String::from("Yaw should be a number of degrees")),
+        Command::Move { end: Some(EndCondition::Time(t)), .. } if t > Duration::from_secs(MAX_MOVE_SECS) =>
+            Err(format!("Moves can run for at most {} seconds", MAX_MOVE_SECS)),
+        Command::SetPid { kp, ki, kd } if !(kp.is_finite() && ki.is_finite() && kd.is_finite()) =>
+            Err(String::from("Gains should be numbers")),
+        Command::Move { target_yaw: None, end: Some(EndCondition::AngleReached), .. } =>
+            Err(String::from("'until angle' needs a yaw to reach")),
+        c => Ok(c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_moves() {
+        match parse_move("0.2 YAW 90 for 1.5") {
+            Ok(Command::Move { speed, target_yaw: Some(yaw), end: Some(EndCondition::Time(t)) }) => {
+                assert_eq!(speed, 0.2);
+                assert_eq!(yaw, 90.0);
+                assert_eq!(t, Duration::from_millis(1500));
+            },
+            r => panic!("expected a timed move, got {:?}", r),
+        }
+        match parse_move("-0.1") {
+            Ok(Command::Move { target_yaw: None, end: None, .. }) => (),
+            r => panic!("expected an endless move, got {:?}", r),
+        }
+        match parse_move("0.2 yaw -45 until angle") {
+            Ok(Command::Move { end: Some(EndCondition::AngleReached), .. }) => (),
+            r => panic!("expected a move until the angle, got {:?}", r),
+        }
+        assert_eq!(parse_move("0.2 for").unwrap_err(), "Expected a number of seconds after 'for'");
+        assert_eq!(parse_move("0.2 for -1").unwrap_err(), "Expected a number of seconds after 'for'");
+        assert_eq!(parse_move("0.2 for 3601").unwrap_err(), "Moves can run for at most 3600 seconds");
+        assert_eq!(parse_move("0.2 for 1e20").unwrap_err(), "Moves can run for at most 3600 seconds");
+        assert_eq!(parse_move(r#"{"speed": 0.2, "target_yaw": null, "end": {"Time": 1e20}}"#).unwrap_err(),
+                   "Moves can run for at most 3600 seconds");
+        for yaw in &["nan", "inf", "-inf"] {
+            assert_eq!(parse_move(&format!("0.2 yaw {}", yaw)).unwrap_err(), "Yaw should be a number of degrees");
+        }
+        assert_eq!(parse_move("0.2 yaw 90 until").unwrap_err(), "Expected 'angle' after 'until'");
+        assert_eq!(parse_move("0.2 yaw 90 until time").unwrap_err(), "Expected 'angle' after 'until'");
+        assert_eq!(parse_move("0.2 until angle").unwrap_err(), "'until angle' needs a yaw to reach");
+        assert_eq!(parse_move("fast").unwrap_err(), "Expected a speed in m/s");
+        assert_eq!(parse_move("0.2 sideways").unwrap_err(), "Unexpected 'sideways'");
+    }
+}
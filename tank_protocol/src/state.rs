@@ -0,0 +1,137 @@
+//! What the tank knows about itself, as sent to clients
+
+use std::time::{Duration, Instant};
+
+use timestamp;
+
+#[derive(Serialize, Deserialize, Default, Copy, Clone)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RawSensorState {
+    #[serde(with = "timestamp")]
+    pub time: Instant,
+    pub gyro: Vec3,
+    pub accel: Vec3,
+    /// Acceleration with gravity removed, in meters/s^2
+    pub linear_accel: Vec3,
+    pub mag: Vec3,
+    pub orientation: Vec3,
+    pub temp: f32,
+    //TODO should PWM state be included?
+}
+impl Default for RawSensorState {
+    fn default() -> RawSensorState {
+        RawSensorState {
+            time: Instant::now(),
+            gyro: Vec3::default(),
+            accel: Vec3::default(),
+            linear_accel: Vec3::default(),
+            mag: Vec3::default(),
+            orientation: Vec3::default(),
+            temp: 0.0,
+        }
+    }
+}
+
+/// Tick counts of the track encoders, and the speed of each track
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct EncoderState {
+    #[serde(with = "timestamp")]
+    pub time: Instant,
+    pub left_ticks: i64,
+    pub right_ticks: i64,
+    /// In m/s
+    pub left_velocity: f32,
+    /// In m/s
+    pub right_velocity: f32,
+}
+
+/// How steady the i2c loop is running, over about a second
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoopStats {
+    /// Shortest time between the start of two loops, in seconds
+    pub min_period: f32,
+    /// Longest time between the start of two loops, in seconds
+    pub max_period: f32,
+    /// Average time between the start of two loops, in seconds
+    pub mean_period: f32,
+    /// Loops that took longer than the target period
+    pub overruns: u32,
+    /// Overruns since the loop started
+    pub total_overruns: u64,
+}
+
+/// Dead reckoned position of the tank relative to its start point.
+/// x points along the starting heading, y points to the right of it.
+/// Like the BNO055's heading, the pose heading increases turning clockwise.
+#[derive(Serialize, Deserialize, Default, Copy, Clone)]
+pub struct Pose {
+    /// Distance in meters
+    pub x: f32,
+    /// Distance in meters
+    pub y: f32,
+    /// Heading in radians, relative to the starting heading
+    pub heading: f32,
+    /// Forward velocity in m/s
+    pub velocity: f32,
+}
+
+/// The tank's sensor state as of its latest update
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SensorReport {
+    /// The time between the most recent update and the previous
+    pub duration: Duration,
+    /// Time at which the sensor state was last updated.
+    #[serde(with = "timestamp")]
+    pub time: Instant,
+    pub raw_state: RawSensorState,
+    /// In radians
+    pub roll: f32,
+    /// In radians, increasing clockwise
+    pub yaw: f32,
+    /// In radians
+    pub pitch: f32,
+    /// Drive power applied to the motors, from -1 to 1
+    pub speed: f32,
+    /// Yaw when the first sensor reading arrived, the pose is relative to this
+    pub start_yaw: Option<f32>,
+    pub pose: Pose,
+    /// Latest reading of the track encoders, if there are any
+    pub encoders: Option<EncoderState>,
+    /// Sonar distance in CM
+    pub sonar: f32,
+    /// When the sonar was last read
+    #[serde(with = "timestamp")]
+    pub sonar_time: Instant,
+    #[serde(with = "timestamp::option")]
+    pub target_time: Option<Instant>,
+    pub target_angle: Option<f32>,
+    /// Timing of the real time loop
+    pub loop_stats: LoopStats,
+}
+
+/// Snapshot of the PID terms, for watching the controller while tuning
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct PidTelemetry {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub error: f32,
+    pub integral: f32,
+    pub derivative: f32,
+    pub output: f32,
+}
+
+/// The devices run by the real time threads
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Device {
+    Pwm,
+    Imu,
+    Sonar,
+    Encoders,
+}
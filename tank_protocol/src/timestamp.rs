@@ -0,0 +1,82 @@
+//! Timestamps are taken from the monotonic clock, so NTP adjusting the wall
+//! clock can't make time jump backwards or skip ahead mid drive. They are
+//! only turned into wall clock times to be written out, so recordings and
+//! telemetry still say when things happened.
+//!
+//! Use with `#[serde(with = "timestamp")]` on Instant fields.
+
+use std::time::{Instant, SystemTime};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
+lazy_static! {
+    /// The same moment on both clocks, taken once so every conversion agrees
+    static ref ANCHOR: (Instant, SystemTime) = (Instant::now(), SystemTime::now());
+}
+
+/// The wall clock time of a monotonic timestamp
+pub fn to_wall(time: Instant) -> SystemTime {
+    let (instant, wall) = *ANCHOR;
+    if time >= instant {
+        wall + (time - instant)
+    } else {
+        wall - (instant - time)
+    }
+}
+
+/// The monotonic timestamp of a wall clock time, None if it is further in
+/// the past than the monotonic clock can go
+pub fn from_wall(time: SystemTime) -> Option<Instant> {
+    let (instant, wall) = *ANCHOR;
+    match time.duration_since(wall) {
+        Ok(after) => Some(instant + after),
+        Err(e) => instant.checked_sub(e.duration()),
+    }
+}
+
+pub fn serialize<S: Serializer>(time: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+    to_wall(*time).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+    let wall = SystemTime::deserialize(deserializer)?;
+    from_wall(wall).ok_or_else(|| D::Error::custom("timestamp is too far in the past"))
+}
+
+/// For Option<Instant> fields, `#[serde(with = "timestamp::option")]`
+pub mod option {
+    use std::time::{Instant, SystemTime};
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(time: &Option<Instant>, serializer: S) -> Result<S::Ok, S::Error> {
+        time.map(super::to_wall).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Instant>, D::Error> {
+        match Option::<SystemTime>::deserialize(deserializer)? {
+            Some(wall) => super::from_wall(wall).map(Some)
+                .ok_or_else(|| D::Error::custom("timestamp is too far in the past")),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn round_trips() {
+        let now = Instant::now();
+        for &time in [now, now + Duration::from_millis(1500), now - Duration::from_millis(10)].iter() {
+            assert_eq!(from_wall(to_wall(time)), Some(time));
+        }
+        //wall clock jumps after the anchor is taken don't move timestamps
+        let later = to_wall(now + Duration::from_secs(1));
+        assert_eq!(later.duration_since(to_wall(now)).unwrap(), Duration::from_secs(1));
+    }
+}